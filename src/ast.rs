@@ -8,6 +8,48 @@ pub enum Bits {
     Bit64
 }
 
+impl Bits {
+    pub fn from_size(size: usize) -> Self {
+        match size {
+            1 => Bits::Bit8,
+            2 => Bits::Bit16,
+            4 => Bits::Bit32,
+            _ => Bits::Bit64
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match self {
+            Bits::Bit8 => 1,
+            Bits::Bit16 => 2,
+            Bits::Bit32 => 4,
+            Bits::Bit64 => 8
+        }
+    }
+
+    pub fn mask(&self) -> u64 {
+        match self {
+            Bits::Bit8 => 0xFF,
+            Bits::Bit16 => 0xFFFF,
+            Bits::Bit32 => 0xFFFF_FFFF,
+            Bits::Bit64 => u64::MAX
+        }
+    }
+
+    pub fn sign_bit(&self) -> u64 {
+        1 << (self.bytes() * 8 - 1)
+    }
+
+    pub fn sign_extend(&self, val: u64) -> i64 {
+        match self {
+            Bits::Bit8 => val as u8 as i8 as i64,
+            Bits::Bit16 => val as u16 as i16 as i64,
+            Bits::Bit32 => val as u32 as i32 as i64,
+            Bits::Bit64 => val as i64
+        }
+    }
+}
+
 impl Into<u32> for Bits {
     fn into(self) -> u32 {
         match self {
@@ -2,6 +2,7 @@ use crate::ast::Bits;
 use crate::bytecode::*;
 use crate::decoder::Decoder;
 use crate::vm::cpu::Cpu;
+use crate::vm::serial::open_backend;
 use crate::vm::Mode;
 
 mod decoder;
@@ -11,13 +12,47 @@ mod vm;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <filename>", args[0]);
+    let usage = || {
+        eprintln!("Usage: {} [--com1..--com4 stdio|file:<path>|pty|unix:<path>] <filename>", args[0]);
+        eprintln!("XVM_RTC_START=<seconds since 1970> starts the RTC at a fixed time");
         std::process::exit(1);
+    };
+    let mut filename = None;
+    let mut serial = Vec::new();
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.strip_prefix("--com").and_then(|n| n.parse::<usize>().ok()) {
+            Some(n @ 1..=4) => match rest.next().map(|spec| open_backend(spec)) {
+                Some(Ok(backend)) => serial.push((n - 1, backend)),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
+                None => usage()
+            },
+            _ if filename.is_none() => filename = Some(arg.clone()),
+            _ => usage()
+        }
+    }
+    let Some(filename) = filename else {
+        return usage();
+    };
+    // seconds since the epoch the RTC starts from, for reproducible runs
+    let mut rtc_start = None;
+    if let Ok(start) = std::env::var("XVM_RTC_START") {
+        match start.parse::<i64>() {
+            Ok(seconds) => rtc_start = Some(seconds),
+            Err(_) => usage()
+        }
+    }
+    let bootloader = std::fs::read(filename).unwrap();
+    let mut cpu = Cpu::new();
+    for (port, backend) in serial {
+        cpu.attach_serial(port, backend);
+    }
+    if let Some(seconds) = rtc_start {
+        cpu.set_rtc_time(seconds);
     }
-    let bootloader = std::fs::read(args[1].clone()).unwrap();
-    let context = sdl2::ttf::init().unwrap();
-    let mut cpu = Cpu::new(&context);
     cpu.disk.write_sector(0, bootloader);
     cpu.init_bios();
     cpu.run();
@@ -0,0 +1,490 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::ast::Bits;
+use crate::vm::io::PortDevice;
+use crate::vm::pic::Pic;
+
+pub const CMOS_INDEX: u16 = 0x70;
+pub const CMOS_DATA: u16 = 0x71;
+pub const NVRAM_FILE: &str = "cpu.nvram";
+const NVRAM_SIZE: usize = 128;
+const RTC_IRQ: u8 = 8;
+
+// clock registers
+const SECONDS: usize = 0x00;
+const SECONDS_ALARM: usize = 0x01;
+const MINUTES: usize = 0x02;
+const MINUTES_ALARM: usize = 0x03;
+const HOURS: usize = 0x04;
+const HOURS_ALARM: usize = 0x05;
+const DAY_OF_WEEK: usize = 0x06;
+const DAY_OF_MONTH: usize = 0x07;
+const MONTH: usize = 0x08;
+const YEAR: usize = 0x09;
+const STATUS_A: usize = 0x0A;
+const STATUS_B: usize = 0x0B;
+const STATUS_C: usize = 0x0C;
+const STATUS_D: usize = 0x0D;
+const CENTURY: usize = 0x32;
+const CLOCK_REGISTERS: [usize; 8] = [SECONDS, MINUTES, HOURS, DAY_OF_WEEK, DAY_OF_MONTH, MONTH, YEAR, CENTURY];
+
+// status A: update in progress, divider chain and periodic rate
+const A_UIP: u8 = 1 << 7;
+const A_DIVIDER: u8 = 0x70;
+const A_DIVIDER_NORMAL: u8 = 0x20;
+const A_RATE: u8 = 0x0F;
+// status B
+const B_SET: u8 = 1 << 7;
+const B_PIE: u8 = 1 << 6;
+const B_AIE: u8 = 1 << 5;
+const B_UIE: u8 = 1 << 4;
+const B_BINARY: u8 = 1 << 2;
+const B_24_HOUR: u8 = 1 << 1;
+// status C, cleared by reading it
+const C_IRQF: u8 = 1 << 7;
+const C_PF: u8 = 1 << 6;
+const C_AF: u8 = 1 << 5;
+const C_UF: u8 = 1 << 4;
+// status D: the battery is always good
+const D_VRT: u8 = 1 << 7;
+
+// alarm bytes with the two top bits set match anything
+const ALARM_ANY: u8 = 0xC0;
+// UIP goes up 244us before the seconds roll over
+const UIP_NS: u64 = 244_000;
+const SECOND_NS: u64 = 1_000_000_000;
+
+// configuration bytes BIOSes read
+const FLOPPY_TYPES: usize = 0x10;
+const DISK_TYPES: usize = 0x12;
+const EQUIPMENT: usize = 0x14;
+const BASE_MEMORY: usize = 0x15;
+const EXTENDED_MEMORY: usize = 0x17;
+const DISK0_EXTENDED_TYPE: usize = 0x19;
+const DISK0_PARAMETERS: usize = 0x1B;
+const CHECKSUM: usize = 0x2E;
+const EXTENDED_MEMORY_POST: usize = 0x30;
+const HIGH_MEMORY: usize = 0x34;
+
+pub struct Cmos {
+    nvram: [u8; NVRAM_SIZE],
+    index: usize,
+    pic: Rc<RefCell<Pic>>,
+    // wall clock in seconds since the epoch, advanced by emulated time
+    time: i64,
+    second_ns: u64,
+    periodic_ns: u64,
+    path: String,
+    // NVRAM bytes changed since the file was last written
+    dirty: bool,
+}
+
+impl Cmos {
+    pub fn new(path: &str, pic: Rc<RefCell<Pic>>) -> Self {
+        let mut nvram = [0; NVRAM_SIZE];
+        if let Ok(data) = std::fs::read(path) {
+            let len = data.len().min(NVRAM_SIZE);
+            nvram[..len].copy_from_slice(&data[..len]);
+        }
+        nvram[STATUS_A] = A_DIVIDER_NORMAL | 0x06;
+        nvram[STATUS_B] = B_24_HOUR;
+        nvram[STATUS_C] = 0;
+        nvram[STATUS_D] = D_VRT;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+        Self {
+            nvram,
+            index: 0,
+            pic,
+            time,
+            second_ns: 0,
+            periodic_ns: 0,
+            path: path.to_string(),
+            dirty: false,
+        }
+    }
+
+    // a fixed starting point makes runs reproducible
+    pub fn set_time(&mut self, seconds: i64) {
+        self.time = seconds;
+        self.second_ns = 0;
+    }
+
+    // memory sizes in KB as the BIOS reports them, and the first hard disk
+    // as a user defined type with the given geometry
+    pub fn configure(&mut self, memory: usize, cylinders: u16, heads: u8, sectors: u8) {
+        let extended = ((memory.saturating_sub(0x10_0000)) / 1024).min(0xFFFF) as u16;
+        let high = ((memory.saturating_sub(0x100_0000)) / 0x1_0000).min(0xFFFF) as u16;
+        self.nvram[FLOPPY_TYPES] = 0;
+        self.nvram[DISK_TYPES] = 0xF0;
+        self.nvram[DISK0_EXTENDED_TYPE] = 47;
+        // no floppy, VGA, math coprocessor present
+        self.nvram[EQUIPMENT] = 0x02;
+        self.nvram[BASE_MEMORY..BASE_MEMORY + 2].copy_from_slice(&640u16.to_le_bytes());
+        self.nvram[EXTENDED_MEMORY..EXTENDED_MEMORY + 2].copy_from_slice(&extended.to_le_bytes());
+        self.nvram[EXTENDED_MEMORY_POST..EXTENDED_MEMORY_POST + 2].copy_from_slice(&extended.to_le_bytes());
+        self.nvram[HIGH_MEMORY..HIGH_MEMORY + 2].copy_from_slice(&high.to_le_bytes());
+        // cylinders, heads, write precompensation, control, landing zone, sectors
+        let parameters = DISK0_PARAMETERS;
+        self.nvram[parameters..parameters + 2].copy_from_slice(&cylinders.to_le_bytes());
+        self.nvram[parameters + 2] = heads;
+        self.nvram[parameters + 3..parameters + 5].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        self.nvram[parameters + 5] = if heads > 8 { 0x08 } else { 0 };
+        self.nvram[parameters + 6..parameters + 8].copy_from_slice(&cylinders.to_le_bytes());
+        self.nvram[parameters + 8] = sectors;
+        self.update_checksum();
+        self.dirty = true;
+    }
+
+    // big-endian sum of 10h-2Dh
+    fn update_checksum(&mut self) {
+        let sum: u16 = self.nvram[FLOPPY_TYPES..CHECKSUM].iter().map(|&b| b as u16).sum();
+        self.nvram[CHECKSUM..CHECKSUM + 2].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    // written back on reset and on exit rather than on every OUT
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        match std::fs::write(&self.path, self.nvram) {
+            Ok(()) => self.dirty = false,
+            Err(e) => eprintln!("cannot save the NVRAM to {}: {}", self.path, e)
+        }
+    }
+
+    fn is_binary(&self) -> bool {
+        self.nvram[STATUS_B] & B_BINARY != 0
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.is_binary() { value } else { ((value / 10) << 4) | (value % 10) }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.is_binary() { value } else { (value >> 4) * 10 + (value & 0xF) }
+    }
+
+    // hours keep the PM flag in bit 7 in 12 hour mode
+    fn encode_hours(&self, hours: u8) -> u8 {
+        if self.nvram[STATUS_B] & B_24_HOUR != 0 {
+            return self.encode(hours);
+        }
+        let pm = if hours >= 12 { 0x80 } else { 0 };
+        let hours = match hours % 12 { 0 => 12, h => h };
+        self.encode(hours) | pm
+    }
+
+    fn decode_hours(&self, value: u8) -> u8 {
+        if self.nvram[STATUS_B] & B_24_HOUR != 0 {
+            return self.decode(value);
+        }
+        let hours = self.decode(value & 0x7F) % 12;
+        if value & 0x80 != 0 { hours + 12 } else { hours }
+    }
+
+    fn clock_register(&self, index: usize) -> u8 {
+        let (year, month, day) = civil_from_days(self.time.div_euclid(86400));
+        let seconds = self.time.rem_euclid(86400);
+        match index {
+            SECONDS => self.encode((seconds % 60) as u8),
+            MINUTES => self.encode((seconds / 60 % 60) as u8),
+            HOURS => self.encode_hours((seconds / 3600) as u8),
+            // 1 is Sunday, and 1970-01-01 was a Thursday
+            DAY_OF_WEEK => self.encode(((self.time.div_euclid(86400) + 4).rem_euclid(7) + 1) as u8),
+            DAY_OF_MONTH => self.encode(day as u8),
+            MONTH => self.encode(month as u8),
+            YEAR => self.encode((year % 100) as u8),
+            CENTURY => self.encode((year / 100) as u8),
+            _ => self.nvram[index]
+        }
+    }
+
+    fn set_clock_register(&mut self, index: usize, value: u8) {
+        let days = self.time.div_euclid(86400);
+        let (mut year, mut month, mut day) = civil_from_days(days);
+        let seconds = self.time.rem_euclid(86400);
+        let (mut hour, mut minute, mut second) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+        match index {
+            SECONDS => second = self.decode(value) as i64,
+            MINUTES => minute = self.decode(value) as i64,
+            HOURS => hour = self.decode_hours(value) as i64,
+            DAY_OF_MONTH => day = self.decode(value) as i64,
+            MONTH => month = self.decode(value) as i64,
+            YEAR => year = year / 100 * 100 + self.decode(value) as i64,
+            CENTURY => year = self.decode(value) as i64 * 100 + year % 100,
+            _ => {}
+        }
+        self.time = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    }
+
+    fn is_clock_register(index: usize) -> bool {
+        CLOCK_REGISTERS.contains(&index)
+    }
+
+    fn is_held(&self) -> bool {
+        self.nvram[STATUS_B] & B_SET != 0
+    }
+
+    // while SET is held the clock registers are plain bytes, so software can
+    // write the date field by field in any order
+    fn hold_clock(&mut self) {
+        for index in CLOCK_REGISTERS {
+            self.nvram[index] = self.clock_register(index);
+        }
+    }
+
+    // and clearing SET turns them into the time in one go
+    fn release_clock(&mut self) {
+        let field = |index: usize| self.decode(self.nvram[index]) as i64;
+        let days = days_from_civil(field(CENTURY) * 100 + field(YEAR), field(MONTH), field(DAY_OF_MONTH));
+        let hours = self.decode_hours(self.nvram[HOURS]) as i64;
+        self.time = days * 86400 + hours * 3600 + field(MINUTES) * 60 + field(SECONDS);
+    }
+
+    fn periodic_ns(&self) -> Option<u64> {
+        // rates 1 and 2 alias to 256 and 128 Hz
+        let rate = match self.nvram[STATUS_A] & A_RATE {
+            0 => return None,
+            1 => 8,
+            2 => 9,
+            rate => rate,
+        };
+        Some(SECOND_NS * (1 << (rate - 1)) / 32768)
+    }
+
+    fn flag(&mut self, flag: u8, enable: u8) {
+        self.nvram[STATUS_C] |= flag;
+        if self.nvram[STATUS_B] & enable != 0 && self.nvram[STATUS_C] & C_IRQF == 0 {
+            self.nvram[STATUS_C] |= C_IRQF;
+            self.pic.borrow_mut().raise_irq(RTC_IRQ);
+        }
+    }
+
+    fn alarm_matches(&self) -> bool {
+        [(SECONDS_ALARM, SECONDS), (MINUTES_ALARM, MINUTES), (HOURS_ALARM, HOURS)].iter()
+            .all(|&(alarm, current)| {
+                let alarm = self.nvram[alarm];
+                alarm & ALARM_ANY == ALARM_ANY || alarm == self.clock_register(current)
+            })
+    }
+
+    pub fn advance(&mut self, ns: u64) {
+        if self.nvram[STATUS_A] & A_DIVIDER != A_DIVIDER_NORMAL {
+            return;
+        }
+        if let Some(period) = self.periodic_ns() {
+            self.periodic_ns += ns;
+            while self.periodic_ns >= period {
+                self.periodic_ns -= period;
+                self.flag(C_PF, B_PIE);
+            }
+        }
+
+        self.second_ns += ns;
+        if self.second_ns >= SECOND_NS - UIP_NS {
+            self.nvram[STATUS_A] |= A_UIP;
+        }
+        while self.second_ns >= SECOND_NS {
+            self.second_ns -= SECOND_NS;
+            self.nvram[STATUS_A] &= !A_UIP;
+            // SET freezes the clock while software updates it
+            if self.nvram[STATUS_B] & B_SET != 0 {
+                continue;
+            }
+            self.time += 1;
+            self.flag(C_UF, B_UIE);
+            if self.alarm_matches() {
+                self.flag(C_AF, B_AIE);
+            }
+        }
+    }
+}
+
+impl PortDevice for Cmos {
+    fn read(&mut self, port: u16, _bits: Bits) -> u64 {
+        if port != CMOS_DATA {
+            return 0xFF;
+        }
+        let value = match self.index {
+            STATUS_C => {
+                let value = self.nvram[STATUS_C];
+                self.nvram[STATUS_C] = 0;
+                self.pic.borrow_mut().lower_irq(RTC_IRQ);
+                value
+            },
+            index if Self::is_clock_register(index) && !self.is_held() => self.clock_register(index),
+            index => self.nvram[index]
+        };
+        value as u64
+    }
+
+    fn write(&mut self, port: u16, value: u64, _bits: Bits) {
+        let value = value as u8;
+        if port == CMOS_INDEX {
+            // bit 7 gates NMI, which nothing raises
+            self.index = (value & 0x7F) as usize;
+            return;
+        }
+        match self.index {
+            STATUS_A => self.nvram[STATUS_A] = (self.nvram[STATUS_A] & A_UIP) | (value & !A_UIP),
+            STATUS_B => {
+                let held = self.is_held();
+                if held && value & B_SET == 0 {
+                    self.release_clock();
+                }
+                self.nvram[STATUS_B] = value;
+                if value & B_SET != 0 {
+                    self.nvram[STATUS_A] &= !A_UIP;
+                    if !held {
+                        self.hold_clock();
+                    }
+                }
+            },
+            STATUS_C | STATUS_D => {},
+            index if Self::is_clock_register(index) && self.is_held() => self.nvram[index] = value,
+            index if Self::is_clock_register(index) => self.set_clock_register(index, value),
+            index => {
+                self.nvram[index] = value;
+                self.dirty = true;
+            }
+        }
+    }
+}
+
+// proleptic Gregorian calendar, from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmos() -> Cmos {
+        Cmos::new("/nonexistent/cpu.nvram", Rc::new(RefCell::new(Pic::new())))
+    }
+
+    fn read(cmos: &mut Cmos, index: u8) -> u8 {
+        cmos.write(CMOS_INDEX, index as u64, Bits::Bit8);
+        cmos.read(CMOS_DATA, Bits::Bit8) as u8
+    }
+
+    fn write(cmos: &mut Cmos, index: u8, value: u8) {
+        cmos.write(CMOS_INDEX, index as u64, Bits::Bit8);
+        cmos.write(CMOS_DATA, value as u64, Bits::Bit8);
+    }
+
+    // century, year, month and day of the month
+    fn date(cmos: &mut Cmos) -> [u8; 4] {
+        [CENTURY, YEAR, MONTH, DAY_OF_MONTH].map(|index| read(cmos, index as u8))
+    }
+
+    #[test]
+    fn bcd_round_trip() {
+        let mut cmos = cmos();
+        for value in 0..100 {
+            assert_eq!(cmos.decode(cmos.encode(value)), value);
+        }
+        assert_eq!(cmos.encode(59), 0x59);
+        cmos.nvram[STATUS_B] |= B_BINARY;
+        assert_eq!(cmos.encode(59), 59);
+    }
+
+    #[test]
+    fn twelve_hour_clock() {
+        let mut cmos = cmos();
+        cmos.nvram[STATUS_B] &= !B_24_HOUR;
+        for (hours, encoded) in [(0, 0x12), (1, 0x01), (12, 0x92), (23, 0x91)] {
+            assert_eq!(cmos.encode_hours(hours), encoded);
+            assert_eq!(cmos.decode_hours(encoded), hours);
+        }
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn leap_years() {
+        // 2000 is divisible by 400, 2100 and 1900 only by 100
+        assert_eq!(days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28), 2);
+        assert_eq!(days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28), 1);
+        assert_eq!(days_from_civil(1900, 3, 1) - days_from_civil(1900, 2, 28), 1);
+        assert_eq!(days_from_civil(2024, 3, 1) - days_from_civil(2024, 2, 28), 2);
+        assert_eq!(civil_from_days(days_from_civil(2000, 2, 28) + 1), (2000, 2, 29));
+        assert_eq!(civil_from_days(days_from_civil(2100, 2, 28) + 1), (2100, 3, 1));
+        assert_eq!(days_from_civil(2000, 1, 1), 10957);
+    }
+
+    #[test]
+    fn civil_round_trip() {
+        let start = days_from_civil(1899, 1, 1);
+        let end = days_from_civil(2101, 12, 31);
+        for days in start..=end {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn clock_rolls_over_the_century() {
+        let mut cmos = cmos();
+        cmos.set_time(days_from_civil(2099, 12, 31) * 86400 + 86399);
+        assert_eq!(date(&mut cmos), [0x20, 0x99, 0x12, 0x31]);
+        cmos.advance(SECOND_NS);
+        assert_eq!(date(&mut cmos), [0x21, 0x00, 0x01, 0x01]);
+        assert_eq!([read(&mut cmos, 0x04), read(&mut cmos, 0x02), read(&mut cmos, 0x00)], [0x00, 0x00, 0x00]);
+        // 2100-01-01 is a Friday
+        assert_eq!(read(&mut cmos, 0x06), 6);
+    }
+
+    #[test]
+    fn set_the_date_in_bcd() {
+        let mut cmos = cmos();
+        cmos.set_time(0);
+        write(&mut cmos, 0x0B, B_SET | B_24_HOUR);
+        for (index, value) in [(0x32, 0x20), (0x09, 0x00), (0x08, 0x02), (0x07, 0x29), (0x04, 0x13), (0x02, 0x45), (0x00, 0x30)] {
+            write(&mut cmos, index, value);
+        }
+        write(&mut cmos, 0x0B, B_24_HOUR);
+        assert_eq!(cmos.time, days_from_civil(2000, 2, 29) * 86400 + 13 * 3600 + 45 * 60 + 30);
+    }
+
+    // Feb 31 must not be normalised before the month is written
+    #[test]
+    fn date_fields_in_any_order() {
+        let mut cmos = cmos();
+        cmos.set_time(days_from_civil(2024, 2, 10) * 86400 + 3600);
+        write(&mut cmos, 0x0B, B_SET | B_24_HOUR);
+        write(&mut cmos, 0x07, 0x31);
+        assert_eq!(read(&mut cmos, 0x07), 0x31);
+        write(&mut cmos, 0x08, 0x03);
+        write(&mut cmos, 0x0B, B_24_HOUR);
+        assert_eq!(date(&mut cmos), [0x20, 0x24, 0x03, 0x31]);
+        assert_eq!(read(&mut cmos, 0x04), 0x01);
+    }
+}
@@ -1,62 +1,190 @@
-use std::io::Write;
+use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use iced_x86::{Code, Instruction, Mnemonic};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
+use sdl2::pixels::PixelFormatEnum;
 use sdl2::render::{Canvas, TextureCreator};
-use sdl2::ttf::{Font, Sdl2TtfContext};
 use sdl2::video::{Window, WindowContext};
 use crate::ast::Bits;
+use crate::vm::cmos::{Cmos, CMOS_INDEX, NVRAM_FILE};
+use crate::vm::io::IoBus;
+use crate::vm::serial::{SerialBackend, Uart, COM_PORTS, UART_PORTS};
+use crate::vm::keyboard::{KeyboardController, KBD_DATA, KBD_STATUS};
 use crate::vm::mem::{Memory, HUNDRED_MO};
+use crate::vm::pic::{Pic, PIC_MASTER, PIC_SLAVE};
+use crate::vm::pit::{Pit, PIT_BASE, PORT_B};
+use crate::vm::vbe::{Vbe, DISPI_INDEX, LFB_BASE, VIDEO_MEMORY};
+use crate::vm::vga::{Vga, CRTC_INDEX, INPUT_STATUS_1, VGA_BASE, VGA_PORTS, VGA_PORT_COUNT, VGA_SIZE};
 use crate::vm::{Mode};
-use crate::vm::register::{FlagsRegister, GeneralPurposeRegisters, InstructionPointer};
-use crate::vm::segment::SegmentRegister;
-use crate::vm::virtualdisk::VirtualDisk;
+use crate::vm::exception::Exception;
+use crate::vm::flags::{CF, DF, IF, OF};
+use crate::vm::cpu::access::Access;
+use crate::vm::register::{ControlRegisters, FlagsRegister, GeneralPurposeRegisters, InstructionPointer};
+use crate::vm::paging::{Tlb, PAGE_MASK};
+use crate::vm::idt::Idt;
+use crate::vm::segment::{Gdt, SegmentCache, TaskRegister};
+use crate::vm::virtualdisk::{VirtualDisk, CYLINDERS, HEADS, SECTORS_PER_TRACK};
 
-pub struct Cpu<'ttf> {
-    mode: Mode,
-    mem: Memory,
-    gpr: GeneralPurposeRegisters,
-    ip: InstructionPointer,
-    pub disk: VirtualDisk,
-    flags: FlagsRegister,
+// emulated time charged to every instruction, about 10 MIPS
+const INSTRUCTION_NS: u64 = 100;
+// a halted CPU sleeps this long on the host and lets the clocks catch up
+const HALT_NS: u64 = 1_000_000;
+// the window is redrawn about 60 times a second of host time
+const FRAME_INTERVAL: Duration = Duration::from_micros(16_667);
+// a 4:3 monitor, every video mode is stretched to fill it
+const WINDOW_WIDTH: u32 = 640;
+const WINDOW_HEIGHT: u32 = 480;
+
+mod stack;
+mod interrupt;
+mod muldiv;
+mod bitops;
+mod string;
+mod bios;
+mod protected;
+mod access;
+mod paging;
+mod msr;
+mod task;
+mod video;
+mod vesa;
+
+// the SDL window the screen is drawn into
+struct Display {
     sdl_context: sdl2::Sdl,
     canvas: Canvas<Window>,
     texture_creator: TextureCreator<WindowContext>,
-    font: Font<'ttf, 'static>,
-    ttf_context: &'ttf Sdl2TtfContext,
 }
-impl<'ttf> Cpu<'ttf> {
-    pub fn with_mode(mode: Mode, sdl2ttf_context: &'ttf Sdl2TtfContext) -> Self {
+
+impl Display {
+    fn new() -> Self {
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
-        let window = video_subsystem.window("XVm", 640, 400)
+        let window = video_subsystem.window("XVm", WINDOW_WIDTH, WINDOW_HEIGHT)
             .position_centered()
             .build()
             .unwrap();
-        let mut canvas = window.into_canvas().build().unwrap();
+        let canvas = window.into_canvas().build().unwrap();
         let texture_creator = canvas.texture_creator();
-        let font = sdl2ttf_context.load_font("/Users/antoine/Library/Fonts/0xProtoNerdFont-Regular.ttf", 16).unwrap();
+        Self {
+            sdl_context,
+            canvas,
+            texture_creator,
+        }
+    }
+}
+
+pub struct Cpu {
+    mode: Mode,
+    mem: Memory,
+    vga: Rc<RefCell<Vga>>,
+    vbe: Rc<RefCell<Vbe>>,
+    io: IoBus,
+    pic: Rc<RefCell<Pic>>,
+    pit: Rc<RefCell<Pit>>,
+    cmos: Rc<RefCell<Cmos>>,
+    keyboard: Rc<RefCell<KeyboardController>>,
+    serial: Vec<Rc<RefCell<Uart>>>,
+    leds: u8,
+    halted: bool,
+    // kept apart from CS.RPL, which is whatever real mode left there until
+    // the first far jump after setting PE
+    cpl: u8,
+    // STI and loads of SS hold off interrupts for one more instruction
+    interrupt_shadow: bool,
+    gpr: GeneralPurposeRegisters,
+    ip: InstructionPointer,
+    pub disk: VirtualDisk,
+    flags: FlagsRegister,
+    cr: ControlRegisters,
+    gdt: Gdt,
+    idt: Idt,
+    tr: TaskRegister,
+    segments: SegmentCache,
+    tlb: Tlb,
+    kernel_gs_base: u64,
+    // none without a window, as in the tests
+    display: Option<Display>,
+    last_frame: Instant,
+    // the instruction trace on stdout, off while a serial port uses stdout
+    trace: bool,
+}
+impl Cpu {
+    pub fn with_mode(mode: Mode) -> Self {
+        Self::build(mode, Some(Display::new()), VirtualDisk::new("cpu.vdisk"), NVRAM_FILE)
+    }
+
+    fn build(mode: Mode, display: Option<Display>, disk: VirtualDisk, nvram: &str) -> Self {
+        // 640K of conventional memory, the VGA window, extended memory up to
+        // the top and the VBE framebuffer; the BIOS maps its ROM when it is
+        // installed
+        let vga = Rc::new(RefCell::new(Vga::new()));
+        let mut mem = Memory::new();
+        mem.map_ram(0, VGA_BASE);
+        mem.map_device(VGA_BASE, VGA_SIZE, vga.clone());
+        mem.map_ram(0x10_0000, HUNDRED_MO - 0x10_0000);
+        let vbe = Rc::new(RefCell::new(Vbe::new()));
+        mem.map_device(LFB_BASE, VIDEO_MEMORY, vbe.clone());
+
+        let pic = Rc::new(RefCell::new(Pic::new()));
+        let mut io = IoBus::new();
+        io.register(PIC_MASTER, 2, pic.clone());
+        io.register(PIC_SLAVE, 2, pic.clone());
+        let pit = Rc::new(RefCell::new(Pit::new(pic.clone())));
+        io.register(VGA_PORTS, VGA_PORT_COUNT, vga.clone());
+        io.register(CRTC_INDEX, 2, vga.clone());
+        io.register(INPUT_STATUS_1, 1, vga.clone());
+        io.register(DISPI_INDEX, 2, vbe.clone());
+        io.register(PIT_BASE, 4, pit.clone());
+        io.register(PORT_B, 1, pit.clone());
+        let cmos = Rc::new(RefCell::new(Cmos::new(nvram, pic.clone())));
+        cmos.borrow_mut().configure(HUNDRED_MO, CYLINDERS, HEADS, SECTORS_PER_TRACK);
+        io.register(CMOS_INDEX, 2, cmos.clone());
+        let keyboard = Rc::new(RefCell::new(KeyboardController::new(pic.clone())));
+        io.register(KBD_DATA, 1, keyboard.clone());
+        io.register(KBD_STATUS, 1, keyboard.clone());
+        let serial: Vec<_> = COM_PORTS.iter()
+            .map(|&(base, irq)| Rc::new(RefCell::new(Uart::new(base, irq, pic.clone()))))
+            .collect();
+        for (uart, &(base, _)) in serial.iter().zip(COM_PORTS.iter()) {
+            io.register(base, UART_PORTS, uart.clone());
+        }
 
         Self {
             mode,
-            mem: Memory::new(HUNDRED_MO),
+            mem,
+            vga,
+            vbe,
+            io,
+            pic,
+            pit,
+            cmos,
+            keyboard,
+            serial,
+            leds: 0,
+            halted: false,
+            cpl: 0,
+            interrupt_shadow: false,
             gpr: GeneralPurposeRegisters::default(),
             ip: InstructionPointer::default(),
-            disk: VirtualDisk::new("cpu.vdisk"),
+            disk,
             flags: FlagsRegister::default(),
-            sdl_context,
-            canvas,
-            texture_creator,
-            font,
-            ttf_context: sdl2ttf_context,
+            cr: ControlRegisters::default(),
+            gdt: Gdt::default(),
+            idt: Idt::default(),
+            tr: TaskRegister::default(),
+            segments: SegmentCache::default(),
+            tlb: Tlb::default(),
+            kernel_gs_base: 0,
+            display,
+            last_frame: Instant::now(),
+            trace: true,
         }
     }
 
-    pub fn new(sdl2ttf_context: &'ttf Sdl2TtfContext) -> Self {
-        Self::with_mode(Mode::Real, sdl2ttf_context)
+    pub fn new() -> Self {
+        Self::with_mode(Mode::Real)
     }
 
     pub fn mode(&self) -> Mode {
@@ -82,309 +210,864 @@ impl<'ttf> Cpu<'ttf> {
     pub fn get_bit(&self) -> Bits {
         match self.mode {
             Mode::Real => Bits::Bit16,
-            Mode::Protected => Bits::Bit32,
+            Mode::Protected if self.segments.get(iced_x86::Register::CS).is_big() => Bits::Bit32,
+            Mode::Protected => Bits::Bit16,
             Mode::Long => Bits::Bit64
         }
     }
 
-    fn segmentation_to_physical(&self, seg: &iced_x86::Register, offset: u32) -> u32 {
-        assert_ne!(self.mode, Mode::Long);
-        let base = self.gpr.get_register_value(*seg) as u32;
-        if self.is_real() {
-            base * 16 + offset
-        } else {
-            base + offset
+    fn segmentation_to_physical(&self, seg: &iced_x86::Register, offset: u64) -> u64 {
+        // 64-bit mode ignores every segment base except FS and GS
+        if self.mode == Mode::Long && !matches!(seg, iced_x86::Register::FS | iced_x86::Register::GS) {
+            return offset;
         }
-
+        self.segments.get(*seg).base.wrapping_add(offset)
     }
 
+    // the VGA draws its own picture, the window only shows it
     pub fn vga_render(&mut self) {
-        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
-        self.canvas.clear();
-
-
-        let vga = self.vga_read(80 * 25);
-        for (i, &c) in vga.iter().enumerate() {
-            if c as u8 == 0 {
-                continue;
-            }
-            let x = (i % 80) as i32 * 8;
-            let y = (i / 80) as i32 * 16;
-            let surface = self.font.render_char(c).blended(Color::RGB(255, 255, 255)).unwrap();
-            let texture = self.texture_creator.create_texture_from_surface(&surface).unwrap();
-            let target = Rect::new(x, y, 8, 16);
-            self.canvas.copy(&texture, None, target).unwrap();
+        if self.display.is_none() || self.last_frame.elapsed() < FRAME_INTERVAL {
+            return;
+        }
+        self.last_frame = Instant::now();
+        // with the VBE display enabled the VGA is not scanned out
+        let mut vga = self.vga.borrow_mut();
+        let mut vbe = self.vbe.borrow_mut();
+        let (width, height, framebuffer) = if vbe.is_enabled() {
+            vbe.render(&vga.palette());
+            let (width, height) = vbe.resolution();
+            (width, height, vbe.framebuffer())
+        } else {
+            vga.render();
+            let (width, height) = vga.resolution();
+            (width, height, vga.framebuffer())
+        };
+        let pixels: Vec<u8> = framebuffer.iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+        drop(vbe);
+        drop(vga);
+        let Some(display) = self.display.as_mut() else {
+            return;
+        };
+        if width == 0 || height == 0 {
+            return;
         }
-        self.canvas.present();
+        let mut texture = display.texture_creator
+            .create_texture_streaming(PixelFormatEnum::ARGB8888, width as u32, height as u32)
+            .unwrap();
+        texture.update(None, &pixels, width * 4).unwrap();
+        display.canvas.copy(&texture, None, None).unwrap();
+        display.canvas.present();
     }
 
-    pub fn run_instr(&mut self, instr: Instruction) {
+    pub fn run_instr(&mut self, instr: Instruction) -> Result<(), Exception> {
         match instr.mnemonic() {
-            Mnemonic::Int => {
-                let int = instr.immediate8();
-                if !self.flags.is_interrupt() {
-                    return;
+            Mnemonic::Int => self.interrupt(instr.immediate8())?,
+            Mnemonic::Int3 => self.interrupt(3)?,
+            // ICEBP is delivered like a hardware debug exception
+            Mnemonic::Int1 => self.deliver(1, None, true)?,
+            Mnemonic::Into => {
+                if self.flags.get(OF) {
+                    self.interrupt(4)?;
                 }
-                self.handle_interrupt(int);
             },
-            Mnemonic::Lgdt => {
-                let addr = self.get_op0addr(instr).expect("gdt expected") as usize;
-                let limit = self.mem.read_u16(addr);
-                let base = self.mem.read_u32(addr + 2);
-
-
-                println!("limit: {}, base: {}", limit, base);
+            Mnemonic::Iret | Mnemonic::Iretd | Mnemonic::Iretq => self.iret(instr)?,
+            Mnemonic::Ud2 => match self.bios_trap_vector(instr) {
+                Some(vector) => self.bios_service(vector),
+                None => return Err(Exception::InvalidOpcode)
             },
+            Mnemonic::Lgdt => self.lgdt(instr)?,
+            Mnemonic::Sgdt => self.sgdt(instr)?,
+            Mnemonic::Lidt => self.lidt(instr)?,
+            Mnemonic::Sidt => self.sidt(instr)?,
+            Mnemonic::Ltr => self.ltr(instr)?,
+            Mnemonic::Str => self.str(instr)?,
+            Mnemonic::Smsw => self.smsw(instr)?,
+            Mnemonic::Lmsw => self.lmsw(instr)?,
+            Mnemonic::Invlpg => self.invlpg(instr)?,
+            Mnemonic::Rdmsr => self.rdmsr()?,
+            Mnemonic::Wrmsr => self.wrmsr()?,
+            Mnemonic::Swapgs => self.swapgs()?,
+            Mnemonic::Lds => self.load_far_pointer(instr, iced_x86::Register::DS)?,
+            Mnemonic::Les => self.load_far_pointer(instr, iced_x86::Register::ES)?,
+            Mnemonic::Lss => self.load_far_pointer(instr, iced_x86::Register::SS)?,
+            Mnemonic::Lfs => self.load_far_pointer(instr, iced_x86::Register::FS)?,
+            Mnemonic::Lgs => self.load_far_pointer(instr, iced_x86::Register::GS)?,
             Mnemonic::Cli => {
-                self.flags.no_interrupt();
+                self.check_iopl()?;
+                self.flags.set(IF, false);
+            },
+            Mnemonic::Sti => {
+                self.check_iopl()?;
+                // interrupts become visible after the next instruction
+                self.interrupt_shadow = !self.flags.get(IF);
+                self.flags.set(IF, true);
             },
             Mnemonic::Cmp => {
-                let op1 = self.get_op1value(instr);
-                let op0 = self.get_op0value(instr);
-                if op0 == op1 {
-                    self.flags.zero();
-                } else if op0 < op1 {
-                    self.flags.carry();
-                } else {
-                    self.flags.no_carry();
-                }
+                let bits = self.operand_bits(instr);
+                let op1 = self.get_op1value(instr)? as u64;
+                let op0 = self.get_op0value(instr)? as u64;
+                self.flags.sub(op0, op1, false, bits);
             }
             Mnemonic::Hlt => {
-                loop {}
+                if self.cpl() != 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.halted = true;
             },
-            Mnemonic::Jmp => {
-                let op0 = self.get_op0value(instr);
-                self.ip.rip = op0 as u64;
-                println!("Jump to {}", op0);
+            Mnemonic::Jmp => self.jump(instr)?,
+            Mnemonic::Jo | Mnemonic::Jno | Mnemonic::Jb | Mnemonic::Jae |
+            Mnemonic::Je | Mnemonic::Jne | Mnemonic::Jbe | Mnemonic::Ja |
+            Mnemonic::Js | Mnemonic::Jns | Mnemonic::Jp | Mnemonic::Jnp |
+            Mnemonic::Jl | Mnemonic::Jge | Mnemonic::Jle | Mnemonic::Jg => {
+                if self.flags.condition(instr.condition_code()) {
+                    self.ip.rip = instr.near_branch_target();
+                }
             },
-            Mnemonic::Je => {
-                if self.flags.is_zero() {
-                    let op0 = self.get_op0value(instr);
-                    self.ip.rip = op0 as u64;
+            Mnemonic::Jcxz | Mnemonic::Jecxz | Mnemonic::Jrcxz => {
+                let counter = Self::counter_register(instr.code());
+                if self.gpr.get_register_value(counter) == 0 {
+                    self.ip.rip = instr.near_branch_target();
                 }
             },
-            Mnemonic::Jne => {
-                if !self.flags.is_zero() {
-                    let op0 = self.get_op0value(instr);
-                    self.ip.rip = op0 as u64;
+            Mnemonic::Loop | Mnemonic::Loope | Mnemonic::Loopne => {
+                let counter = Self::counter_register(instr.code());
+                let count = self.gpr.get_register_value(counter).wrapping_sub(1);
+                self.gpr.set_register_value(counter, count as usize);
+                let count = self.gpr.get_register_value(counter);
+                if count != 0 && self.flags.condition(instr.condition_code()) {
+                    self.ip.rip = instr.near_branch_target();
                 }
             },
+            Mnemonic::Seto | Mnemonic::Setno | Mnemonic::Setb | Mnemonic::Setae |
+            Mnemonic::Sete | Mnemonic::Setne | Mnemonic::Setbe | Mnemonic::Seta |
+            Mnemonic::Sets | Mnemonic::Setns | Mnemonic::Setp | Mnemonic::Setnp |
+            Mnemonic::Setl | Mnemonic::Setge | Mnemonic::Setle | Mnemonic::Setg => {
+                let cond = self.flags.condition(instr.condition_code());
+                self.write_op0(instr, cond as usize)?;
+            },
+            Mnemonic::Cmovo | Mnemonic::Cmovno | Mnemonic::Cmovb | Mnemonic::Cmovae |
+            Mnemonic::Cmove | Mnemonic::Cmovne | Mnemonic::Cmovbe | Mnemonic::Cmova |
+            Mnemonic::Cmovs | Mnemonic::Cmovns | Mnemonic::Cmovp | Mnemonic::Cmovnp |
+            Mnemonic::Cmovl | Mnemonic::Cmovge | Mnemonic::Cmovle | Mnemonic::Cmovg => {
+                // the source is always read and a 32-bit destination is always
+                // zero-extended, even when the condition is false
+                let op1 = self.get_op1value(instr)?;
+                let op0 = self.get_op0value(instr)?;
+                let cond = self.flags.condition(instr.condition_code());
+                self.write_op0(instr, if cond { op1 } else { op0 })?;
+            },
+            Mnemonic::Push => self.push_instr(instr)?,
+            Mnemonic::Pop => self.pop_instr(instr)?,
+            Mnemonic::Pusha => self.pusha(Bits::Bit16)?,
+            Mnemonic::Pushad => self.pusha(Bits::Bit32)?,
+            Mnemonic::Popa => self.popa(Bits::Bit16)?,
+            Mnemonic::Popad => self.popa(Bits::Bit32)?,
+            Mnemonic::Pushf | Mnemonic::Pushfd | Mnemonic::Pushfq => {
+                let bits = Bits::from_size(-instr.stack_pointer_increment() as usize);
+                self.push(self.flags_image(), bits)?;
+            },
+            Mnemonic::Popf | Mnemonic::Popfd | Mnemonic::Popfq => {
+                let bits = Bits::from_size(instr.stack_pointer_increment() as usize);
+                let value = self.pop(bits)?;
+                let value = self.keep_privileged_flags(value, self.cpl());
+                self.load_flags_image(value, bits);
+            },
+            Mnemonic::Call => self.call(instr)?,
+            Mnemonic::Ret | Mnemonic::Retf => self.ret(instr)?,
+            Mnemonic::Enter => self.enter(instr)?,
+            Mnemonic::Leave => self.leave(instr)?,
+            Mnemonic::Lea => {
+                let bits = self.operand_bits(instr);
+                let offset = self.effective_address(instr) & bits.mask();
+                self.write_op0(instr, offset as usize)?;
+            },
             Mnemonic::Mov => {
-                let op1 = self.get_op1value(instr);
-                self.write_op0(instr, op1);
+                let op1 = self.get_op1value(instr)?;
+                self.write_op0(instr, op1)?;
             },
             Mnemonic::Add => {
-                let op1 = self.get_op1value(instr);
-                let op0 = self.get_op0value(instr);
-                self.write_op0(instr, op0 + op1);
+                let bits = self.operand_bits(instr);
+                let op1 = self.get_op1value(instr)? as u64;
+                let op0 = self.get_op0value(instr)? as u64;
+                let result = self.flags.add(op0, op1, false, bits);
+                self.write_op0(instr, result as usize)?;
             },
             Mnemonic::Sub => {
-                let op1 = self.get_op1value(instr);
-                let op0 = self.get_op0value(instr);
-                self.write_op0(instr, op0 - op1);
+                let bits = self.operand_bits(instr);
+                let op1 = self.get_op1value(instr)? as u64;
+                let op0 = self.get_op0value(instr)? as u64;
+                let result = self.flags.sub(op0, op1, false, bits);
+                self.write_op0(instr, result as usize)?;
+            },
+            Mnemonic::Adc => {
+                let bits = self.operand_bits(instr);
+                let op1 = self.get_op1value(instr)? as u64;
+                let op0 = self.get_op0value(instr)? as u64;
+                let carry = self.flags.get(CF);
+                let result = self.flags.add(op0, op1, carry, bits);
+                self.write_op0(instr, result as usize)?;
             },
+            Mnemonic::Sbb => {
+                let bits = self.operand_bits(instr);
+                let op1 = self.get_op1value(instr)? as u64;
+                let op0 = self.get_op0value(instr)? as u64;
+                let borrow = self.flags.get(CF);
+                let result = self.flags.sub(op0, op1, borrow, bits);
+                self.write_op0(instr, result as usize)?;
+            },
+            Mnemonic::Inc | Mnemonic::Dec => self.inc_dec(instr)?,
+            Mnemonic::Neg => self.neg(instr)?,
+            Mnemonic::Not => self.not(instr)?,
+            Mnemonic::Mul => self.mul(instr)?,
+            Mnemonic::Imul => self.imul(instr)?,
+            Mnemonic::Div => self.div(instr)?,
+            Mnemonic::Idiv => self.idiv(instr)?,
+            Mnemonic::Cbw => self.sign_extend_accumulator(Bits::Bit16),
+            Mnemonic::Cwde => self.sign_extend_accumulator(Bits::Bit32),
+            Mnemonic::Cdqe => self.sign_extend_accumulator(Bits::Bit64),
+            Mnemonic::Cwd => self.sign_extend_into_data(Bits::Bit16),
+            Mnemonic::Cdq => self.sign_extend_into_data(Bits::Bit32),
+            Mnemonic::Cqo => self.sign_extend_into_data(Bits::Bit64),
+            Mnemonic::Movzx => self.movzx(instr)?,
+            Mnemonic::Movsx | Mnemonic::Movsxd => self.movsx(instr)?,
             Mnemonic::And => {
-                let op1 = self.get_op1value(instr);
-                let op0 = self.get_op0value(instr);
-                self.write_op0(instr, op0 & op1);
+                let bits = self.operand_bits(instr);
+                let op1 = self.get_op1value(instr)? as u64;
+                let op0 = self.get_op0value(instr)? as u64;
+                let result = self.flags.logic(op0 & op1, bits);
+                self.write_op0(instr, result as usize)?;
             },
             Mnemonic::Or => {
-                let op1 = self.get_op1value(instr);
-                let op0 = self.get_op0value(instr);
-                self.write_op0(instr, op0 | op1);
+                let bits = self.operand_bits(instr);
+                let op1 = self.get_op1value(instr)? as u64;
+                let op0 = self.get_op0value(instr)? as u64;
+                let result = self.flags.logic(op0 | op1, bits);
+                self.write_op0(instr, result as usize)?;
+            },
+            Mnemonic::Xor => {
+                let bits = self.operand_bits(instr);
+                let op1 = self.get_op1value(instr)? as u64;
+                let op0 = self.get_op0value(instr)? as u64;
+                let result = self.flags.logic(op0 ^ op1, bits);
+                self.write_op0(instr, result as usize)?;
             },
+            Mnemonic::Test => {
+                let bits = self.operand_bits(instr);
+                let op1 = self.get_op1value(instr)? as u64;
+                let op0 = self.get_op0value(instr)? as u64;
+                self.flags.logic(op0 & op1, bits);
+            },
+            Mnemonic::Shl | Mnemonic::Sal | Mnemonic::Shr | Mnemonic::Sar |
+            Mnemonic::Rol | Mnemonic::Ror | Mnemonic::Rcl | Mnemonic::Rcr => self.shift(instr)?,
+            Mnemonic::Shld | Mnemonic::Shrd => self.double_shift(instr)?,
+            Mnemonic::Bt | Mnemonic::Bts | Mnemonic::Btr | Mnemonic::Btc => self.bit_test(instr)?,
+            Mnemonic::Bsf | Mnemonic::Bsr | Mnemonic::Tzcnt | Mnemonic::Lzcnt | Mnemonic::Popcnt => self.bit_scan(instr)?,
+            Mnemonic::Bswap => self.bswap(instr),
+            Mnemonic::Xchg => self.xchg(instr)?,
+            Mnemonic::Xadd => self.xadd(instr)?,
+            Mnemonic::Cmpxchg => self.cmpxchg(instr)?,
+            Mnemonic::Cld => {
+                self.flags.set(DF, false);
+            },
+            Mnemonic::Std => {
+                self.flags.set(DF, true);
+            },
+            Mnemonic::Movsb | Mnemonic::Movsw | Mnemonic::Movsq |
+            Mnemonic::Stosb | Mnemonic::Stosw | Mnemonic::Stosd | Mnemonic::Stosq |
+            Mnemonic::Lodsb | Mnemonic::Lodsw | Mnemonic::Lodsd | Mnemonic::Lodsq |
+            Mnemonic::Cmpsb | Mnemonic::Cmpsw | Mnemonic::Cmpsq |
+            Mnemonic::Scasb | Mnemonic::Scasw | Mnemonic::Scasd | Mnemonic::Scasq => self.string_op(instr)?,
+            Mnemonic::In => {
+                let reg = instr.op0_register();
+                let port = self.port_operand(instr, 1);
+                let value = self.port_in(port, Bits::from_size(reg.size()))?;
+                self.gpr.set_register_value(reg, value as usize);
+            },
+            Mnemonic::Out => {
+                let reg = instr.op1_register();
+                let port = self.port_operand(instr, 0);
+                let value = self.gpr.get_register_value(reg);
+                self.port_out(port, value, Bits::from_size(reg.size()))?;
+            },
+            Mnemonic::Insb | Mnemonic::Insw | Mnemonic::Insd |
+            Mnemonic::Outsb | Mnemonic::Outsw | Mnemonic::Outsd => self.string_op(instr)?,
+            // MOVSD and CMPSD share their mnemonic with the SSE instructions
+            Mnemonic::Movsd | Mnemonic::Cmpsd if Self::is_string_op(instr) => self.string_op(instr)?,
             e => {
-                println!("unhandled instruction: {:?}", e);
+                eprintln!("unhandled instruction: {:?}", e);
             }
         }
+        Ok(())
     }
 
     pub fn run(&mut self) {
-        let mut event_pump = self.sdl_context.event_pump().unwrap();
+        let mut event_pump = self.display.as_ref().unwrap().sdl_context.event_pump().unwrap();
         loop {
 
             for event in event_pump.poll_iter() {
                 match event {
-                    Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    Event::Quit {..} => {
+                        self.cmos.borrow_mut().save();
                         return;
                     }
+                    // the keyboard does its own typematic repeat
+                    Event::KeyDown { scancode: Some(scancode), repeat: false, .. } => {
+                        self.keyboard.borrow_mut().key(scancode, true);
+                    }
+                    Event::KeyUp { scancode: Some(scancode), .. } => {
+                        self.keyboard.borrow_mut().key(scancode, false);
+                    }
                     _ => {}
                 }
             }
-            let ip = self.ip.rip;
-            let bytes = self.mem.read_many_u8(ip as usize, 15);
-            let mut decoder = iced_x86::Decoder::new(self.get_bit().into(), &bytes, iced_x86::DecoderOptions::NONE);
-            let instr = decoder.decode();
-            println!("{}", instr);
-            self.ip.rip += instr.len() as u64;
-            self.run_instr(instr);
+            let ns = if self.halted { HALT_NS } else { INSTRUCTION_NS };
+            self.pit.borrow_mut().advance(ns);
+            self.cmos.borrow_mut().advance(ns);
+            self.keyboard.borrow_mut().advance(ns);
+            for uart in &self.serial {
+                uart.borrow_mut().advance(ns);
+            }
+            if self.flags.get(IF) && !self.interrupt_shadow {
+                let vector = self.pic.borrow_mut().acknowledge();
+                if let Some(vector) = vector {
+                    self.halted = false;
+                    self.external_interrupt(vector);
+                }
+            }
+            self.interrupt_shadow = false;
+            // the screen and the keyboard controller are still serviced while
+            // halted, a guest ending in CLI; HLT gets its last frame drawn
+            if self.halted {
+                std::thread::sleep(Duration::from_nanos(HALT_NS));
+            } else {
+                let ip = self.ip.rip;
+                if let Err(exception) = self.step() {
+                    // faults restart the faulting instruction once handled
+                    self.ip.rip = ip;
+                    self.raise(exception);
+                }
+                if self.trace {
+                    println!();
+                }
+            }
+            self.sync_keyboard_controller();
             self.vga_render();
-            println!();
         }
     }
 
+    pub fn step(&mut self) -> Result<(), Exception> {
+        let ip = self.ip.rip;
+        let bytes = self.fetch(ip)?;
+        let mut decoder = iced_x86::Decoder::new(self.get_bit().into(), &bytes, iced_x86::DecoderOptions::NONE);
+        decoder.set_ip(ip);
+        let instr = decoder.decode();
+        if decoder.last_error() == iced_x86::DecoderError::NoMoreBytes {
+            // the instruction runs into a page that is not mapped
+            let linear = self.segmentation_to_physical(&iced_x86::Register::CS, ip) + bytes.len() as u64;
+            self.translate(linear, Access::Execute, self.cpl() == 3)?;
+        }
+        if self.trace {
+            println!("{}", instr);
+        }
+        self.check_access(iced_x86::Register::CS, ip, instr.len(), Access::Execute)?;
+        self.ip.rip += instr.len() as u64;
+        self.run_instr(instr)
+    }
+
+    // up to 15 bytes, stopping early at a page that cannot be fetched from
+    fn fetch(&mut self, ip: u64) -> Result<Vec<u8>, Exception> {
+        let linear = self.segmentation_to_physical(&iced_x86::Register::CS, ip);
+        let user = self.cpl() == 3;
+        let addr = self.translate(linear, Access::Execute, user)?;
+        let first = (PAGE_MASK + 1 - (linear & PAGE_MASK)).min(15) as usize;
+        let mut bytes = self.mem.read_many_u8(addr as usize, first);
+        if first < 15 {
+            if let Ok(next) = self.translate(linear + first as u64, Access::Execute, user) {
+                bytes.extend(self.mem.read_many_u8(next as usize, 15 - first));
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn operand_bits(&self, instruction: Instruction) -> Bits {
+        match instruction.op0_kind() {
+            iced_x86::OpKind::Register => Bits::from_size(instruction.op0_register().size()),
+            iced_x86::OpKind::Memory => Bits::from_size(instruction.memory_size().size()),
+            _ => self.get_bit()
+        }
+    }
+
+    pub fn branch_target(&mut self, instruction: Instruction) -> Result<u64, Exception> {
+        match instruction.op0_kind() {
+            iced_x86::OpKind::NearBranch16 | iced_x86::OpKind::NearBranch32 | iced_x86::OpKind::NearBranch64 => {
+                Ok(instruction.near_branch_target())
+            },
+            _ => Ok(self.get_op0value(instruction)? as u64)
+        }
+    }
+
+    fn counter_register(code: Code) -> iced_x86::Register {
+        match code {
+            Code::Jecxz_rel8_16 | Code::Jecxz_rel8_32 | Code::Jecxz_rel8_64 |
+            Code::Loop_rel8_16_ECX | Code::Loop_rel8_32_ECX | Code::Loop_rel8_64_ECX |
+            Code::Loope_rel8_16_ECX | Code::Loope_rel8_32_ECX | Code::Loope_rel8_64_ECX |
+            Code::Loopne_rel8_16_ECX | Code::Loopne_rel8_32_ECX | Code::Loopne_rel8_64_ECX => iced_x86::Register::ECX,
+            Code::Jrcxz_rel8_16 | Code::Jrcxz_rel8_64 |
+            Code::Loop_rel8_16_RCX | Code::Loop_rel8_64_RCX |
+            Code::Loope_rel8_16_RCX | Code::Loope_rel8_64_RCX |
+            Code::Loopne_rel8_16_RCX | Code::Loopne_rel8_64_RCX => iced_x86::Register::RCX,
+            _ => iced_x86::Register::CX
+        }
+    }
+
+    pub fn address_bits(&self, instruction: Instruction) -> Bits {
+        let base = instruction.memory_base();
+        let index = instruction.memory_index();
+        let reg = if base != iced_x86::Register::None { base } else { index };
+        match reg {
+            iced_x86::Register::None => match instruction.memory_displ_size() {
+                2 => Bits::Bit16,
+                4 => Bits::Bit32,
+                8 => Bits::Bit64,
+                _ => self.get_bit()
+            },
+            iced_x86::Register::EIP => Bits::Bit32,
+            iced_x86::Register::RIP => Bits::Bit64,
+            reg => Bits::from_size(reg.size())
+        }
+    }
+
+    pub fn effective_address(&self, instruction: Instruction) -> u64 {
+        let bits = self.address_bits(instruction);
+        if instruction.is_ip_rel_memory_operand() {
+            return instruction.ip_rel_memory_address() & bits.mask();
+        }
+
+        let base = match instruction.memory_base() {
+            iced_x86::Register::None => 0,
+            reg => self.gpr.get_register_value(reg)
+        };
+        let index = match instruction.memory_index() {
+            iced_x86::Register::None => 0,
+            reg => self.gpr.get_register_value(reg)
+        };
+        let scale = instruction.memory_index_scale() as u64;
+
+        base.wrapping_add(index.wrapping_mul(scale))
+            .wrapping_add(instruction.memory_displacement64())
+            & bits.mask()
+    }
+
+    // memory_segment() already accounts for segment overrides and the SS
+    // default of BP/SP based addresses
+    pub fn memory_address(&self, instruction: Instruction) -> u64 {
+        let offset = self.effective_address(instruction);
+        self.segmentation_to_physical(&instruction.memory_segment(), offset)
+    }
+
     pub fn get_op0addr(&mut self, instruction: Instruction) -> Option<u64> {
         if instruction.op0_kind() != iced_x86::OpKind::Memory {
             None
         } else {
-            let base_register = instruction.memory_base();
-            let displacement = if self.mode == Mode::Real || self.mode == Mode::Protected {
-                instruction.memory_displacement32() as u64
-            } else {
-                instruction.memory_displacement64()
-            };
-
-            if let iced_x86::Register::None = base_register {
-                Some(displacement)
-            } else {
-                let base_value = self.gpr.get_register_value(base_register);
-                Some(base_value + displacement)
-            }
+            Some(self.memory_address(instruction))
         }
     }
 
-    pub fn get_op0value(&mut self, instruction: Instruction) -> usize {
+    pub fn get_op0value(&mut self, instruction: Instruction) -> Result<usize, Exception> {
         let op0 = instruction.op0_kind();
         match op0 {
             iced_x86::OpKind::Register => {
-                let reg = instruction.op1_register();
-                self.gpr.get_register_value(reg) as usize
+                let reg = instruction.op0_register();
+                Ok(self.read_register(reg) as usize)
             },
             iced_x86::OpKind::Memory => {
-                let offset = if self.get_bit() == Bits::Bit64 {
-                    instruction.memory_displacement64()
-                } else {
-                    instruction.memory_displacement32() as u64
-                };
-                let physical = self.segmentation_to_physical(&instruction.segment_prefix(), offset as u32);
-                self.mem.read_u32(physical as usize) as usize
+                let (seg, offset) = self.operand_location(instruction);
+                let bits = Bits::from_size(instruction.memory_size().size());
+                Ok(self.read_memory(seg, offset, bits)? as usize)
             },
-            _ => 0
+            _ => Ok(0)
         }
     }
 
-    pub fn get_op1value(&mut self, instruction: Instruction) -> usize {
+    pub fn get_op1value(&mut self, instruction: Instruction) -> Result<usize, Exception> {
         let op1 = instruction.op1_kind();
-        match op1 {
+        Ok(match op1 {
             iced_x86::OpKind::Register => {
                 let reg = instruction.op1_register();
-                self.gpr.get_register_value(reg) as usize
+                self.read_register(reg) as usize
             },
             iced_x86::OpKind::Memory => {
-                let offset = if self.get_bit() == Bits::Bit64 {
-                    instruction.memory_displacement64()
-                } else {
-                    instruction.memory_displacement32() as u64
-                };
-                let physical = self.segmentation_to_physical(&instruction.segment_prefix(), offset as u32);
-                self.mem.read_u32(physical as usize) as usize
+                let (seg, offset) = self.operand_location(instruction);
+                let bits = Bits::from_size(instruction.memory_size().size());
+                self.read_memory(seg, offset, bits)? as usize
             },
             iced_x86::OpKind::Immediate8 => instruction.immediate8() as usize,
             iced_x86::OpKind::Immediate16 => instruction.immediate16() as usize,
             iced_x86::OpKind::Immediate32 => instruction.immediate32() as usize,
             iced_x86::OpKind::Immediate64 => instruction.immediate64() as usize,
+            iced_x86::OpKind::Immediate8to16 => instruction.immediate8to16() as u16 as usize,
+            iced_x86::OpKind::Immediate8to32 => instruction.immediate8to32() as u32 as usize,
+            iced_x86::OpKind::Immediate8to64 => instruction.immediate8to64() as usize,
+            iced_x86::OpKind::Immediate32to64 => instruction.immediate32to64() as usize,
             _ => 0
-        }
+        })
     }
 
-    pub fn write_op0(&mut self, instruction: Instruction, val: usize) {
+    pub fn write_op0(&mut self, instruction: Instruction, val: usize) -> Result<(), Exception> {
         let op0 = instruction.op0_kind();
         match op0 {
             iced_x86::OpKind::Register => {
                 let reg = instruction.op0_register();
-                self.gpr.set_register_value(reg, val);
+                self.write_register(reg, val as u64)
             },
             iced_x86::OpKind::Memory => {
-                if self.mode != Mode::Long {
-                    let offset = if self.get_bit() == Bits::Bit64 {
-                        instruction.memory_displacement64()
-                    } else {
-                        instruction.memory_displacement32() as u64
-                    };
-                    let physical = self.segmentation_to_physical(&instruction.segment_prefix(), offset as u32);
-                    self.write_to_mem(physical as usize, val);
-                }
+                let (seg, offset) = self.operand_location(instruction);
+                let bits = Bits::from_size(instruction.memory_size().size());
+                self.write_memory(seg, offset, val as u64, bits)
             },
-            _ => {}
+            _ => Ok(())
         }
     }
 
-    pub fn write_to_mem(&mut self, addr: usize, value: usize) {
-        match self.get_bit() {
-            Bits::Bit8 => self.mem.write_u8(addr, value as u8),
-            Bits::Bit16 => self.mem.write_u16(addr, value as u16),
-            Bits::Bit32 => self.mem.write_u32(addr, value as u32),
-            Bits::Bit64 => self.mem.write_u64(addr, value as u64),
-
+    pub fn write_op1(&mut self, instruction: Instruction, val: usize) -> Result<(), Exception> {
+        match instruction.op1_kind() {
+            iced_x86::OpKind::Register => {
+                let reg = instruction.op1_register();
+                self.write_register(reg, val as u64)
+            },
+            iced_x86::OpKind::Memory => {
+                let (seg, offset) = self.operand_location(instruction);
+                let bits = Bits::from_size(instruction.memory_size().size());
+                self.write_memory(seg, offset, val as u64, bits)
+            },
+            _ => Ok(())
         }
     }
 
-    pub fn vga_read(&self, size: usize) -> Vec<char> {
-        let mut vga = Vec::new();
-        for i in 0..size {
-            let c = self.mem.read_u8(0xb8000 + i);
-            vga.push(c as char);
+    pub fn port_in(&mut self, port: u16, bits: Bits) -> Result<u64, Exception> {
+        self.check_io(port, bits)?;
+        Ok(self.io.read(port, bits))
+    }
+
+    pub fn port_out(&mut self, port: u16, value: u64, bits: Bits) -> Result<(), Exception> {
+        self.check_io(port, bits)?;
+        self.io.write(port, value, bits);
+        Ok(())
+    }
+
+    // IN and OUT take the port from an immediate byte or from DX
+    fn port_operand(&self, instruction: Instruction, operand: u32) -> u16 {
+        match instruction.op_kind(operand) {
+            iced_x86::OpKind::Immediate8 => instruction.immediate8() as u16,
+            _ => self.gpr.get_register_value(iced_x86::Register::DX) as u16
         }
-        vga
     }
-    pub fn vga_read_chars(&self) -> Vec<char> {
-        self.vga_read(80 * 25)
+
+    pub fn write_to_mem(&mut self, addr: usize, value: usize, bits: Bits) {
+        self.mem.write(addr, value as u64, bits);
     }
-    pub fn console(&self) {
-        let vga = self.vga_read_chars();
 
-        for i in 0..vga.len() {
-            if (vga[i] as u8) == 0 {
-                continue;
-            }
-            print!("{}", vga[i]);
-            std::io::stdout().flush().unwrap();
-            if (i + 1) % 80 == 0 {
-                println!();
+    // the 8042 output port drives the A20 gate and can pulse the reset line;
+    // its LEDs show up in the window title
+    fn sync_keyboard_controller(&mut self) {
+        let (a20, reset, leds) = {
+            let mut keyboard = self.keyboard.borrow_mut();
+            (keyboard.a20(), keyboard.take_reset(), keyboard.leds())
+        };
+        if reset {
+            eprintln!("reset through the keyboard controller");
+            self.reset();
+            return;
+        }
+        self.mem.set_a20(a20);
+        if leds != self.leds {
+            self.leds = leds;
+            let names = [(4, "CAPS"), (2, "NUM"), (1, "SCROLL")];
+            let lit: Vec<&str> = names.iter().filter(|(bit, _)| leds & bit != 0).map(|(_, name)| *name).collect();
+            let title = if lit.is_empty() { "XVm".to_string() } else { format!("XVm [{}]", lit.join(" ")) };
+            if let Some(display) = self.display.as_mut() {
+                display.canvas.window_mut().set_title(&title).unwrap();
             }
         }
+    }
 
+    // COM1 is port 0
+    pub fn attach_serial(&mut self, port: usize, backend: Box<dyn SerialBackend>) {
+        if backend.uses_stdout() {
+            self.trace = false;
+        }
+        self.serial[port].borrow_mut().attach(backend);
     }
 
-    pub fn handle_interrupt(&mut self, int: u8) {
-        match int {
-            0x10 => {
-                let ah = self.gpr.get_register_value(iced_x86::Register::AH);
-                match ah {
-                    0x0e => {
-                        let mut i = 0;
-                        loop {
-                            let m = self.mem.read_u16(0xb8000 + i);
-                            if m == 0 {
-                                break;
-                            }
-                            i += 1;
-                        }
-                        let al = self.gpr.get_register_value(iced_x86::Register::AL);
-                        self.write_to_mem(0xb8000 + i, al as usize);
-                    },
-                    _ => {}
-                }
-            },
-            _ => {}
+    // seconds since the epoch, for runs that must not depend on the host clock
+    pub fn set_rtc_time(&mut self, seconds: i64) {
+        self.cmos.borrow_mut().set_time(seconds);
+    }
+
+    // triple fault: back to the power-on state and boot again
+    pub fn reset(&mut self) {
+        self.mode = Mode::Real;
+        self.gpr = GeneralPurposeRegisters::default();
+        self.ip = InstructionPointer::default();
+        self.flags = FlagsRegister::default();
+        self.cr = ControlRegisters::default();
+        self.gdt = Gdt::default();
+        self.idt = Idt::default();
+        self.tr = TaskRegister::default();
+        self.segments = SegmentCache::default();
+        self.tlb.flush(false);
+        self.kernel_gs_base = 0;
+        self.halted = false;
+        self.cpl = 0;
+        self.interrupt_shadow = false;
+        *self.pic.borrow_mut() = Pic::new();
+        *self.pit.borrow_mut() = Pit::new(self.pic.clone());
+        *self.keyboard.borrow_mut() = KeyboardController::new(self.pic.clone());
+        self.mem.set_a20(true);
+        self.cmos.borrow_mut().save();
+        for uart in &self.serial {
+            uart.borrow_mut().reset();
         }
+        self.init_bios();
     }
 
     pub fn init_bios(&mut self) {
-        self.gpr.set_register_value(iced_x86::Register::CS, 0xF000);
-        self.gpr.set_register_value(iced_x86::Register::DS, 0x0);
-        self.gpr.set_register_value(iced_x86::Register::SS, 0x0);
+        self.load_segment(iced_x86::Register::CS, 0x0).unwrap();
+        self.load_segment(iced_x86::Register::DS, 0x0).unwrap();
+        self.load_segment(iced_x86::Register::SS, 0x0).unwrap();
         self.gpr.set_register_value(iced_x86::Register::SP, 0x7C00);
+        self.gpr.set_register_value(iced_x86::Register::DL, 0x80);
 
-        self.ip.rip = 0xFFF0;
+        self.ip.rip = 0x7C00;
+        self.flags.set(IF, true);
+        self.install_bios();
 
         let bootloader = self.disk.read_sector(0);
 
         for i in 0..bootloader.len() {
-            self.mem.write_u8(0xFFF0 + i, bootloader[i]);
+            self.mem.write_u8(0x7C00 + i, bootloader[i]);
         }
     }
 
 
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::cpu::protected::CR0_PE;
+    use crate::vm::flags::IOPL;
+    use crate::vm::segment::SegmentDescriptor;
+
+    const CODE: usize = 0x7C00;
+    const GDT_BASE: usize = 0x500;
+
+    // no window, a scratch disk and an NVRAM file that never gets written
+    fn cpu() -> Cpu {
+        let disk = std::env::temp_dir().join("xvm-test.vdisk");
+        let mut cpu = Cpu::build(Mode::Real, None, VirtualDisk::new(disk.to_str().unwrap()), "/nonexistent/cpu.nvram");
+        cpu.trace = false;
+        cpu
+    }
+
+    fn load(cpu: &mut Cpu, addr: usize, code: &[u8]) {
+        for (i, &byte) in code.iter().enumerate() {
+            cpu.mem.write_u8(addr + i, byte);
+        }
+    }
+
+    const TSS_BASE: usize = 0x600;
+    const IDT_BASE: usize = 0x800;
+    const HANDLER: u64 = 0x1000;
+
+    // null, flat 32-bit code and data for ring 0 at 08h/10h and ring 3 at
+    // 18h/20h, and a ring 0 stack at 28h that is only 16 bytes long
+    const DESCRIPTORS: [u64; 6] = [
+        0,
+        0x00CF_9A00_0000_FFFF,
+        0x00CF_9200_0000_FFFF,
+        0x00CF_FA00_0000_FFFF,
+        0x00CF_F200_0000_FFFF,
+        0x0040_9200_0000_000F,
+    ];
+
+    fn flat_gdt(cpu: &mut Cpu) {
+        for (i, desc) in DESCRIPTORS.into_iter().enumerate() {
+            cpu.mem.write(GDT_BASE + i * 8, desc, Bits::Bit64);
+        }
+        cpu.gdt = Gdt::new(GDT_BASE as u64, (DESCRIPTORS.len() * 8 - 1) as u16);
+    }
+
+    fn descriptor(selector: u16) -> SegmentDescriptor {
+        SegmentDescriptor::from_raw(DESCRIPTORS[selector as usize >> 3])
+    }
+
+    // ring 3 code at 1Bh:0, a DPL 3 interrupt gate at 80h into HANDLER and
+    // the given ring 0 stack in the TSS
+    fn ring3(cpu: &mut Cpu, ss0: u16, esp0: u32) {
+        flat_gdt(cpu);
+        cpu.cr.cr0 |= CR0_PE;
+        cpu.mode = Mode::Protected;
+        cpu.set_code_segment(0x1B, descriptor(0x18), 3);
+        cpu.set_segment(iced_x86::Register::SS, 0x23, descriptor(0x20));
+        cpu.gpr.set_register_value(iced_x86::Register::ESP, 0x8000);
+        cpu.ip.rip = 0;
+        cpu.tr = TaskRegister { selector: 0x30, base: TSS_BASE as u64, limit: 0x67 };
+        cpu.mem.write_u32(TSS_BASE + 4, esp0);
+        cpu.mem.write_u16(TSS_BASE + 8, ss0);
+        cpu.idt.base = IDT_BASE as u64;
+        cpu.idt.limit = 0x7FF;
+        cpu.mem.write(IDT_BASE + 0x80 * 8, 0x0000_EE00_0008_0000 | HANDLER, Bits::Bit64);
+    }
+
+    #[test]
+    fn far_jump_into_protected_mode() {
+        let mut cpu = cpu();
+        flat_gdt(&mut cpu);
+        load(&mut cpu, CODE, &[
+            0x0F, 0x20, 0xC0,             // mov eax, cr0
+            0x66, 0x83, 0xC8, 0x01,       // or eax, 1
+            0x0F, 0x22, 0xC0,             // mov cr0, eax
+            0xEA, 0x20, 0x7C, 0x08, 0x00, // jmp 0008h:7C20h
+        ]);
+        // 32-bit code at the target
+        load(&mut cpu, CODE + 0x20, &[0xB8, 0x78, 0x56, 0x34, 0x12]);
+        cpu.ip.rip = CODE as u64;
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert!(cpu.is_protected());
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::CS), 0x08);
+        assert_eq!(cpu.ip.rip, 0x7C20);
+        cpu.step().unwrap();
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::EAX), 0x1234_5678);
+    }
+
+    // real mode leaves RPL 3 in CS, the code still runs at CPL 0
+    #[test]
+    fn cpl_is_0_until_the_far_jump() {
+        let mut cpu = cpu();
+        flat_gdt(&mut cpu);
+        cpu.load_segment(iced_x86::Register::CS, 0x07C3).unwrap();
+        load(&mut cpu, 0x7C30, &[
+            0x0F, 0x20, 0xC0,             // mov eax, cr0
+            0x66, 0x83, 0xC8, 0x01,       // or eax, 1
+            0x0F, 0x22, 0xC0,             // mov cr0, eax
+            0x0F, 0x20, 0xC0,             // mov eax, cr0
+            0xEA, 0x00, 0x7D, 0x08, 0x00, // jmp 0008h:7D00h
+        ]);
+        cpu.ip.rip = 0;
+        for _ in 0..5 {
+            cpu.step().unwrap();
+            assert_eq!(cpu.cpl(), 0);
+        }
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::CS), 0x08);
+    }
+
+    #[test]
+    fn interrupt_to_ring0_and_back() {
+        let mut cpu = cpu();
+        ring3(&mut cpu, 0x10, 0x9000);
+        cpu.flags.set(IF, true);
+        cpu.interrupt(0x80).unwrap();
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::CS), 0x08);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::SS), 0x10);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::ESP), 0x9000 - 20);
+        assert_eq!(cpu.ip.rip, HANDLER);
+        assert!(!cpu.flags.get(IF));
+        let frame: Vec<u32> = (0..5).map(|i| cpu.mem.read_u32(0x9000 - 20 + i * 4)).collect();
+        assert_eq!([frame[0], frame[1], frame[3], frame[4]], [0, 0x1B, 0x8000, 0x23]);
+
+        load(&mut cpu, HANDLER as usize, &[0xCF]); // iretd
+        cpu.step().unwrap();
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::CS), 0x1B);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::SS), 0x23);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::ESP), 0x8000);
+        assert_eq!(cpu.cpl(), 3);
+        assert!(cpu.flags.get(IF));
+    }
+
+    // ring 3 must not get back the ring 0 data segment the handler loaded
+    #[test]
+    fn outward_iret_nulls_inner_data_segments() {
+        let mut cpu = cpu();
+        ring3(&mut cpu, 0x10, 0x9000);
+        cpu.load_segment(iced_x86::Register::ES, 0x23).unwrap();
+        cpu.interrupt(0x80).unwrap();
+        // mov ax, 0x10; mov ds, ax; iretd
+        load(&mut cpu, HANDLER as usize, &[0x66, 0xB8, 0x10, 0x00, 0x8E, 0xD8, 0xCF]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::DS), 0x10);
+        cpu.step().unwrap();
+        assert_eq!(cpu.cpl(), 3);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::DS), 0);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::ES), 0x23);
+    }
+
+    #[test]
+    fn outward_retf_nulls_inner_data_segments() {
+        let mut cpu = cpu();
+        ring3(&mut cpu, 0x10, 0x9000);
+        cpu.interrupt(0x80).unwrap();
+        cpu.load_segment(iced_x86::Register::FS, 0x10).unwrap();
+        // the ring 0 stack holds eip, cs, esp, ss for the return
+        let sp = 0x9000 - 16;
+        for (i, value) in [0x40, 0x1B, 0x7F00, 0x23].iter().enumerate() {
+            cpu.mem.write_u32(sp + i * 4, *value);
+        }
+        cpu.gpr.set_register_value(iced_x86::Register::ESP, sp);
+        load(&mut cpu, HANDLER as usize, &[0xCB]); // retf
+        cpu.step().unwrap();
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::CS), 0x1B);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::SS), 0x23);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::ESP), 0x7F00);
+        assert_eq!(cpu.ip.rip, 0x40);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::FS), 0);
+    }
+
+    // the ring 0 stack cannot hold the frame: the fault leaves the
+    // interrupted context as it was
+    #[test]
+    fn faulting_frame_keeps_the_context() {
+        let mut cpu = cpu();
+        ring3(&mut cpu, 0x28, 0x20);
+        assert_eq!(cpu.interrupt(0x80), Err(Exception::StackFault(0)));
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::CS), 0x1B);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::SS), 0x23);
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::ESP), 0x8000);
+        assert_eq!(cpu.cpl(), 3);
+    }
+
+    #[test]
+    fn ring3_popf_keeps_iopl_and_if() {
+        let mut cpu = cpu();
+        ring3(&mut cpu, 0x10, 0x9000);
+        load(&mut cpu, 0, &[0x9D, 0x9D]); // popfd; popfd
+        cpu.mem.write_u32(0x8000, (IOPL | IF | CF) as u32);
+        cpu.step().unwrap();
+        assert_eq!(cpu.flags.flags & (IOPL | IF | CF), CF);
+        // with IOPL 3 set from ring 0, ring 3 may change IF but not IOPL
+        cpu.flags.flags |= IOPL;
+        cpu.mem.write_u32(0x8004, IF as u32);
+        cpu.step().unwrap();
+        assert_eq!(cpu.flags.flags & (IOPL | IF | CF), IOPL | IF);
+    }
+
+    // a word at SS:FFFF runs past the 64K limit
+    #[test]
+    fn faulting_push_keeps_sp() {
+        let mut cpu = cpu();
+        load(&mut cpu, CODE, &[0x50, 0x60]); // push ax; pusha
+        cpu.ip.rip = CODE as u64;
+        cpu.gpr.set_register_value(iced_x86::Register::SP, 1);
+        assert_eq!(cpu.step(), Err(Exception::StackFault(0)));
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::SP), 1);
+        // three of the eight words fit
+        cpu.ip.rip = CODE as u64 + 1;
+        cpu.gpr.set_register_value(iced_x86::Register::SP, 7);
+        assert_eq!(cpu.step(), Err(Exception::StackFault(0)));
+        assert_eq!(cpu.gpr.get_register_value(iced_x86::Register::SP), 7);
+    }
+}
@@ -0,0 +1,147 @@
+use iced_x86::{Instruction, Register};
+use crate::ast::Bits;
+use crate::vm::cpu::Cpu;
+use crate::vm::exception::Exception;
+use crate::vm::paging::PAGE_MASK;
+use crate::vm::segment::{SegmentRegister, CODE_DATA, CONFORMING_EXPAND_DOWN, EXECUTABLE, PRESENT, READ_WRITE};
+use crate::vm::Mode;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Cpu {
+    pub fn cpl(&self) -> u8 {
+        match self.mode {
+            Mode::Real => 0,
+            _ => self.cpl
+        }
+    }
+
+    fn segment_fault(seg: Register, code: u16) -> Exception {
+        if seg == Register::SS {
+            Exception::StackFault(code)
+        } else {
+            Exception::GeneralProtection(code)
+        }
+    }
+
+    // limit and type checks against the hidden descriptor cache, returning
+    // the linear address of the access
+    pub fn check_access(&self, seg: Register, offset: u64, size: usize, access: Access) -> Result<u64, Exception> {
+        if self.mode == Mode::Long {
+            return Self::check_canonical(seg, self.segmentation_to_physical(&seg, offset), size);
+        }
+        self.check_segment(seg, self.segments.get(seg), offset, size, access)
+    }
+
+    // bits 63..47 must all match bit 47
+    pub fn check_canonical(seg: Register, linear: u64, size: usize) -> Result<u64, Exception> {
+        let canonical = |addr: u64| ((addr as i64) << 16 >> 16) as u64 == addr;
+        if !canonical(linear) || !canonical(linear.wrapping_add(size as u64 - 1)) {
+            return Err(Self::segment_fault(seg, 0));
+        }
+        Ok(linear)
+    }
+
+    // the same checks against a cache that need not be loaded yet, as for
+    // the stack an interrupt switches to
+    pub fn check_segment(&self, seg: Register, cache: &SegmentRegister, offset: u64, size: usize, access: Access) -> Result<u64, Exception> {
+        let last = offset + size as u64 - 1;
+
+        if self.is_protected() {
+            if cache.flags & PRESENT == 0 || cache.flags & CODE_DATA == 0 {
+                return Err(Self::segment_fault(seg, 0));
+            }
+            let allowed = match access {
+                Access::Execute => cache.is_code(),
+                // code segments are readable only with the R bit set
+                Access::Read => !cache.is_code() || cache.flags & READ_WRITE != 0,
+                Access::Write => !cache.is_code() && cache.flags & READ_WRITE != 0,
+            };
+            if !allowed {
+                return Err(Self::segment_fault(seg, 0));
+            }
+        }
+
+        let expand_down = cache.flags & (EXECUTABLE | CONFORMING_EXPAND_DOWN) == CONFORMING_EXPAND_DOWN;
+        let within = if expand_down {
+            let upper = if cache.is_big() { 0xFFFF_FFFF } else { 0xFFFF };
+            offset > cache.limit as u64 && last <= upper
+        } else {
+            last <= cache.limit as u64
+        };
+        if !within {
+            return Err(Self::segment_fault(seg, 0));
+        }
+        Ok(cache.base + offset)
+    }
+
+    pub fn read_memory(&mut self, seg: Register, offset: u64, bits: Bits) -> Result<u64, Exception> {
+        let linear = self.check_access(seg, offset, bits.bytes(), Access::Read)?;
+        self.read_linear(linear, bits, Access::Read, self.cpl() == 3)
+    }
+
+    pub fn write_memory(&mut self, seg: Register, offset: u64, value: u64, bits: Bits) -> Result<(), Exception> {
+        let linear = self.check_access(seg, offset, bits.bytes(), Access::Write)?;
+        self.write_linear(linear, value, bits, self.cpl() == 3)
+    }
+
+    // descriptor table and TSS accesses are supervisor accesses whatever the CPL
+    pub fn read_system(&mut self, linear: u64, bits: Bits) -> Result<u64, Exception> {
+        self.read_linear(linear, bits, Access::Read, false)
+    }
+
+    pub fn write_system(&mut self, linear: u64, value: u64, bits: Bits) -> Result<(), Exception> {
+        self.write_linear(linear, value, bits, false)
+    }
+
+    // physical addresses of the first and last byte, both pages are checked
+    // before anything is touched so a split access never half-completes
+    fn translate_span(&mut self, linear: u64, size: usize, access: Access, user: bool) -> Result<(u64, u64), Exception> {
+        let first = self.translate(linear, access, user)?;
+        let last = self.translate(linear.wrapping_add(size as u64 - 1), access, user)?;
+        Ok((first, last))
+    }
+
+    fn span_address(linear: u64, first: u64, last: u64, i: usize) -> usize {
+        let split = (PAGE_MASK + 1 - (linear & PAGE_MASK)) as usize;
+        if i < split {
+            first as usize + i
+        } else {
+            (last & !PAGE_MASK) as usize + i - split
+        }
+    }
+
+    pub fn read_linear(&mut self, linear: u64, bits: Bits, access: Access, user: bool) -> Result<u64, Exception> {
+        let (first, last) = self.translate_span(linear, bits.bytes(), access, user)?;
+        if last == first + bits.bytes() as u64 - 1 {
+            return Ok(self.mem.read(first as usize, bits));
+        }
+        let mut value = 0;
+        for i in 0..bits.bytes() {
+            let byte = self.mem.read_u8(Self::span_address(linear, first, last, i));
+            value |= (byte as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    pub fn write_linear(&mut self, linear: u64, value: u64, bits: Bits, user: bool) -> Result<(), Exception> {
+        let (first, last) = self.translate_span(linear, bits.bytes(), Access::Write, user)?;
+        if last == first + bits.bytes() as u64 - 1 {
+            self.mem.write(first as usize, value, bits);
+            return Ok(());
+        }
+        for i in 0..bits.bytes() {
+            self.mem.write_u8(Self::span_address(linear, first, last, i), (value >> (i * 8)) as u8);
+        }
+        Ok(())
+    }
+
+    pub fn operand_location(&self, instr: Instruction) -> (Register, u64) {
+        (instr.memory_segment(), self.effective_address(instr))
+    }
+}
@@ -0,0 +1,232 @@
+use iced_x86::{Instruction, Register};
+use crate::ast::Bits;
+use crate::vm::cmos::{CMOS_DATA, CMOS_INDEX};
+use crate::vm::cpu::Cpu;
+use crate::vm::cpu::video::{FONT_8X14_OFFSET, FONT_8X16_OFFSET, FONT_8X8_OFFSET, FONT_8X8_UPPER_OFFSET};
+use crate::vm::flags::{CF, IF, ZF};
+use crate::vm::font::{FONT_8X14, FONT_8X16, FONT_8X8};
+use crate::vm::keyboard::KBD_DATA;
+use crate::vm::serial::COM_PORTS;
+
+pub const BIOS_SEGMENT: u16 = 0xF000;
+// every vector gets a four byte stub: UD2 traps into bios_service, then IRET
+const STUB_OFFSET: u16 = 0xE000;
+const STUB_SIZE: u16 = 4;
+const STUB: [u8; STUB_SIZE as usize] = [0x0F, 0x0B, 0xCF, 0x90];
+
+// BIOS data area timer count, bumped by IRQ0 at 18.2 Hz
+const BDA_TICKS: usize = 0x46C;
+const BDA_MIDNIGHT: usize = 0x470;
+const TICKS_PER_DAY: u32 = 0x18_00B0;
+
+// I/O addresses of COM1-COM4
+const BDA_COM_PORTS: usize = 0x400;
+
+// keyboard flags and the 16 entry type-ahead ring of scan code/ASCII words
+const BDA_KEYBOARD_FLAGS: usize = 0x417;
+const BDA_KEYBOARD_HEAD: usize = 0x41A;
+const BDA_KEYBOARD_TAIL: usize = 0x41C;
+const KEYBOARD_BUFFER: u16 = 0x1E;
+const KEYBOARD_BUFFER_END: u16 = 0x3E;
+const RIGHT_SHIFT: u8 = 1 << 0;
+const LEFT_SHIFT: u8 = 1 << 1;
+const CTRL: u8 = 1 << 2;
+const ALT: u8 = 1 << 3;
+
+// ASCII for the set 1 scan codes up to the space bar
+const ASCII: &[u8; 0x3A] = b"\x00\x1b1234567890-=\x08\tqwertyuiop[]\r\x00asdfghjkl;'`\x00\\zxcvbnm,./\x00*\x00 ";
+const ASCII_SHIFT: &[u8; 0x3A] = b"\x00\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\r\x00ASDFGHJKL:\"~\x00|ZXCVBNM<>?\x00*\x00 ";
+
+impl Cpu {
+    // the 64K system BIOS segment is mapped read-only at F0000
+    pub fn install_bios(&mut self) {
+        let mut rom = vec![0xFF; 0x10000];
+        for vector in 0..256usize {
+            let offset = STUB_OFFSET + vector as u16 * STUB_SIZE;
+            rom[offset as usize..offset as usize + STUB.len()].copy_from_slice(&STUB);
+            self.mem.write_u16(vector * 4, offset);
+            self.mem.write_u16(vector * 4 + 2, BIOS_SEGMENT);
+        }
+        for (offset, font) in [(FONT_8X16_OFFSET, &FONT_8X16[..]), (FONT_8X14_OFFSET, &FONT_8X14[..]), (FONT_8X8_OFFSET, &FONT_8X8[..])] {
+            rom[offset as usize..offset as usize + font.len()].copy_from_slice(font);
+        }
+        for (vector, offset) in [(0x1F, FONT_8X8_UPPER_OFFSET), (0x43, FONT_8X8_OFFSET)] {
+            self.mem.write_u16(vector * 4, offset);
+        }
+        self.mem.map_rom((BIOS_SEGMENT as usize) << 4, rom);
+        for (i, &(base, _)) in COM_PORTS.iter().enumerate() {
+            self.mem.write_u16(BDA_COM_PORTS + i * 2, base);
+        }
+        self.mem.write_u16(BDA_KEYBOARD_HEAD, KEYBOARD_BUFFER);
+        self.mem.write_u16(BDA_KEYBOARD_TAIL, KEYBOARD_BUFFER);
+        // 80x25 colour text with a blank screen
+        self.set_video_mode(0x03);
+
+        // channel 0 as an 18.2 Hz square wave, the full 65536 count
+        self.io.write(0x43, 0x36, Bits::Bit8);
+        self.io.write(0x40, 0, Bits::Bit8);
+        self.io.write(0x40, 0, Bits::Bit8);
+    }
+
+    pub fn bios_trap_vector(&self, instr: Instruction) -> Option<u8> {
+        if !self.is_real() || self.gpr.get_register_value(Register::CS) != BIOS_SEGMENT as u64 {
+            return None;
+        }
+        let offset = instr.ip().checked_sub(STUB_OFFSET as u64)?;
+        if offset % STUB_SIZE as u64 != 0 || offset / (STUB_SIZE as u64) > 0xFF {
+            return None;
+        }
+        Some((offset / STUB_SIZE as u64) as u8)
+    }
+
+    pub fn bios_service(&mut self, vector: u8) {
+        // hardware interrupt handlers end with an EOI to the PIC
+        let irq = self.pic.borrow().irq_for_vector(vector);
+        if let Some(irq) = irq {
+            self.pic.borrow_mut().end_of_interrupt(irq);
+        }
+        match vector {
+            0x10 => self.video_service(),
+            0x08 => {
+                let mut ticks = self.mem.read_u32(BDA_TICKS) + 1;
+                if ticks >= TICKS_PER_DAY {
+                    ticks = 0;
+                    self.mem.write_u8(BDA_MIDNIGHT, 1);
+                }
+                self.mem.write_u32(BDA_TICKS, ticks);
+                // then the user timer hook, returning to this stub's IRET
+                if let Err(fault) = self.interrupt(0x1C) {
+                    self.raise(fault);
+                }
+            },
+            0x09 => self.keyboard_interrupt(),
+            0x16 => {
+                let ah = self.gpr.get_register_value(Register::AH);
+                match ah {
+                    // wait for a key with interrupts on by running the stub again
+                    0x00 | 0x10 => match self.dequeue_key(true) {
+                        Some(key) => self.gpr.set_register_value(Register::AX, key as usize),
+                        None => {
+                            self.flags.set(IF, true);
+                            self.ip.rip -= 2;
+                        }
+                    },
+                    0x01 | 0x11 => match self.dequeue_key(false) {
+                        Some(key) => {
+                            self.gpr.set_register_value(Register::AX, key as usize);
+                            self.set_stacked_flag(ZF, false);
+                        },
+                        None => self.set_stacked_flag(ZF, true)
+                    },
+                    0x02 | 0x12 => {
+                        let flags = self.mem.read_u8(BDA_KEYBOARD_FLAGS);
+                        self.gpr.set_register_value(Register::AL, flags as usize);
+                    },
+                    _ => {}
+                }
+            },
+            // IRQ8: reading status C acknowledges the RTC
+            0x70 => {
+                self.read_cmos(0x0C);
+            },
+            0x1A => {
+                let ah = self.gpr.get_register_value(Register::AH);
+                match ah {
+                    0x00 => {
+                        let ticks = self.mem.read_u32(BDA_TICKS);
+                        self.gpr.set_register_value(Register::CX, (ticks >> 16) as usize);
+                        self.gpr.set_register_value(Register::DX, (ticks & 0xFFFF) as usize);
+                        self.gpr.set_register_value(Register::AL, self.mem.read_u8(BDA_MIDNIGHT) as usize);
+                        self.mem.write_u8(BDA_MIDNIGHT, 0);
+                    },
+                    0x01 => {
+                        let cx = self.gpr.get_register_value(Register::CX) as u32;
+                        let dx = self.gpr.get_register_value(Register::DX) as u32;
+                        self.mem.write_u32(BDA_TICKS, cx << 16 | dx);
+                        self.mem.write_u8(BDA_MIDNIGHT, 0);
+                    },
+                    // RTC time and date, straight from the CMOS in BCD
+                    0x02 => {
+                        for (register, index) in [(Register::CH, 0x04), (Register::CL, 0x02), (Register::DH, 0x00)] {
+                            let value = self.read_cmos(index);
+                            self.gpr.set_register_value(register, value as usize);
+                        }
+                        self.gpr.set_register_value(Register::DL, 0);
+                        self.set_stacked_flag(CF, false);
+                    },
+                    0x04 => {
+                        for (register, index) in [(Register::CH, 0x32), (Register::CL, 0x09), (Register::DH, 0x08), (Register::DL, 0x07)] {
+                            let value = self.read_cmos(index);
+                            self.gpr.set_register_value(register, value as usize);
+                        }
+                        self.set_stacked_flag(CF, false);
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn read_cmos(&mut self, index: u8) -> u8 {
+        self.io.write(CMOS_INDEX, index as u64, Bits::Bit8);
+        self.io.read(CMOS_DATA, Bits::Bit8) as u8
+    }
+
+    // IRQ1: take the scan code, track the shift keys and queue anything else
+    fn keyboard_interrupt(&mut self) {
+        let code = self.io.read(KBD_DATA, Bits::Bit8) as u8;
+        let pressed = code & 0x80 == 0;
+        let flag = match code & 0x7F {
+            0x2A => LEFT_SHIFT,
+            0x36 => RIGHT_SHIFT,
+            0x1D => CTRL,
+            0x38 => ALT,
+            _ => 0
+        };
+        let mut flags = self.mem.read_u8(BDA_KEYBOARD_FLAGS);
+        if flag != 0 {
+            flags = if pressed { flags | flag } else { flags & !flag };
+            self.mem.write_u8(BDA_KEYBOARD_FLAGS, flags);
+            return;
+        }
+        // breaks, E0 prefixes and command replies all have bit 7 set
+        if !pressed {
+            return;
+        }
+        let table = if flags & (LEFT_SHIFT | RIGHT_SHIFT) != 0 { ASCII_SHIFT } else { ASCII };
+        let mut ascii = table.get(code as usize).copied().unwrap_or(0);
+        if flags & CTRL != 0 && ascii.is_ascii_alphabetic() {
+            ascii &= 0x1F;
+        }
+        let tail = self.mem.read_u16(BDA_KEYBOARD_TAIL);
+        let next = if tail + 2 >= KEYBOARD_BUFFER_END { KEYBOARD_BUFFER } else { tail + 2 };
+        // a full buffer drops the key
+        if next == self.mem.read_u16(BDA_KEYBOARD_HEAD) {
+            return;
+        }
+        self.mem.write_u16(0x400 + tail as usize, (code as u16) << 8 | ascii as u16);
+        self.mem.write_u16(BDA_KEYBOARD_TAIL, next);
+    }
+
+    fn dequeue_key(&mut self, remove: bool) -> Option<u16> {
+        let head = self.mem.read_u16(BDA_KEYBOARD_HEAD);
+        if head == self.mem.read_u16(BDA_KEYBOARD_TAIL) {
+            return None;
+        }
+        let key = self.mem.read_u16(0x400 + head as usize);
+        if remove {
+            let next = if head + 2 >= KEYBOARD_BUFFER_END { KEYBOARD_BUFFER } else { head + 2 };
+            self.mem.write_u16(BDA_KEYBOARD_HEAD, next);
+        }
+        Some(key)
+    }
+
+    // status flags have to go into the FLAGS image IRET pops
+    fn set_stacked_flag(&mut self, flag: u64, set: bool) {
+        let addr = self.segmentation_to_physical(&Register::SS, self.stack_pointer() + 4) as usize;
+        let flags = self.mem.read_u16(addr);
+        let flags = if set { flags | flag as u16 } else { flags & !(flag as u16) };
+        self.mem.write_u16(addr, flags);
+    }
+}
@@ -0,0 +1,265 @@
+use iced_x86::{Instruction, Mnemonic, OpKind};
+use crate::ast::Bits;
+use crate::vm::cpu::Cpu;
+use crate::vm::exception::Exception;
+use crate::vm::flags::{AF, CF, OF, PF, SF, ZF};
+
+impl Cpu {
+    // the last operand of a shift is either an immediate or CL
+    fn shift_count(&self, instr: Instruction, bits: Bits) -> u32 {
+        let last = instr.op_count() - 1;
+        let count = match instr.op_kind(last) {
+            OpKind::Register => self.gpr.get_register_value(instr.op_register(last)),
+            _ => instr.immediate(last)
+        };
+        let mask = if bits == Bits::Bit64 { 0x3F } else { 0x1F };
+        (count & mask) as u32
+    }
+
+    pub fn shift(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let count = self.shift_count(instr, bits);
+        if count == 0 {
+            return Ok(());
+        }
+        let width = bits.bytes() as u32 * 8;
+        let value = self.get_op0value(instr)? as u64 & bits.mask();
+        let msb = |v: u64| v & bits.sign_bit() != 0;
+
+        let result = match instr.mnemonic() {
+            Mnemonic::Shl | Mnemonic::Sal => {
+                let result = ((value as u128) << count) as u64 & bits.mask();
+                let carry = count <= width && (value >> (width - count)) & 1 != 0;
+                self.flags.set(CF, carry).set(OF, msb(result) != carry);
+                self.flags.set_result(result, bits)
+            },
+            Mnemonic::Shr => {
+                let result = ((value as u128) >> count) as u64;
+                let carry = count <= width && (value >> (count - 1)) & 1 != 0;
+                self.flags.set(CF, carry).set(OF, msb(value));
+                self.flags.set_result(result, bits)
+            },
+            Mnemonic::Sar => {
+                let signed = bits.sign_extend(value);
+                let result = (signed >> count.min(63)) as u64 & bits.mask();
+                let carry = (signed >> (count - 1).min(63)) & 1 != 0;
+                self.flags.set(CF, carry).set(OF, false);
+                self.flags.set_result(result, bits)
+            },
+            Mnemonic::Rol => {
+                let count = count % width;
+                let result = if count == 0 { value } else { (value << count | value >> (width - count)) & bits.mask() };
+                let carry = result & 1 != 0;
+                self.flags.set(CF, carry).set(OF, msb(result) != carry);
+                result
+            },
+            Mnemonic::Ror => {
+                let count = count % width;
+                let result = if count == 0 { value } else { (value >> count | value << (width - count)) & bits.mask() };
+                let second = result & (bits.sign_bit() >> 1) != 0;
+                self.flags.set(CF, msb(result)).set(OF, msb(result) != second);
+                result
+            },
+            Mnemonic::Rcl => {
+                // rotate through a (width + 1)-bit value with CF on top
+                let count = count % (width + 1);
+                let wide = (self.flags.get(CF) as u128) << width | value as u128;
+                let rotated = (wide << count | wide >> (width + 1 - count)) & ((1u128 << (width + 1)) - 1);
+                let result = rotated as u64 & bits.mask();
+                let carry = rotated >> width & 1 != 0;
+                self.flags.set(CF, carry).set(OF, msb(result) != carry);
+                result
+            },
+            Mnemonic::Rcr => {
+                let count = count % (width + 1);
+                let wide = (self.flags.get(CF) as u128) << width | value as u128;
+                let rotated = (wide >> count | wide << (width + 1 - count)) & ((1u128 << (width + 1)) - 1);
+                let result = rotated as u64 & bits.mask();
+                let carry = rotated >> width & 1 != 0;
+                let second = result & (bits.sign_bit() >> 1) != 0;
+                self.flags.set(CF, carry).set(OF, msb(result) != second);
+                result
+            },
+            _ => value
+        };
+        self.write_op0(instr, result as usize)?;
+        Ok(())
+    }
+
+    pub fn double_shift(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let count = self.shift_count(instr, bits);
+        if count == 0 {
+            return Ok(());
+        }
+        let width = bits.bytes() as u32 * 8;
+        let dest = self.get_op0value(instr)? as u128 & bits.mask() as u128;
+        let src = self.get_op1value(instr)? as u128 & bits.mask() as u128;
+
+        let (result, carry) = if instr.mnemonic() == Mnemonic::Shld {
+            let wide = dest << width | src;
+            ((wide << count >> width) as u64, (dest >> (width - count.min(width))) & 1 != 0)
+        } else {
+            let wide = src << width | dest;
+            ((wide >> count) as u64, (dest >> (count - 1)) & 1 != 0)
+        };
+        let result = self.flags.set_result(result, bits);
+        let overflow = (result ^ dest as u64) & bits.sign_bit() != 0;
+        self.flags.set(CF, carry).set(OF, overflow);
+        self.write_op0(instr, result as usize)?;
+        Ok(())
+    }
+
+    pub fn bit_test(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let offset = self.get_op1value(instr)? as u64;
+
+        // a register bit offset on a memory operand can reach outside the
+        // addressed operand, so go through the containing byte
+        if instr.op0_kind() == OpKind::Memory && instr.op1_kind() == OpKind::Register {
+            let offset = self.source_bits(instr).sign_extend(offset);
+            let (seg, addr) = self.operand_location(instr);
+            let addr = (addr as i64).wrapping_add(offset >> 3) as u64 & self.address_bits(instr).mask();
+            let bit = (offset & 7) as u32;
+            let byte = self.read_memory(seg, addr, Bits::Bit8)?;
+            self.flags.set(CF, byte >> bit & 1 != 0);
+            if let Some(byte) = Self::update_bit(instr.mnemonic(), byte, bit) {
+                self.write_memory(seg, addr, byte, Bits::Bit8)?;
+            }
+            return Ok(());
+        }
+
+        let bit = (offset % (bits.bytes() as u64 * 8)) as u32;
+        let value = self.get_op0value(instr)? as u64;
+        self.flags.set(CF, value >> bit & 1 != 0);
+        if let Some(value) = Self::update_bit(instr.mnemonic(), value, bit) {
+            self.write_op0(instr, value as usize)?;
+        }
+        Ok(())
+    }
+
+    fn update_bit(mnemonic: Mnemonic, value: u64, bit: u32) -> Option<u64> {
+        match mnemonic {
+            Mnemonic::Bts => Some(value | 1 << bit),
+            Mnemonic::Btr => Some(value & !(1 << bit)),
+            Mnemonic::Btc => Some(value ^ 1 << bit),
+            _ => None
+        }
+    }
+
+    pub fn bit_scan(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let width = bits.bytes() as u32 * 8;
+        let src = self.get_op1value(instr)? as u64 & bits.mask();
+
+        match instr.mnemonic() {
+            Mnemonic::Bsf | Mnemonic::Bsr => {
+                // the destination is left untouched for a zero source
+                self.flags.set(ZF, src == 0);
+                if src != 0 {
+                    let index = if instr.mnemonic() == Mnemonic::Bsf {
+                        src.trailing_zeros()
+                    } else {
+                        63 - src.leading_zeros()
+                    };
+                    self.write_op0(instr, index as usize)?;
+                }
+            },
+            Mnemonic::Tzcnt | Mnemonic::Lzcnt => {
+                let count = if src == 0 {
+                    width
+                } else if instr.mnemonic() == Mnemonic::Tzcnt {
+                    src.trailing_zeros()
+                } else {
+                    src.leading_zeros() - (64 - width)
+                };
+                self.flags.set(CF, src == 0).set(ZF, count == 0);
+                self.write_op0(instr, count as usize)?;
+            },
+            _ => {
+                let count = src.count_ones();
+                self.flags.set(CF, false).set(OF, false).set(SF, false).set(AF, false).set(PF, false);
+                self.flags.set(ZF, src == 0);
+                self.write_op0(instr, count as usize)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn bswap(&mut self, instr: Instruction) {
+        let reg = instr.op0_register();
+        let value = self.gpr.get_register_value(reg);
+        let swapped = match reg.size() {
+            8 => value.swap_bytes(),
+            4 => (value as u32).swap_bytes() as u64,
+            // BSWAP on a 16-bit register is undefined, real CPUs clear it
+            _ => 0
+        };
+        self.gpr.set_register_value(reg, swapped as usize);
+    }
+
+    pub fn inc_dec(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let value = self.get_op0value(instr)? as u64;
+        // INC and DEC leave CF alone
+        let carry = self.flags.get(CF);
+        let result = if instr.mnemonic() == Mnemonic::Inc {
+            self.flags.add(value, 1, false, bits)
+        } else {
+            self.flags.sub(value, 1, false, bits)
+        };
+        self.flags.set(CF, carry);
+        self.write_op0(instr, result as usize)?;
+        Ok(())
+    }
+
+    pub fn neg(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let value = self.get_op0value(instr)? as u64;
+        let result = self.flags.sub(0, value, false, bits);
+        self.write_op0(instr, result as usize)?;
+        Ok(())
+    }
+
+    pub fn not(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let value = !(self.get_op0value(instr)? as u64) & bits.mask();
+        self.write_op0(instr, value as usize)?;
+        Ok(())
+    }
+
+    pub fn xchg(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let op0 = self.get_op0value(instr)?;
+        let op1 = self.get_op1value(instr)?;
+        self.write_op0(instr, op1)?;
+        self.write_op1(instr, op0)?;
+        Ok(())
+    }
+
+    pub fn xadd(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let op0 = self.get_op0value(instr)?;
+        let op1 = self.get_op1value(instr)?;
+        let sum = self.flags.add(op0 as u64, op1 as u64, false, bits);
+        self.write_op1(instr, op0)?;
+        self.write_op0(instr, sum as usize)?;
+        Ok(())
+    }
+
+    pub fn cmpxchg(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let acc = Self::accumulator(bits);
+        let dest = self.get_op0value(instr)?;
+        let expected = self.gpr.get_register_value(acc);
+        self.flags.sub(expected, dest as u64, false, bits);
+        if self.flags.get(ZF) {
+            let src = self.get_op1value(instr)?;
+            self.write_op0(instr, src)?;
+        } else {
+            // the destination is always written back, as on a locked bus cycle
+            self.write_op0(instr, dest)?;
+            self.gpr.set_register_value(acc, dest);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use iced_x86::{Code, Instruction, Register};
+use crate::ast::Bits;
+use crate::vm::cpu::access::Access;
+use crate::vm::cpu::protected::EFER_LMA;
+use crate::vm::cpu::Cpu;
+use crate::vm::exception::Exception;
+use crate::vm::flags::{IF, NT, RF, TF, VM};
+use crate::vm::idt::{Gate, INTERRUPT_GATE, TASK_GATE, TRAP_GATE};
+use crate::vm::segment::{SegmentRegister, CONFORMING_EXPAND_DOWN, DEFAULT_BIG, EXECUTABLE, LONG};
+use crate::vm::Mode;
+
+impl Cpu {
+    // a fault while delivering an exception either gets delivered on its
+    // own, turns into #DF, or (when #DF itself cannot be delivered) shuts
+    // the machine down
+    pub fn raise(&mut self, exception: Exception) {
+        if let Err(fault) = self.deliver(exception.vector(), exception.error_code(), true) {
+            if exception == Exception::DoubleFault {
+                eprintln!("triple fault while delivering {:?}, resetting", fault);
+                self.reset();
+            } else if Self::is_double_fault(exception, fault) {
+                self.raise(Exception::DoubleFault);
+            } else {
+                self.raise(fault);
+            }
+        }
+    }
+
+    fn is_double_fault(first: Exception, second: Exception) -> bool {
+        match first {
+            Exception::PageFault(_) => second.is_contributory() || matches!(second, Exception::PageFault(_)),
+            _ => first.is_contributory() && second.is_contributory()
+        }
+    }
+
+    // INT n, INT3 and INTO; IF only masks external interrupts
+    pub fn interrupt(&mut self, vector: u8) -> Result<(), Exception> {
+        self.deliver(vector, None, false)
+    }
+
+    // an IRQ acknowledged from the PIC; faults while delivering it are
+    // handled like any other exception
+    pub fn external_interrupt(&mut self, vector: u8) {
+        if let Err(fault) = self.deliver(vector, None, true) {
+            self.raise(fault);
+        }
+    }
+
+    // software interrupts are held to the gate's DPL, exceptions and
+    // hardware interrupts (external) are not
+    pub fn deliver(&mut self, vector: u8, error_code: Option<u32>, external: bool) -> Result<(), Exception> {
+        match self.mode {
+            Mode::Real => self.deliver_real_mode(vector),
+            _ => self.deliver_protected_mode(vector, error_code, external)
+        }
+    }
+
+    pub fn iret(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = match instr.code() {
+            Code::Iretw => Bits::Bit16,
+            Code::Iretd => Bits::Bit32,
+            _ => Bits::Bit64
+        };
+        if self.is_real() {
+            let (ip, sp) = self.pop_at(self.stack_pointer(), bits)?;
+            let (cs, sp) = self.pop_at(sp, bits)?;
+            let (flags, sp) = self.pop_at(sp, bits)?;
+            self.set_stack_pointer(sp);
+            self.ip.rip = ip;
+            self.load_segment(Register::CS, cs as u16)?;
+            self.load_flags_image(flags, bits);
+            return Ok(());
+        }
+
+        // NT means we are in a nested task: return along the back link
+        if self.flags.get(NT) && self.cr.efer & EFER_LMA == 0 {
+            let link = self.read_system(self.tr.base, Bits::Bit16)? as u16;
+            return self.task_switch(link, false);
+        }
+
+        // the whole frame is read and checked before anything is loaded
+        let cpl = self.cpl();
+        let (ip, sp) = self.pop_at(self.stack_pointer(), bits)?;
+        let (cs, sp) = self.pop_at(sp, bits)?;
+        let (flags, sp) = self.pop_at(sp, bits)?;
+        let cs = cs as u16;
+        let (desc, new_cpl) = self.check_code_segment(cs, cpl, true)?;
+        // 64-bit mode always pops SS:RSP, otherwise only on an outward return
+        let stack = if self.mode == Mode::Long || new_cpl > cpl {
+            let (new_sp, sp) = self.pop_at(sp, bits)?;
+            let (ss, _) = self.pop_at(sp, bits)?;
+            let ss = ss as u16;
+            let long = self.cr.efer & EFER_LMA != 0 && desc.flags & LONG != 0;
+            Some((ss, self.check_stack_segment(ss, new_cpl, long)?, new_sp))
+        } else {
+            None
+        };
+
+        self.set_code_segment(cs, desc, new_cpl);
+        self.ip.rip = ip;
+        let flags = self.keep_privileged_flags(flags, cpl);
+        self.load_flags_image(flags, bits);
+        match stack {
+            Some((ss, desc, sp)) => {
+                self.set_segment(Register::SS, ss, desc);
+                self.set_stack_pointer(sp);
+            },
+            None => self.set_stack_pointer(sp)
+        }
+        if new_cpl > cpl {
+            self.null_outer_data_segments();
+        }
+        Ok(())
+    }
+
+    // real mode interrupts go through the IVT at the IDTR base (physical 0
+    // unless LIDT moved it): four bytes per vector, offset first then segment
+    pub fn deliver_real_mode(&mut self, vector: u8) -> Result<(), Exception> {
+        let entry = vector as u64 * 4;
+        if entry + 3 > self.idt.limit as u64 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let entry = (self.idt.base + entry) as usize;
+        let offset = self.mem.read_u16(entry);
+        let segment = self.mem.read_u16(entry + 2);
+
+        let sp = self.push_at(self.stack_pointer(), self.flags_image(), Bits::Bit16)?;
+        let sp = self.push_at(sp, self.gpr.get_register_value(Register::CS), Bits::Bit16)?;
+        let sp = self.push_at(sp, self.ip.rip, Bits::Bit16)?;
+        self.set_stack_pointer(sp);
+        self.flags.set(IF | TF, false);
+
+        self.load_segment(Register::CS, segment)?;
+        self.ip.rip = offset as u64;
+        Ok(())
+    }
+
+    fn deliver_protected_mode(&mut self, vector: u8, error_code: Option<u32>, external: bool) -> Result<(), Exception> {
+        let long = self.cr.efer & EFER_LMA != 0;
+        let ext = external as u16;
+        // error codes that point into the IDT have bit 1 set
+        let idt_code = (vector as u16) << 3 | 2 | ext;
+
+        let addr = self.idt.gate_address(vector, long).ok_or(Exception::GeneralProtection(idt_code))?;
+        let low = self.read_system(addr, Bits::Bit64)?;
+        let high = if long { self.read_system(addr + 8, Bits::Bit64)? } else { 0 };
+        let gate = Gate::from_raw(low, high);
+
+        let valid = if long {
+            matches!(gate.kind, INTERRUPT_GATE | TRAP_GATE)
+        } else {
+            gate.kind == TASK_GATE || gate.is_interrupt() || gate.is_trap()
+        };
+        if !valid || (!external && gate.dpl < self.cpl()) {
+            return Err(Exception::GeneralProtection(idt_code));
+        }
+        if !gate.present {
+            return Err(Exception::SegmentNotPresent(idt_code));
+        }
+
+        if gate.kind == TASK_GATE {
+            self.task_switch(gate.selector, true)?;
+            if let Some(code) = error_code {
+                let bits = if self.segments.get(Register::CS).is_big() { Bits::Bit32 } else { Bits::Bit16 };
+                self.push(code as u64, bits)?;
+            }
+            return Ok(());
+        }
+
+        let code = (gate.selector & 0xFFFC) | ext;
+        if gate.selector & !3 == 0 {
+            return Err(Exception::GeneralProtection(ext));
+        }
+        let desc = self.read_checked_descriptor(gate.selector)?;
+        let cpl = self.cpl();
+        let dpl = desc.dpl();
+        if desc.flags & EXECUTABLE == 0 || dpl > cpl {
+            return Err(Exception::GeneralProtection(code));
+        }
+        if long && (desc.flags & LONG == 0 || desc.flags & DEFAULT_BIG != 0) {
+            return Err(Exception::GeneralProtection(code));
+        }
+        if !desc.is_present() {
+            return Err(Exception::SegmentNotPresent(code));
+        }
+        let new_cpl = if desc.flags & CONFORMING_EXPAND_DOWN != 0 { cpl } else { dpl };
+        let inner = new_cpl < cpl;
+
+        let bits = if long { Bits::Bit64 } else { gate.bits() };
+        let old_ss = self.gpr.get_register_value(Register::SS);
+        let old_sp = self.stack_pointer();
+        let old_cs = self.gpr.get_register_value(Register::CS);
+        let old_flags = self.flags_image();
+        let old_ip = self.ip.rip;
+
+        // long mode can force a known good stack through the IST
+        let new_stack = if long && gate.ist != 0 {
+            Some((new_cpl as u16, self.ist_stack(gate.ist)?))
+        } else if inner {
+            Some(self.tss_stack(new_cpl)?)
+        } else {
+            None
+        };
+        // only an inner level switches SS
+        let (new_ss, mut sp) = match new_stack {
+            Some((ss, sp)) if inner => {
+                let ss_desc = self.check_stack_segment(ss, new_cpl, long).map_err(|_| Exception::InvalidTss(ss & 0xFFFC))?;
+                (Some((ss, ss_desc)), sp)
+            },
+            Some((_, sp)) => (None, sp),
+            None => (None, old_sp)
+        };
+        if long {
+            sp &= !0xF;
+        }
+
+        // the frame is built on the new stack before CS, SS and the stack
+        // pointer change, so a fault on the way is taken in the interrupted
+        // context
+        let mut ss_cache = *self.segments.get(Register::SS);
+        if let Some((ss, ss_desc)) = new_ss {
+            ss_cache.load_descriptor(ss, ss_desc);
+        }
+        let mut frame = Vec::new();
+        if long || inner {
+            frame.extend([old_ss, old_sp]);
+        }
+        frame.extend([old_flags, old_cs, old_ip]);
+        frame.extend(error_code.map(|code| code as u64));
+        let sp = self.write_frame(&ss_cache, long, sp, &frame, bits, new_cpl == 3)?;
+
+        self.set_code_segment(gate.selector, desc, new_cpl);
+        if let Some((ss, ss_desc)) = new_ss {
+            self.set_segment(Register::SS, ss, ss_desc);
+        }
+        self.set_stack_pointer(sp);
+        self.flags.set(TF | NT | RF | VM, false);
+        if gate.is_interrupt() {
+            self.flags.set(IF, false);
+        }
+        self.ip.rip = gate.offset & bits.mask();
+        Ok(())
+    }
+
+    // pushes onto a stack that need not be loaded yet, returning the new
+    // stack pointer
+    fn write_frame(&mut self, ss: &SegmentRegister, long: bool, mut sp: u64, frame: &[u64], bits: Bits, user: bool) -> Result<u64, Exception> {
+        let mask = if long { u64::MAX } else if ss.is_big() { 0xFFFF_FFFF } else { 0xFFFF };
+        for &value in frame {
+            sp = sp.wrapping_sub(bits.bytes() as u64) & mask;
+            let linear = if long {
+                Self::check_canonical(Register::SS, sp, bits.bytes())?
+            } else {
+                self.check_segment(Register::SS, ss, sp, bits.bytes(), Access::Write)?
+            };
+            self.write_linear(linear, value, bits, user)?;
+        }
+        Ok(sp)
+    }
+}
@@ -0,0 +1,83 @@
+use iced_x86::Register;
+use crate::vm::cpu::protected::{CR0_PG, EFER_LMA, EFER_LME, EFER_NXE};
+use crate::vm::cpu::Cpu;
+use crate::vm::exception::Exception;
+use crate::vm::Mode;
+
+pub const MSR_EFER: u32 = 0xC000_0080;
+pub const MSR_FS_BASE: u32 = 0xC000_0100;
+pub const MSR_GS_BASE: u32 = 0xC000_0101;
+pub const MSR_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+// SCE, LME and NXE; LMA is set by the CPU itself
+const EFER_WRITABLE: u64 = 1 | EFER_LME | EFER_NXE;
+
+impl Cpu {
+    pub fn read_msr(&self, msr: u32) -> Result<u64, Exception> {
+        match msr {
+            MSR_EFER => Ok(self.cr.efer),
+            MSR_FS_BASE => Ok(self.segments.get(Register::FS).base),
+            MSR_GS_BASE => Ok(self.segments.get(Register::GS).base),
+            MSR_KERNEL_GS_BASE => Ok(self.kernel_gs_base),
+            _ => Err(Exception::GeneralProtection(0))
+        }
+    }
+
+    pub fn write_msr(&mut self, msr: u32, value: u64) -> Result<(), Exception> {
+        let canonical = ((value as i64) << 16 >> 16) as u64 == value;
+        match msr {
+            MSR_EFER => {
+                if value & !EFER_WRITABLE != 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                // LME cannot change while paging is on
+                if self.cr.cr0 & CR0_PG != 0 && (value ^ self.cr.efer) & EFER_LME != 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.cr.efer = value | (self.cr.efer & EFER_LMA);
+            },
+            MSR_FS_BASE | MSR_GS_BASE | MSR_KERNEL_GS_BASE if !canonical => {
+                return Err(Exception::GeneralProtection(0));
+            },
+            MSR_FS_BASE => self.segments.get_mut(Register::FS).base = value,
+            MSR_GS_BASE => self.segments.get_mut(Register::GS).base = value,
+            MSR_KERNEL_GS_BASE => self.kernel_gs_base = value,
+            _ => return Err(Exception::GeneralProtection(0))
+        }
+        Ok(())
+    }
+
+    pub fn rdmsr(&mut self) -> Result<(), Exception> {
+        if self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let value = self.read_msr(self.gpr.get_register_value(Register::ECX) as u32)?;
+        self.gpr.set_register_value(Register::EAX, value as u32 as usize);
+        self.gpr.set_register_value(Register::EDX, (value >> 32) as usize);
+        Ok(())
+    }
+
+    pub fn wrmsr(&mut self) -> Result<(), Exception> {
+        if self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let msr = self.gpr.get_register_value(Register::ECX) as u32;
+        let value = self.gpr.get_register_value(Register::EAX) & 0xFFFF_FFFF
+            | self.gpr.get_register_value(Register::EDX) << 32;
+        self.write_msr(msr, value)
+    }
+
+    // exchanges GS base with IA32_KERNEL_GS_BASE, 64-bit mode only
+    pub fn swapgs(&mut self) -> Result<(), Exception> {
+        if self.mode != Mode::Long {
+            return Err(Exception::InvalidOpcode);
+        }
+        if self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let gs = self.segments.get(Register::GS).base;
+        self.segments.get_mut(Register::GS).base = self.kernel_gs_base;
+        self.kernel_gs_base = gs;
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use iced_x86::{Instruction, OpKind, Register};
+use crate::ast::Bits;
+use crate::vm::cpu::Cpu;
+use crate::vm::exception::Exception;
+use crate::vm::flags::{CF, OF};
+
+impl Cpu {
+    pub fn accumulator(bits: Bits) -> Register {
+        match bits {
+            Bits::Bit8 => Register::AL,
+            Bits::Bit16 => Register::AX,
+            Bits::Bit32 => Register::EAX,
+            Bits::Bit64 => Register::RAX
+        }
+    }
+
+    fn data_register(bits: Bits) -> Register {
+        match bits {
+            Bits::Bit8 => Register::AH,
+            Bits::Bit16 => Register::DX,
+            Bits::Bit32 => Register::EDX,
+            Bits::Bit64 => Register::RDX
+        }
+    }
+
+    // the double-width value held in AX, DX:AX, EDX:EAX or RDX:RAX
+    fn read_pair(&self, bits: Bits) -> u128 {
+        if bits == Bits::Bit8 {
+            return self.gpr.get_register_value(Register::AX) as u128;
+        }
+        let low = self.gpr.get_register_value(Self::accumulator(bits)) as u128;
+        let high = self.gpr.get_register_value(Self::data_register(bits)) as u128;
+        high << (bits.bytes() * 8) | low
+    }
+
+    fn write_pair(&mut self, bits: Bits, low: u64, high: u64) {
+        self.gpr.set_register_value(Self::accumulator(bits), (low & bits.mask()) as usize);
+        self.gpr.set_register_value(Self::data_register(bits), (high & bits.mask()) as usize);
+    }
+
+    pub fn mul(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let src = self.get_op0value(instr)? as u128;
+        let acc = self.gpr.get_register_value(Self::accumulator(bits)) as u128;
+        let product = acc * src;
+        let width = bits.bytes() * 8;
+        let high = (product >> width) as u64 & bits.mask();
+
+        self.write_pair(bits, product as u64, high);
+        self.flags.set(CF, high != 0).set(OF, high != 0);
+        Ok(())
+    }
+
+    pub fn imul(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let width = bits.bytes() * 8;
+        match instr.op_count() {
+            1 => {
+                let src = bits.sign_extend(self.get_op0value(instr)? as u64) as i128;
+                let acc = bits.sign_extend(self.gpr.get_register_value(Self::accumulator(bits))) as i128;
+                let product = acc * src;
+                let low = product as u64 & bits.mask();
+                let high = (product >> width) as u64 & bits.mask();
+
+                self.write_pair(bits, low, high);
+                let overflow = bits.sign_extend(low) as i128 != product;
+                self.flags.set(CF, overflow).set(OF, overflow);
+            },
+            count => {
+                let (a, b) = if count == 2 {
+                    (self.get_op0value(instr)? as u64, self.get_op1value(instr)? as u64)
+                } else {
+                    (self.get_op1value(instr)? as u64, instr.immediate(2))
+                };
+                let product = bits.sign_extend(a) as i128 * bits.sign_extend(b) as i128;
+                let low = product as u64 & bits.mask();
+
+                self.write_op0(instr, low as usize)?;
+                let overflow = bits.sign_extend(low) as i128 != product;
+                self.flags.set(CF, overflow).set(OF, overflow);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn div(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let divisor = self.get_op0value(instr)? as u128 & bits.mask() as u128;
+        if divisor == 0 {
+            return Err(Exception::DivideError);
+        }
+        let dividend = self.read_pair(bits);
+        let quotient = dividend / divisor;
+        if quotient > bits.mask() as u128 {
+            return Err(Exception::DivideError);
+        }
+
+        let remainder = (dividend % divisor) as u64;
+        self.write_pair(bits, quotient as u64, remainder);
+        Ok(())
+    }
+
+    pub fn idiv(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let divisor = bits.sign_extend(self.get_op0value(instr)? as u64) as i128;
+        if divisor == 0 {
+            return Err(Exception::DivideError);
+        }
+        let width = bits.bytes() * 8;
+        // sign-extend the double-width dividend from bit (2 * width - 1)
+        let shift = 128 - 2 * width;
+        let dividend = ((self.read_pair(bits) << shift) as i128) >> shift;
+
+        let quotient = dividend / divisor;
+        let max = (bits.sign_bit() - 1) as i128;
+        if quotient > max || quotient < -max - 1 {
+            return Err(Exception::DivideError);
+        }
+
+        let remainder = (dividend % divisor) as u64;
+        self.write_pair(bits, quotient as u64, remainder);
+        Ok(())
+    }
+
+    // CBW, CWDE and CDQE
+    pub fn sign_extend_accumulator(&mut self, bits: Bits) {
+        let half = match bits {
+            Bits::Bit16 => Bits::Bit8,
+            Bits::Bit32 => Bits::Bit16,
+            _ => Bits::Bit32
+        };
+        let value = half.sign_extend(self.gpr.get_register_value(Self::accumulator(half)));
+        self.gpr.set_register_value(Self::accumulator(bits), (value as u64 & bits.mask()) as usize);
+    }
+
+    // CWD, CDQ and CQO
+    pub fn sign_extend_into_data(&mut self, bits: Bits) {
+        let acc = self.gpr.get_register_value(Self::accumulator(bits));
+        let high = if acc & bits.sign_bit() != 0 { bits.mask() } else { 0 };
+        self.gpr.set_register_value(Self::data_register(bits), high as usize);
+    }
+
+    pub fn source_bits(&self, instr: Instruction) -> Bits {
+        match instr.op1_kind() {
+            OpKind::Register => Bits::from_size(instr.op1_register().size()),
+            _ => Bits::from_size(instr.memory_size().size())
+        }
+    }
+
+    pub fn movzx(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let value = self.get_op1value(instr)? as u64 & self.source_bits(instr).mask();
+        self.write_op0(instr, value as usize)?;
+        Ok(())
+    }
+
+    pub fn movsx(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = self.operand_bits(instr);
+        let value = self.source_bits(instr).sign_extend(self.get_op1value(instr)? as u64) as u64;
+        self.write_op0(instr, (value & bits.mask()) as usize)?;
+        Ok(())
+    }
+}
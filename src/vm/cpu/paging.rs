@@ -0,0 +1,161 @@
+use crate::vm::cpu::access::Access;
+use crate::vm::cpu::protected::{CR0_PG, CR0_WP, CR4_PAE, CR4_PGE, CR4_PSE, EFER_LMA, EFER_NXE};
+use crate::vm::cpu::Cpu;
+use crate::vm::exception::Exception;
+use crate::vm::paging::{TlbEntry, PAGE_ACCESSED, PAGE_DIRTY, PAGE_GLOBAL, PAGE_MASK, PAGE_NO_EXECUTE,
+                        PAGE_PRESENT, PAGE_SIZE, PAGE_USER, PAGE_WRITABLE, PF_FETCH, PF_PROTECTION,
+                        PF_RESERVED, PF_USER, PF_WRITE};
+
+const WIDE_ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+const NARROW_ADDRESS: u64 = 0xFFFF_F000;
+
+impl Cpu {
+    pub fn is_paging(&self) -> bool {
+        self.cr.cr0 & CR0_PG != 0
+    }
+
+    fn page_fault(&mut self, linear: u64, access: Access, user: bool, code: u32) -> Exception {
+        self.cr.cr2 = linear;
+        let mut code = code;
+        if access == Access::Write {
+            code |= PF_WRITE;
+        }
+        if user {
+            code |= PF_USER;
+        }
+        // I/D is only reported when NX is enabled
+        if access == Access::Execute && self.cr.efer & EFER_NXE != 0 {
+            code |= PF_FETCH;
+        }
+        Exception::PageFault(code)
+    }
+
+    // linear to physical, through the TLB when possible
+    pub fn translate(&mut self, linear: u64, access: Access, user: bool) -> Result<u64, Exception> {
+        if !self.is_paging() {
+            return Ok(linear);
+        }
+        let entry = match self.tlb.lookup(linear) {
+            Some(entry) => entry,
+            None => {
+                let entry = self.walk(linear, access, user)?;
+                self.tlb.insert(linear, entry);
+                entry
+            }
+        };
+
+        let allowed = match access {
+            Access::Read => !user || entry.user,
+            // supervisor writes to read-only pages only fault with CR0.WP
+            Access::Write => (!user || entry.user) && (entry.writable || (!user && self.cr.cr0 & CR0_WP == 0)),
+            Access::Execute => (!user || entry.user) && entry.executable,
+        };
+        if !allowed {
+            return Err(self.page_fault(linear, access, user, PF_PROTECTION));
+        }
+
+        if access == Access::Write && !entry.dirty {
+            let leaf = self.read_table_entry(entry.leaf, entry.wide);
+            self.write_table_entry(entry.leaf, leaf | PAGE_DIRTY, entry.wide);
+            self.tlb.mark_dirty(linear);
+        }
+        Ok(entry.frame | (linear & PAGE_MASK))
+    }
+
+    fn walk(&mut self, linear: u64, access: Access, user: bool) -> Result<TlbEntry, Exception> {
+        let pae = self.cr.cr4 & CR4_PAE != 0;
+        let long = self.cr.efer & EFER_LMA != 0;
+        let nx_enabled = self.cr.efer & EFER_NXE != 0;
+        let pse = self.cr.cr4 & CR4_PSE != 0;
+
+        // index shift of each level, top to bottom
+        let (mut table, shifts, wide): (u64, &[u32], bool) = if long {
+            (self.cr.cr3 & WIDE_ADDRESS, &[39, 30, 21, 12], true)
+        } else if pae {
+            // the four PDPTEs only carry a present bit
+            let pdpte_addr = (self.cr.cr3 & 0xFFFF_FFE0) + ((linear >> 30) & 3) * 8;
+            let pdpte = self.mem.read_u64(pdpte_addr as usize);
+            if pdpte & PAGE_PRESENT == 0 {
+                return Err(self.page_fault(linear, access, user, 0));
+            }
+            (pdpte & WIDE_ADDRESS, &[21, 12], true)
+        } else {
+            (self.cr.cr3 & NARROW_ADDRESS, &[22, 12], false)
+        };
+        let (address_mask, index_mask, entry_size) = if wide {
+            (WIDE_ADDRESS, 0x1FF, 8)
+        } else {
+            (NARROW_ADDRESS, 0x3FF, 4)
+        };
+
+        let mut writable = true;
+        let mut page_user = true;
+        let mut executable = true;
+        for (level, &shift) in shifts.iter().enumerate() {
+            let addr = table + ((linear >> shift) & index_mask) * entry_size;
+            let mut entry = self.read_table_entry(addr, wide);
+            if entry & PAGE_PRESENT == 0 {
+                return Err(self.page_fault(linear, access, user, 0));
+            }
+            if entry & PAGE_NO_EXECUTE != 0 && !nx_enabled {
+                return Err(self.page_fault(linear, access, user, PF_PROTECTION | PF_RESERVED));
+            }
+
+            writable &= entry & PAGE_WRITABLE != 0;
+            page_user &= entry & PAGE_USER != 0;
+            executable &= entry & PAGE_NO_EXECUTE == 0;
+
+            let last = level == shifts.len() - 1;
+            // PS on the PML4 is reserved, on a 2-level directory it needs CR4.PSE
+            let large = !last && if long { level > 0 } else { pae || pse };
+            let leaf = last || (large && entry & PAGE_SIZE != 0);
+
+            if entry & PAGE_ACCESSED == 0 {
+                entry |= PAGE_ACCESSED;
+                self.write_table_entry(addr, entry, wide);
+            }
+            if leaf {
+                let size = 1u64 << shift;
+                let base = entry & address_mask & !(size - 1);
+                return Ok(TlbEntry {
+                    frame: base | (linear & (size - 1) & !PAGE_MASK),
+                    size,
+                    writable,
+                    user: page_user,
+                    executable,
+                    global: entry & PAGE_GLOBAL != 0 && self.cr.cr4 & CR4_PGE != 0,
+                    dirty: entry & PAGE_DIRTY != 0,
+                    leaf: addr,
+                    wide,
+                });
+            }
+            table = entry & address_mask;
+        }
+        unreachable!()
+    }
+
+    fn read_table_entry(&self, addr: u64, wide: bool) -> u64 {
+        if wide {
+            self.mem.read_u64(addr as usize)
+        } else {
+            self.mem.read_u32(addr as usize) as u64
+        }
+    }
+
+    fn write_table_entry(&mut self, addr: u64, entry: u64, wide: bool) {
+        if wide {
+            self.mem.write_u64(addr as usize, entry);
+        } else {
+            self.mem.write_u32(addr as usize, entry as u32);
+        }
+    }
+
+    pub fn invlpg(&mut self, instr: iced_x86::Instruction) -> Result<(), Exception> {
+        if self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let linear = self.memory_address(instr);
+        self.tlb.invalidate(linear);
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+use iced_x86::{Code, Instruction, OpKind, Register};
+use crate::ast::Bits;
+use crate::vm::cpu::Cpu;
+use crate::vm::exception::Exception;
+use crate::vm::flags::IOPL;
+use crate::vm::segment::{SegmentDescriptor, ACCESSED, CODE_DATA, CONFORMING_EXPAND_DOWN, DEFAULT_BIG, EXECUTABLE, LONG, READ_WRITE};
+use crate::vm::Mode;
+
+pub const CR0_PE: u64 = 1 << 0;
+pub const CR0_TS: u64 = 1 << 3;
+pub const CR0_ET: u64 = 1 << 4;
+pub const CR0_WP: u64 = 1 << 16;
+pub const CR0_PG: u64 = 1 << 31;
+pub const CR4_PSE: u64 = 1 << 4;
+pub const CR4_PAE: u64 = 1 << 5;
+pub const CR4_PGE: u64 = 1 << 7;
+pub const EFER_LME: u64 = 1 << 8;
+pub const EFER_LMA: u64 = 1 << 10;
+pub const EFER_NXE: u64 = 1 << 11;
+
+impl Cpu {
+    pub fn read_register(&self, reg: Register) -> u64 {
+        match reg {
+            Register::CR0 => self.cr.cr0,
+            Register::CR2 => self.cr.cr2,
+            Register::CR3 => self.cr.cr3,
+            Register::CR4 => self.cr.cr4,
+            Register::CR8 => self.cr.cr8,
+            _ => self.gpr.get_register_value(reg)
+        }
+    }
+
+    pub fn write_register(&mut self, reg: Register, value: u64) -> Result<(), Exception> {
+        if reg.is_segment_register() {
+            self.load_segment(reg, value as u16)?;
+            // so that SS:SP can be switched without an interrupt in between
+            if reg == Register::SS {
+                self.interrupt_shadow = true;
+            }
+        } else if reg.is_cr() {
+            self.set_control_register(reg, value)?;
+        } else {
+            self.gpr.set_register_value(reg, value as usize);
+        }
+        Ok(())
+    }
+
+    pub fn set_control_register(&mut self, reg: Register, value: u64) -> Result<(), Exception> {
+        if self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        match reg {
+            Register::CR0 => {
+                if value & CR0_PG != 0 && value & CR0_PE == 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                // leaving long mode has to go through compatibility mode
+                if value & CR0_PG == 0 && self.mode == Mode::Long {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                let enabling = value & CR0_PG != 0 && self.cr.cr0 & CR0_PG == 0;
+                // long mode paging needs PAE tables to be in place first
+                if enabling && self.cr.efer & EFER_LME != 0 && self.cr.cr4 & CR4_PAE == 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                // real mode code runs at CPL 0 whatever CS.RPL says
+                if value & CR0_PE != 0 && self.cr.cr0 & CR0_PE == 0 {
+                    self.cpl = 0;
+                }
+                // ET is hardwired to one on anything newer than a 386
+                self.cr.cr0 = value | CR0_ET;
+                if self.cr.cr0 & CR0_PG != 0 && self.cr.efer & EFER_LME != 0 {
+                    self.cr.efer |= EFER_LMA;
+                } else {
+                    self.cr.efer &= !EFER_LMA;
+                }
+                self.tlb.flush(false);
+                self.update_mode();
+            },
+            Register::CR2 => self.cr.cr2 = value,
+            Register::CR3 => {
+                self.cr.cr3 = value;
+                self.tlb.flush(true);
+            },
+            Register::CR4 => {
+                if value & CR4_PAE == 0 && self.cr.efer & EFER_LMA != 0 {
+                    return Err(Exception::GeneralProtection(0));
+                }
+                self.cr.cr4 = value;
+                self.tlb.flush(false);
+            },
+            Register::CR8 => self.cr.cr8 = value & 0xF,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // setting PE switches mode at once, but CS keeps its real mode
+    // attributes (16-bit code) until the far jump that reloads it. The same
+    // goes for long mode: with LMA set, only a CS with L=1 runs 64-bit code,
+    // anything else is compatibility mode and behaves as protected mode
+    fn update_mode(&mut self) {
+        self.mode = if self.cr.cr0 & CR0_PE == 0 {
+            Mode::Real
+        } else if self.cr.efer & EFER_LMA != 0 && self.segments.get(Register::CS).is_long() {
+            Mode::Long
+        } else {
+            Mode::Protected
+        };
+    }
+
+    pub fn read_checked_descriptor(&mut self, selector: u16) -> Result<SegmentDescriptor, Exception> {
+        let code = selector & 0xFFFC;
+        // there is no LDT support, so TI selectors can never be valid
+        if selector & 4 != 0 {
+            return Err(Exception::GeneralProtection(code));
+        }
+        let addr = self.gdt.descriptor_address(selector).ok_or(Exception::GeneralProtection(code))?;
+        let desc = SegmentDescriptor::from_raw(self.read_system(addr, Bits::Bit64)?);
+        if desc.flags & CODE_DATA == 0 {
+            return Err(Exception::GeneralProtection(code));
+        }
+        if desc.flags & ACCESSED == 0 && desc.is_present() {
+            let access = self.read_system(addr + 5, Bits::Bit8)?;
+            self.write_system(addr + 5, access | ACCESSED as u64, Bits::Bit8)?;
+        }
+        Ok(desc)
+    }
+
+    pub fn load_segment(&mut self, reg: Register, selector: u16) -> Result<(), Exception> {
+        if self.is_real() {
+            self.gpr.set_register_value(reg, selector as usize);
+            self.segments.get_mut(reg).load_real(selector);
+            return Ok(());
+        }
+        if reg == Register::CS {
+            return self.load_code_segment(selector, self.cpl(), false);
+        }
+
+        let cpl = self.cpl();
+        if reg == Register::SS {
+            let desc = self.check_stack_segment(selector, cpl, self.mode == Mode::Long)?;
+            self.set_segment(reg, selector, desc);
+            return Ok(());
+        }
+
+        // a null selector leaves the segment unusable until reloaded
+        if selector & !3 == 0 {
+            self.set_segment(reg, selector, SegmentDescriptor::default());
+            return Ok(());
+        }
+
+        let code = selector & 0xFFFC;
+        let rpl = (selector & 3) as u8;
+        let desc = self.read_checked_descriptor(selector)?;
+        let dpl = desc.dpl();
+        let executable = desc.flags & EXECUTABLE != 0;
+        let conforming = executable && desc.flags & CONFORMING_EXPAND_DOWN != 0;
+        let readable = !executable || desc.flags & READ_WRITE != 0;
+        if !readable || (!conforming && (rpl > dpl || cpl > dpl)) {
+            return Err(Exception::GeneralProtection(code));
+        }
+        if !desc.is_present() {
+            return Err(Exception::SegmentNotPresent(code));
+        }
+
+        self.set_segment(reg, selector, desc);
+        Ok(())
+    }
+
+    pub fn set_segment(&mut self, reg: Register, selector: u16, desc: SegmentDescriptor) {
+        self.gpr.set_register_value(reg, selector as usize);
+        self.segments.get_mut(reg).load_descriptor(selector, desc);
+    }
+
+    // after a return to an outer level, data segments (and non-conforming
+    // code) more privileged than the new CPL are nulled so that the outer
+    // ring cannot keep using what the inner one had loaded
+    pub fn null_outer_data_segments(&mut self) {
+        for reg in [Register::ES, Register::DS, Register::FS, Register::GS] {
+            let cache = self.segments.get(reg);
+            let conforming = cache.is_code() && cache.flags & CONFORMING_EXPAND_DOWN != 0;
+            if cache.selector & !3 != 0 && cache.dpl() < self.cpl && !conforming {
+                self.set_segment(reg, 0, SegmentDescriptor::default());
+            }
+        }
+    }
+
+    // the checks for an SS load at the given CPL without loading it, so
+    // stack switches can validate the new stack before committing anything;
+    // a 64-bit stack may be null outside of ring 3
+    pub fn check_stack_segment(&mut self, selector: u16, cpl: u8, long: bool) -> Result<SegmentDescriptor, Exception> {
+        let code = selector & 0xFFFC;
+        if selector & !3 == 0 {
+            if !long || cpl == 3 {
+                return Err(Exception::GeneralProtection(0));
+            }
+            return Ok(SegmentDescriptor::default());
+        }
+        let rpl = (selector & 3) as u8;
+        let desc = self.read_checked_descriptor(selector)?;
+        let writable = desc.flags & EXECUTABLE == 0 && desc.flags & READ_WRITE != 0;
+        if rpl != cpl || desc.dpl() != cpl || !writable {
+            return Err(Exception::GeneralProtection(code));
+        }
+        if !desc.is_present() {
+            return Err(Exception::StackFault(code));
+        }
+        Ok(desc)
+    }
+
+    // CS loads for JMP/CALL keep the current privilege level, RET and IRET
+    // (returning) may move outwards to the selector's RPL
+    pub fn load_code_segment(&mut self, selector: u16, cpl: u8, returning: bool) -> Result<(), Exception> {
+        if self.is_real() {
+            self.gpr.set_register_value(Register::CS, selector as usize);
+            self.segments.get_mut(Register::CS).load_real(selector);
+            return Ok(());
+        }
+        let (desc, new_cpl) = self.check_code_segment(selector, cpl, returning)?;
+        self.set_code_segment(selector, desc, new_cpl);
+        Ok(())
+    }
+
+    // the descriptor and the CPL it would run at, nothing is loaded yet
+    pub fn check_code_segment(&mut self, selector: u16, cpl: u8, returning: bool) -> Result<(SegmentDescriptor, u8), Exception> {
+        let code = selector & 0xFFFC;
+        if selector & !3 == 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let desc = self.read_checked_descriptor(selector)?;
+        let rpl = (selector & 3) as u8;
+        let dpl = desc.dpl();
+        if desc.flags & EXECUTABLE == 0 {
+            return Err(Exception::GeneralProtection(code));
+        }
+        // L and D together are reserved once long mode is active
+        if self.cr.efer & EFER_LMA != 0 && desc.flags & LONG != 0 && desc.flags & DEFAULT_BIG != 0 {
+            return Err(Exception::GeneralProtection(code));
+        }
+
+        let new_cpl = if returning { rpl } else { cpl };
+        let conforming = desc.flags & CONFORMING_EXPAND_DOWN != 0;
+        let privilege_ok = if returning {
+            rpl >= cpl && if conforming { dpl <= rpl } else { dpl == rpl }
+        } else if conforming {
+            dpl <= cpl
+        } else {
+            rpl <= cpl && dpl == cpl
+        };
+        if !privilege_ok {
+            return Err(Exception::GeneralProtection(code));
+        }
+        if !desc.is_present() {
+            return Err(Exception::SegmentNotPresent(code));
+        }
+        Ok((desc, new_cpl))
+    }
+
+    // from here on CS.RPL reflects the current privilege level
+    pub fn set_code_segment(&mut self, selector: u16, desc: SegmentDescriptor, cpl: u8) {
+        let selector = (selector & 0xFFFC) | cpl as u16;
+        self.cpl = cpl;
+        self.gpr.set_register_value(Register::CS, selector as usize);
+        self.segments.get_mut(Register::CS).load_descriptor(selector, desc);
+        self.update_mode();
+    }
+
+    fn table_register_bits(instr: Instruction) -> Bits {
+        match instr.code() {
+            Code::Lgdt_m1632_16 | Code::Sgdt_m1632_16 | Code::Lidt_m1632_16 | Code::Sidt_m1632_16 => Bits::Bit16,
+            Code::Lgdt_m1664 | Code::Sgdt_m1664 | Code::Lidt_m1664 | Code::Sidt_m1664 => Bits::Bit64,
+            _ => Bits::Bit32
+        }
+    }
+
+    // the 6 or 10 byte pseudo-descriptor used by LGDT and LIDT
+    pub fn read_table_register(&mut self, instr: Instruction) -> Result<(u64, u16), Exception> {
+        let (seg, offset) = self.operand_location(instr);
+        let limit = self.read_memory(seg, offset, Bits::Bit16)? as u16;
+        let base = match Self::table_register_bits(instr) {
+            // a 16-bit operand only loads 24 bits of base
+            Bits::Bit16 => self.read_memory(seg, offset + 2, Bits::Bit32)? & 0xFF_FFFF,
+            Bits::Bit64 => self.read_memory(seg, offset + 2, Bits::Bit64)?,
+            _ => self.read_memory(seg, offset + 2, Bits::Bit32)?
+        };
+        Ok((base, limit))
+    }
+
+    pub fn write_table_register(&mut self, instr: Instruction, base: u64, limit: u16) -> Result<(), Exception> {
+        let (seg, offset) = self.operand_location(instr);
+        self.write_memory(seg, offset, limit as u64, Bits::Bit16)?;
+        match Self::table_register_bits(instr) {
+            Bits::Bit64 => self.write_memory(seg, offset + 2, base, Bits::Bit64),
+            _ => self.write_memory(seg, offset + 2, base, Bits::Bit32)
+        }
+    }
+
+    // CLI and STI outside real mode need CPL <= IOPL
+    pub fn check_iopl(&self) -> Result<(), Exception> {
+        if !self.is_real() && self.cpl() as u64 > (self.flags.flags & IOPL) >> 12 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        Ok(())
+    }
+
+    pub fn lgdt(&mut self, instr: Instruction) -> Result<(), Exception> {
+        if self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let (base, limit) = self.read_table_register(instr)?;
+        self.gdt.base = base;
+        self.gdt.limit = limit;
+        Ok(())
+    }
+
+    pub fn sgdt(&mut self, instr: Instruction) -> Result<(), Exception> {
+        self.write_table_register(instr, self.gdt.base, self.gdt.limit)
+    }
+
+    pub fn lidt(&mut self, instr: Instruction) -> Result<(), Exception> {
+        if self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let (base, limit) = self.read_table_register(instr)?;
+        self.idt.base = base;
+        self.idt.limit = limit;
+        Ok(())
+    }
+
+    pub fn sidt(&mut self, instr: Instruction) -> Result<(), Exception> {
+        self.write_table_register(instr, self.idt.base, self.idt.limit)
+    }
+
+    pub fn smsw(&mut self, instr: Instruction) -> Result<(), Exception> {
+        self.write_op0(instr, (self.cr.cr0 & 0xFFFF) as usize)
+    }
+
+    // LMSW can set PE but never clear it
+    pub fn lmsw(&mut self, instr: Instruction) -> Result<(), Exception> {
+        if self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let value = self.get_op0value(instr)? as u64 & 0xF;
+        let cr0 = (self.cr.cr0 & !0xE) | value | (self.cr.cr0 & CR0_PE);
+        self.set_control_register(Register::CR0, cr0)
+    }
+
+    pub fn jump(&mut self, instr: Instruction) -> Result<(), Exception> {
+        match instr.op0_kind() {
+            OpKind::FarBranch16 => self.far_jump(instr.far_branch_selector(), instr.far_branch16() as u64),
+            OpKind::FarBranch32 => self.far_jump(instr.far_branch_selector(), instr.far_branch32() as u64),
+            OpKind::Memory if matches!(instr.code(), Code::Jmp_m1616 | Code::Jmp_m1632 | Code::Jmp_m1664) => {
+                let (selector, offset, _) = self.read_far_pointer(instr)?;
+                self.far_jump(selector, offset)
+            },
+            _ => {
+                let target = self.branch_target(instr)?;
+                self.ip.rip = target;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn far_jump(&mut self, selector: u16, offset: u64) -> Result<(), Exception> {
+        self.load_segment(Register::CS, selector)?;
+        self.ip.rip = offset;
+        Ok(())
+    }
+
+    // LDS, LES, LSS, LFS and LGS
+    pub fn load_far_pointer(&mut self, instr: Instruction, seg: Register) -> Result<(), Exception> {
+        let (selector, offset, _) = self.read_far_pointer(instr)?;
+        self.load_segment(seg, selector)?;
+        self.write_op0(instr, offset as usize)
+    }
+}
@@ -0,0 +1,286 @@
+use iced_x86::{Code, Instruction, MemorySize, OpKind, Register};
+use crate::ast::Bits;
+use crate::vm::cpu::Cpu;
+use crate::vm::cpu::protected::EFER_LMA;
+use crate::vm::exception::Exception;
+use crate::vm::flags::{IF, IOPL};
+use crate::vm::segment::LONG;
+use crate::vm::Mode;
+
+impl Cpu {
+    pub fn stack_bits(&self) -> Bits {
+        match self.mode {
+            Mode::Protected if self.segments.get(Register::SS).is_big() => Bits::Bit32,
+            Mode::Real | Mode::Protected => Bits::Bit16,
+            Mode::Long => Bits::Bit64
+        }
+    }
+
+    fn stack_register(&self) -> Register {
+        match self.stack_bits() {
+            Bits::Bit16 => Register::SP,
+            Bits::Bit32 => Register::ESP,
+            _ => Register::RSP
+        }
+    }
+
+    fn frame_register(&self) -> Register {
+        match self.stack_bits() {
+            Bits::Bit16 => Register::BP,
+            Bits::Bit32 => Register::EBP,
+            _ => Register::RBP
+        }
+    }
+
+    pub fn stack_pointer(&self) -> u64 {
+        self.gpr.get_register_value(self.stack_register())
+    }
+
+    pub fn set_stack_pointer(&mut self, sp: u64) {
+        let sp = sp & self.stack_bits().mask();
+        self.gpr.set_register_value(self.stack_register(), sp as usize);
+    }
+
+    // writes below sp and returns the new stack pointer without committing
+    // it, so a faulting push restarts with SP untouched
+    pub fn push_at(&mut self, sp: u64, value: u64, bits: Bits) -> Result<u64, Exception> {
+        let sp = sp.wrapping_sub(bits.bytes() as u64) & self.stack_bits().mask();
+        self.write_memory(Register::SS, sp, value, bits)?;
+        Ok(sp)
+    }
+
+    pub fn push(&mut self, value: u64, bits: Bits) -> Result<(), Exception> {
+        let sp = self.push_at(self.stack_pointer(), value, bits)?;
+        self.set_stack_pointer(sp);
+        Ok(())
+    }
+
+    // the value at sp and the stack pointer above it, again uncommitted
+    pub fn pop_at(&mut self, sp: u64, bits: Bits) -> Result<(u64, u64), Exception> {
+        let value = self.read_memory(Register::SS, sp & self.stack_bits().mask(), bits)?;
+        Ok((value, sp.wrapping_add(bits.bytes() as u64)))
+    }
+
+    pub fn pop(&mut self, bits: Bits) -> Result<u64, Exception> {
+        let (value, sp) = self.pop_at(self.stack_pointer(), bits)?;
+        self.set_stack_pointer(sp);
+        Ok(value)
+    }
+
+    pub fn flags_image(&self) -> u64 {
+        // bit 1 is reserved and always reads as one
+        self.flags.flags | 2
+    }
+
+    pub fn load_flags_image(&mut self, value: u64, bits: Bits) {
+        let writable = match bits {
+            Bits::Bit16 => 0x7FD5,
+            _ => 0x24_7FD5
+        };
+        self.flags.flags = (self.flags.flags & !writable) | (value & writable);
+    }
+
+    // IOPL is only writable from ring 0 and IF only up to IOPL, for POPF
+    // and IRET alike
+    pub fn keep_privileged_flags(&self, value: u64, cpl: u8) -> u64 {
+        let mut value = value;
+        if cpl > 0 {
+            value = (value & !IOPL) | (self.flags.flags & IOPL);
+        }
+        if cpl as u64 > (self.flags.flags & IOPL) >> 12 {
+            value = (value & !IF) | (self.flags.flags & IF);
+        }
+        value
+    }
+
+    pub fn push_instr(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = Bits::from_size(-instr.stack_pointer_increment() as usize);
+        let value = match instr.op0_kind() {
+            OpKind::Register | OpKind::Memory => self.get_op0value(instr)? as u64,
+            _ => instr.immediate(0)
+        };
+        self.push(value, bits)?;
+        Ok(())
+    }
+
+    pub fn pop_instr(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = Bits::from_size(instr.stack_pointer_increment() as usize);
+        let value = self.pop(bits)?;
+        self.write_op0(instr, value as usize)?;
+        Ok(())
+    }
+
+    pub fn pusha(&mut self, bits: Bits) -> Result<(), Exception> {
+        // the stack pointer is pushed as it was before the first push
+        let regs = match bits {
+            Bits::Bit16 => [Register::AX, Register::CX, Register::DX, Register::BX, Register::SP, Register::BP, Register::SI, Register::DI],
+            _ => [Register::EAX, Register::ECX, Register::EDX, Register::EBX, Register::ESP, Register::EBP, Register::ESI, Register::EDI]
+        };
+        let mut sp = self.stack_pointer();
+        for reg in regs {
+            sp = self.push_at(sp, self.gpr.get_register_value(reg), bits)?;
+        }
+        self.set_stack_pointer(sp);
+        Ok(())
+    }
+
+    pub fn popa(&mut self, bits: Bits) -> Result<(), Exception> {
+        let regs = match bits {
+            Bits::Bit16 => [Register::DI, Register::SI, Register::BP, Register::SP, Register::BX, Register::DX, Register::CX, Register::AX],
+            _ => [Register::EDI, Register::ESI, Register::EBP, Register::ESP, Register::EBX, Register::EDX, Register::ECX, Register::EAX]
+        };
+        for reg in regs {
+            let value = self.pop(bits)?;
+            // the saved stack pointer is discarded
+            if reg != Register::SP && reg != Register::ESP {
+                self.gpr.set_register_value(reg, value as usize);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn call(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let return_ip = self.ip.rip;
+        match instr.op0_kind() {
+            OpKind::FarBranch16 | OpKind::FarBranch32 => {
+                let bits = if instr.op0_kind() == OpKind::FarBranch16 { Bits::Bit16 } else { Bits::Bit32 };
+                let offset = if bits == Bits::Bit16 { instr.far_branch16() as u64 } else { instr.far_branch32() as u64 };
+                self.far_call(instr.far_branch_selector(), offset, return_ip, bits)?;
+            },
+            OpKind::Memory if Self::is_far_pointer(instr.memory_size()) => {
+                let (selector, offset, bits) = self.read_far_pointer(instr)?;
+                self.far_call(selector, offset, return_ip, bits)?;
+            },
+            _ => {
+                let bits = Bits::from_size(-instr.stack_pointer_increment() as usize);
+                let target = self.branch_target(instr)? & bits.mask();
+                self.push(return_ip, bits)?;
+                self.ip.rip = target;
+            }
+        }
+        Ok(())
+    }
+
+    fn far_call(&mut self, selector: u16, offset: u64, return_ip: u64, bits: Bits) -> Result<(), Exception> {
+        let cs = self.gpr.get_register_value(Register::CS);
+        let sp = self.push_at(self.stack_pointer(), cs, bits)?;
+        let sp = self.push_at(sp, return_ip, bits)?;
+        self.load_segment(Register::CS, selector)?;
+        self.set_stack_pointer(sp);
+        self.ip.rip = offset;
+        Ok(())
+    }
+
+    fn is_far_pointer(size: MemorySize) -> bool {
+        matches!(size, MemorySize::SegPtr16 | MemorySize::SegPtr32 | MemorySize::SegPtr64)
+    }
+
+    pub fn read_far_pointer(&mut self, instr: Instruction) -> Result<(u16, u64, Bits), Exception> {
+        let bits = match instr.memory_size() {
+            MemorySize::SegPtr16 => Bits::Bit16,
+            MemorySize::SegPtr32 => Bits::Bit32,
+            _ => Bits::Bit64
+        };
+        let (seg, addr) = self.operand_location(instr);
+        let offset = self.read_memory(seg, addr, bits)?;
+        let selector = self.read_memory(seg, addr + bits.bytes() as u64, Bits::Bit16)? as u16;
+        Ok((selector, offset, bits))
+    }
+
+    pub fn ret(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let release = if instr.op_count() == 1 { instr.immediate16() as u64 } else { 0 };
+        let bits = match instr.code() {
+            Code::Retnw | Code::Retnw_imm16 | Code::Retfw | Code::Retfw_imm16 => Bits::Bit16,
+            Code::Retnd | Code::Retnd_imm16 | Code::Retfd | Code::Retfd_imm16 => Bits::Bit32,
+            _ => Bits::Bit64
+        };
+        let (ip, sp) = self.pop_at(self.stack_pointer(), bits)?;
+        if instr.mnemonic() == iced_x86::Mnemonic::Retf && !self.is_real() {
+            // everything is read and checked before anything is loaded
+            let cpl = self.cpl();
+            let (cs, sp) = self.pop_at(sp, bits)?;
+            let cs = cs as u16;
+            let (desc, new_cpl) = self.check_code_segment(cs, cpl, true)?;
+            let sp = sp.wrapping_add(release);
+            // an outward return also restores the caller's stack, the
+            // parameters are released from both
+            let stack = if new_cpl > cpl {
+                let (new_sp, sp) = self.pop_at(sp, bits)?;
+                let (ss, _) = self.pop_at(sp, bits)?;
+                let ss = ss as u16;
+                let long = self.cr.efer & EFER_LMA != 0 && desc.flags & LONG != 0;
+                Some((ss, self.check_stack_segment(ss, new_cpl, long)?, new_sp.wrapping_add(release)))
+            } else {
+                None
+            };
+            match stack {
+                Some((ss, ss_desc, sp)) => {
+                    self.set_code_segment(cs, desc, new_cpl);
+                    self.set_segment(Register::SS, ss, ss_desc);
+                    self.set_stack_pointer(sp);
+                    self.null_outer_data_segments();
+                },
+                None => {
+                    self.set_stack_pointer(sp);
+                    self.set_code_segment(cs, desc, new_cpl);
+                }
+            }
+            self.ip.rip = ip;
+            return Ok(());
+        }
+        let sp = if instr.mnemonic() == iced_x86::Mnemonic::Retf {
+            let (cs, sp) = self.pop_at(sp, bits)?;
+            self.load_segment(Register::CS, cs as u16)?;
+            sp
+        } else {
+            sp
+        };
+        self.ip.rip = ip;
+        self.set_stack_pointer(sp.wrapping_add(release));
+        Ok(())
+    }
+
+    pub fn enter(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = match instr.code() {
+            Code::Enterw_imm16_imm8 => Bits::Bit16,
+            Code::Enterd_imm16_imm8 => Bits::Bit32,
+            _ => Bits::Bit64
+        };
+        let size = instr.immediate16() as u64;
+        let level = (instr.immediate8_2nd() & 0x1F) as u64;
+        let frame = self.frame_register();
+
+        let mut sp = self.push_at(self.stack_pointer(), self.gpr.get_register_value(frame), bits)?;
+        let frame_temp = sp;
+        if level > 0 {
+            let mut bp = self.gpr.get_register_value(frame);
+            for _ in 1..level {
+                bp = bp.wrapping_sub(bits.bytes() as u64) & self.stack_bits().mask();
+                let value = self.read_memory(Register::SS, bp, bits)?;
+                sp = self.push_at(sp, value, bits)?;
+            }
+            sp = self.push_at(sp, frame_temp, bits)?;
+        }
+        self.gpr.set_register_value(frame, frame_temp as usize);
+        self.set_stack_pointer(sp.wrapping_sub(size));
+        Ok(())
+    }
+
+    pub fn leave(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let bits = match instr.code() {
+            Code::Leavew => Bits::Bit16,
+            Code::Leaved => Bits::Bit32,
+            _ => Bits::Bit64
+        };
+        let frame = self.frame_register();
+        self.set_stack_pointer(self.gpr.get_register_value(frame));
+        let bp = self.pop(bits)?;
+        let frame = match bits {
+            Bits::Bit16 => Register::BP,
+            Bits::Bit32 => Register::EBP,
+            _ => Register::RBP
+        };
+        self.gpr.set_register_value(frame, bp as usize);
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use iced_x86::{Instruction, Mnemonic, OpKind, Register};
+use crate::ast::Bits;
+use crate::vm::cpu::Cpu;
+use crate::vm::exception::Exception;
+use crate::vm::flags::{DF, ZF};
+
+impl Cpu {
+    pub fn is_string_op(instr: Instruction) -> bool {
+        (0..instr.op_count()).any(|i| matches!(instr.op_kind(i),
+            OpKind::MemorySegSI | OpKind::MemorySegESI | OpKind::MemorySegRSI |
+            OpKind::MemoryESDI | OpKind::MemoryESEDI | OpKind::MemoryESRDI))
+    }
+
+    fn string_address_bits(instr: Instruction) -> Bits {
+        for i in 0..instr.op_count() {
+            match instr.op_kind(i) {
+                OpKind::MemorySegSI | OpKind::MemoryESDI => return Bits::Bit16,
+                OpKind::MemorySegESI | OpKind::MemoryESEDI => return Bits::Bit32,
+                OpKind::MemorySegRSI | OpKind::MemoryESRDI => return Bits::Bit64,
+                _ => {}
+            }
+        }
+        Bits::Bit16
+    }
+
+    fn index_registers(bits: Bits) -> (Register, Register, Register) {
+        match bits {
+            Bits::Bit16 => (Register::SI, Register::DI, Register::CX),
+            Bits::Bit32 => (Register::ESI, Register::EDI, Register::ECX),
+            _ => (Register::RSI, Register::RDI, Register::RCX)
+        }
+    }
+
+    fn advance(&mut self, reg: Register, step: u64, bits: Bits) {
+        let value = self.gpr.get_register_value(reg).wrapping_add(step) & bits.mask();
+        self.gpr.set_register_value(reg, value as usize);
+    }
+
+    // one iteration per call: a repeated instruction rewinds IP onto itself
+    // until the count runs out, so interrupts can be taken in between
+    pub fn string_op(&mut self, instr: Instruction) -> Result<(), Exception> {
+        let addr_bits = Self::string_address_bits(instr);
+        let (si, di, cx) = Self::index_registers(addr_bits);
+        let repeated = instr.has_rep_prefix() || instr.has_repne_prefix();
+
+        if repeated && self.gpr.get_register_value(cx) & addr_bits.mask() == 0 {
+            return Ok(());
+        }
+
+        let bits = Bits::from_size(instr.memory_size().size());
+        let step = if self.flags.get(DF) {
+            (bits.bytes() as u64).wrapping_neg()
+        } else {
+            bits.bytes() as u64
+        };
+        // only the source (DS:SI) honours a segment override, ES:DI is fixed
+        let source_seg = instr.memory_segment();
+        let source = self.gpr.get_register_value(si);
+        let dest = self.gpr.get_register_value(di);
+        let acc = Self::accumulator(bits);
+        let port = self.gpr.get_register_value(Register::DX) as u16;
+
+        let mut compare = false;
+        match instr.mnemonic() {
+            Mnemonic::Movsb | Mnemonic::Movsw | Mnemonic::Movsd | Mnemonic::Movsq => {
+                let value = self.read_memory(source_seg, source, bits)?;
+                self.write_memory(Register::ES, dest, value, bits)?;
+                self.advance(si, step, addr_bits);
+                self.advance(di, step, addr_bits);
+            },
+            Mnemonic::Stosb | Mnemonic::Stosw | Mnemonic::Stosd | Mnemonic::Stosq => {
+                let value = self.gpr.get_register_value(acc);
+                self.write_memory(Register::ES, dest, value, bits)?;
+                self.advance(di, step, addr_bits);
+            },
+            Mnemonic::Lodsb | Mnemonic::Lodsw | Mnemonic::Lodsd | Mnemonic::Lodsq => {
+                let value = self.read_memory(source_seg, source, bits)?;
+                self.gpr.set_register_value(acc, value as usize);
+                self.advance(si, step, addr_bits);
+            },
+            Mnemonic::Cmpsb | Mnemonic::Cmpsw | Mnemonic::Cmpsd | Mnemonic::Cmpsq => {
+                let a = self.read_memory(source_seg, source, bits)?;
+                let b = self.read_memory(Register::ES, dest, bits)?;
+                self.flags.sub(a, b, false, bits);
+                self.advance(si, step, addr_bits);
+                self.advance(di, step, addr_bits);
+                compare = true;
+            },
+            Mnemonic::Scasb | Mnemonic::Scasw | Mnemonic::Scasd | Mnemonic::Scasq => {
+                let a = self.gpr.get_register_value(acc);
+                let b = self.read_memory(Register::ES, dest, bits)?;
+                self.flags.sub(a, b, false, bits);
+                self.advance(di, step, addr_bits);
+                compare = true;
+            },
+            Mnemonic::Insb | Mnemonic::Insw | Mnemonic::Insd => {
+                let value = self.port_in(port, bits)?;
+                self.write_memory(Register::ES, dest, value, bits)?;
+                self.advance(di, step, addr_bits);
+            },
+            Mnemonic::Outsb | Mnemonic::Outsw | Mnemonic::Outsd => {
+                let value = self.read_memory(source_seg, source, bits)?;
+                self.port_out(port, value, bits)?;
+                self.advance(si, step, addr_bits);
+            },
+            _ => {}
+        }
+
+        if repeated {
+            let count = self.gpr.get_register_value(cx).wrapping_sub(1) & addr_bits.mask();
+            self.gpr.set_register_value(cx, count as usize);
+
+            // F3 is REPE and F2 is REPNE for CMPS and SCAS
+            let terminated = compare && if instr.has_repne_prefix() {
+                self.flags.get(ZF)
+            } else {
+                !self.flags.get(ZF)
+            };
+            if count != 0 && !terminated {
+                self.ip.rip = instr.ip();
+            }
+        }
+        Ok(())
+    }
+}
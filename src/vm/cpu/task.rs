@@ -0,0 +1,205 @@
+use iced_x86::{Instruction, Register};
+use crate::ast::Bits;
+use crate::vm::cpu::protected::{CR0_PG, CR0_TS, EFER_LMA};
+use crate::vm::cpu::Cpu;
+use crate::vm::exception::Exception;
+use crate::vm::flags::{IOPL, NT};
+use crate::vm::segment::{SegmentDescriptor, TaskRegister, CODE_DATA, EXECUTABLE, TSS_AVAILABLE, TSS_BUSY};
+
+// 32-bit TSS layout
+const TSS_LINK: u64 = 0x00;
+const TSS_CR3: u64 = 0x1C;
+const TSS_EIP: u64 = 0x20;
+const TSS_EFLAGS: u64 = 0x24;
+const TSS_REGISTERS: u64 = 0x28;
+const TSS_SEGMENTS: u64 = 0x48;
+const TSS_IO_MAP: u64 = 0x66;
+const TSS_MIN_LIMIT: u32 = 0x67;
+
+// 64-bit TSS: RSP0-2 from offset 4, IST1-7 from 0x24
+const TSS64_IST: u64 = 0x24;
+
+const TSS_GPRS: [Register; 8] = [
+    Register::EAX, Register::ECX, Register::EDX, Register::EBX,
+    Register::ESP, Register::EBP, Register::ESI, Register::EDI,
+];
+const TSS_SEGS: [Register; 6] = [Register::ES, Register::CS, Register::SS, Register::DS, Register::FS, Register::GS];
+
+impl Cpu {
+    // TSS descriptors grow to 16 bytes in long mode for the upper base
+    fn read_tss_descriptor(&mut self, selector: u16) -> Result<(u64, SegmentDescriptor, u64), Exception> {
+        let code = selector & 0xFFFC;
+        if selector & 4 != 0 || selector & !3 == 0 {
+            return Err(Exception::GeneralProtection(code));
+        }
+        let addr = self.gdt.descriptor_address(selector).ok_or(Exception::GeneralProtection(code))?;
+        let desc = SegmentDescriptor::from_raw(self.read_system(addr, Bits::Bit64)?);
+        let mut base = desc.base as u64;
+        if self.cr.efer & EFER_LMA != 0 {
+            base |= (self.read_system(addr + 8, Bits::Bit32)?) << 32;
+        }
+        if desc.flags & CODE_DATA != 0 || !matches!(desc.flags & 0xF, TSS_AVAILABLE | TSS_BUSY) {
+            return Err(Exception::GeneralProtection(code));
+        }
+        Ok((addr, desc, base))
+    }
+
+    fn set_busy(&mut self, addr: u64, busy: bool) -> Result<(), Exception> {
+        let access = self.read_system(addr + 5, Bits::Bit8)?;
+        let access = if busy { access | 2 } else { access & !2 };
+        self.write_system(addr + 5, access, Bits::Bit8)
+    }
+
+    pub fn ltr(&mut self, instr: Instruction) -> Result<(), Exception> {
+        if self.is_real() {
+            return Err(Exception::InvalidOpcode);
+        }
+        if self.cpl() != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        let selector = self.get_op0value(instr)? as u16;
+        let (addr, desc, base) = self.read_tss_descriptor(selector)?;
+        if desc.flags & 0xF != TSS_AVAILABLE {
+            return Err(Exception::GeneralProtection(selector & 0xFFFC));
+        }
+        if !desc.is_present() {
+            return Err(Exception::SegmentNotPresent(selector & 0xFFFC));
+        }
+        self.set_busy(addr, true)?;
+        self.tr = TaskRegister { selector, base, limit: desc.limit };
+        Ok(())
+    }
+
+    pub fn str(&mut self, instr: Instruction) -> Result<(), Exception> {
+        if self.is_real() {
+            return Err(Exception::InvalidOpcode);
+        }
+        self.write_op0(instr, self.tr.selector as usize)
+    }
+
+    fn read_tss(&mut self, offset: u64, bits: Bits) -> Result<u64, Exception> {
+        if offset + bits.bytes() as u64 - 1 > self.tr.limit as u64 {
+            return Err(Exception::InvalidTss(self.tr.selector & 0xFFFC));
+        }
+        self.read_system(self.tr.base + offset, bits)
+    }
+
+    // SS:ESP for an inner privilege level; long mode has no SS in the TSS
+    // and runs on a null SS carrying the new RPL
+    pub fn tss_stack(&mut self, dpl: u8) -> Result<(u16, u64), Exception> {
+        let offset = 4 + dpl as u64 * 8;
+        if self.cr.efer & EFER_LMA != 0 {
+            return Ok((dpl as u16, self.read_tss(offset, Bits::Bit64)?));
+        }
+        let sp = self.read_tss(offset, Bits::Bit32)?;
+        let ss = self.read_tss(offset + 4, Bits::Bit16)? as u16;
+        Ok((ss, sp))
+    }
+
+    pub fn ist_stack(&mut self, ist: u8) -> Result<u64, Exception> {
+        self.read_tss(TSS64_IST + (ist as u64 - 1) * 8, Bits::Bit64)
+    }
+
+    // above IOPL every port touched must be clear in the TSS I/O bitmap; a
+    // bitmap that is cut short by the TSS limit denies the access
+    pub fn check_io(&mut self, port: u16, bits: Bits) -> Result<(), Exception> {
+        if self.is_real() || self.cpl() as u64 <= (self.flags.flags & IOPL) >> 12 {
+            return Ok(());
+        }
+        let map = self.read_tss(TSS_IO_MAP, Bits::Bit16).map_err(|_| Exception::GeneralProtection(0))?;
+        let permissions = self.read_tss(map + port as u64 / 8, Bits::Bit16).map_err(|_| Exception::GeneralProtection(0))?;
+        let mask = ((1 << bits.bytes()) - 1) << (port & 7);
+        if permissions & mask != 0 {
+            return Err(Exception::GeneralProtection(0));
+        }
+        Ok(())
+    }
+
+    // hardware task switch through a task gate (nested) or back along the
+    // previous task link on IRET with NT set
+    pub fn task_switch(&mut self, selector: u16, nested: bool) -> Result<(), Exception> {
+        let code = selector & 0xFFFC;
+        if self.cr.efer & EFER_LMA != 0 {
+            return Err(Exception::GeneralProtection(code));
+        }
+        let (addr, desc, base) = self.read_tss_descriptor(selector)
+            .map_err(|_| if nested { Exception::GeneralProtection(code) } else { Exception::InvalidTss(code) })?;
+        let expected = if nested { TSS_AVAILABLE } else { TSS_BUSY };
+        if desc.flags & 0xF != expected {
+            return Err(if nested { Exception::GeneralProtection(code) } else { Exception::InvalidTss(code) });
+        }
+        if !desc.is_present() {
+            return Err(Exception::SegmentNotPresent(code));
+        }
+        if desc.limit < TSS_MIN_LIMIT {
+            return Err(Exception::InvalidTss(code));
+        }
+
+        // save the outgoing task
+        let old = self.tr;
+        let mut flags = self.flags_image();
+        if !nested {
+            flags &= !NT;
+            if let Some(old_addr) = self.gdt.descriptor_address(old.selector) {
+                self.set_busy(old_addr, false)?;
+            }
+        }
+        self.write_system(old.base + TSS_EIP, self.ip.rip, Bits::Bit32)?;
+        self.write_system(old.base + TSS_EFLAGS, flags, Bits::Bit32)?;
+        for (i, reg) in TSS_GPRS.iter().enumerate() {
+            let value = self.gpr.get_register_value(*reg);
+            self.write_system(old.base + TSS_REGISTERS + i as u64 * 4, value, Bits::Bit32)?;
+        }
+        for (i, reg) in TSS_SEGS.iter().enumerate() {
+            let value = self.gpr.get_register_value(*reg);
+            self.write_system(old.base + TSS_SEGMENTS + i as u64 * 4, value, Bits::Bit16)?;
+        }
+
+        // load the incoming one
+        let cr3 = self.read_system(base + TSS_CR3, Bits::Bit32)?;
+        let eip = self.read_system(base + TSS_EIP, Bits::Bit32)?;
+        let mut eflags = self.read_system(base + TSS_EFLAGS, Bits::Bit32)?;
+        let mut registers = [0; 8];
+        for (i, value) in registers.iter_mut().enumerate() {
+            *value = self.read_system(base + TSS_REGISTERS + i as u64 * 4, Bits::Bit32)?;
+        }
+        let mut selectors = [0u16; 6];
+        for (i, value) in selectors.iter_mut().enumerate() {
+            *value = self.read_system(base + TSS_SEGMENTS + i as u64 * 4, Bits::Bit16)? as u16;
+        }
+        if nested {
+            self.write_system(base + TSS_LINK, old.selector as u64, Bits::Bit16)?;
+            self.set_busy(addr, true)?;
+            eflags |= NT;
+        }
+
+        self.tr = TaskRegister { selector, base, limit: desc.limit };
+        self.cr.cr0 |= CR0_TS;
+        if self.cr.cr0 & CR0_PG != 0 {
+            self.cr.cr3 = cr3;
+            self.tlb.flush(true);
+        }
+        self.ip.rip = eip;
+        self.flags.flags = eflags & !2;
+        for (reg, value) in TSS_GPRS.iter().zip(registers) {
+            self.gpr.set_register_value(*reg, value as usize);
+        }
+
+        // the new CPL comes from the CS selector stored in the TSS
+        let cs = selectors[1];
+        let cs_desc = self.read_checked_descriptor(cs).map_err(|_| Exception::InvalidTss(cs & 0xFFFC))?;
+        if cs_desc.flags & EXECUTABLE == 0 {
+            return Err(Exception::InvalidTss(cs & 0xFFFC));
+        }
+        if !cs_desc.is_present() {
+            return Err(Exception::SegmentNotPresent(cs & 0xFFFC));
+        }
+        self.set_code_segment(cs, cs_desc, (cs & 3) as u8);
+        for (reg, selector) in TSS_SEGS.iter().zip(selectors) {
+            if *reg != Register::CS {
+                self.load_segment(*reg, selector).map_err(|_| Exception::InvalidTss(selector & 0xFFFC))?;
+            }
+        }
+        Ok(())
+    }
+}
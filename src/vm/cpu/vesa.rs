@@ -0,0 +1,300 @@
+use iced_x86::Register;
+use crate::ast::Bits;
+use crate::vm::cpu::Cpu;
+use crate::vm::cpu::video::BDA_VIDEO_MODE;
+use crate::vm::vbe::{DISPI_BPP, DISPI_DATA, DISPI_ENABLE, DISPI_ENABLED, DISPI_INDEX, DISPI_LFB_ENABLED, DISPI_NOCLEARMEM, DISPI_VIRT_HEIGHT, DISPI_VIRT_WIDTH, DISPI_XRES, DISPI_X_OFFSET, DISPI_YRES, DISPI_Y_OFFSET, LFB_BASE, VIDEO_MEMORY};
+use crate::vm::vga::{DAC_DATA, DAC_READ_INDEX, DAC_WRITE_INDEX};
+
+// AL=4Fh says the function is there, AH is the status
+const VBE_SUCCESS: usize = 0x004F;
+const VBE_FAILED: usize = 0x014F;
+const VBE_INVALID_IN_MODE: usize = 0x034F;
+
+// AX=4F02h mode number flags
+const MODE_NUMBER: u16 = 0x01FF;
+const MODE_LFB: u16 = 1 << 14;
+const MODE_NO_CLEAR: u16 = 1 << 15;
+
+// the controller info block is 256 bytes, 512 when the caller asks for VBE 2
+const CONTROLLER_INFO_SIZE: usize = 0x100;
+const CONTROLLER_INFO_VBE2_SIZE: usize = 0x200;
+const MODE_INFO_SIZE: usize = 0x100;
+// the mode list and the strings go into the reserved part of the block
+const CONTROLLER_INFO_RESERVED: usize = 0x22;
+const VBE_VERSION: u16 = 0x0300;
+const OEM_STRINGS: [&[u8]; 4] = [b"XVm VBE BIOS\0", b"XVm\0", b"XVm DISPI adapter\0", b"1.0\0"];
+
+// supported, reserved, color, graphics, not VGA compatible, no banked
+// window, linear framebuffer
+const MODE_ATTRIBUTES: u16 = 0x00FB;
+const MEMORY_MODEL_PACKED: u8 = 0x04;
+const MEMORY_MODEL_DIRECT: u8 = 0x06;
+
+// the standard VESA numbers where there is one
+const VBE_MODES: [(u16, u16, u16, u16); 25] = [
+    (0x100, 640, 400, 8), (0x101, 640, 480, 8), (0x103, 800, 600, 8), (0x105, 1024, 768, 8), (0x107, 1280, 1024, 8),
+    (0x111, 640, 480, 16), (0x114, 800, 600, 16), (0x117, 1024, 768, 16), (0x11A, 1280, 1024, 16),
+    (0x112, 640, 480, 24), (0x115, 800, 600, 24), (0x118, 1024, 768, 24), (0x11B, 1280, 1024, 24),
+    (0x140, 640, 480, 32), (0x141, 800, 600, 32), (0x142, 1024, 768, 32), (0x143, 1280, 1024, 32),
+    (0x144, 1280, 720, 8), (0x145, 1280, 720, 16), (0x146, 1280, 720, 24), (0x147, 1280, 720, 32),
+    (0x148, 1920, 1080, 8), (0x149, 1920, 1080, 16), (0x14A, 1920, 1080, 24), (0x14B, 1920, 1080, 32),
+];
+
+fn vbe_mode(number: u16) -> Option<(u16, u16, u16, u16)> {
+    VBE_MODES.iter().copied().find(|&(mode, ..)| mode == number & MODE_NUMBER)
+}
+
+// size and position of red, green, blue and reserved in a direct color pixel
+fn color_fields(bpp: u16) -> [u8; 8] {
+    match bpp {
+        15 => [5, 10, 5, 5, 5, 0, 1, 15],
+        16 => [5, 11, 6, 5, 5, 0, 0, 0],
+        24 => [8, 16, 8, 8, 8, 0, 0, 0],
+        32 => [8, 16, 8, 8, 8, 0, 8, 24],
+        _ => [0; 8]
+    }
+}
+
+impl Cpu {
+    pub fn write_dispi(&mut self, index: u16, value: u16) {
+        self.io.write(DISPI_INDEX, index as u64, Bits::Bit16);
+        self.io.write(DISPI_DATA, value as u64, Bits::Bit16);
+    }
+
+    fn read_dispi(&mut self, index: u16) -> u16 {
+        self.io.write(DISPI_INDEX, index as u64, Bits::Bit16);
+        self.io.read(DISPI_DATA, Bits::Bit16) as u16
+    }
+
+    // INT 10h AH=4Fh, the VBE 3.0 functions a bootloader needs; there is no
+    // banked window, only the linear framebuffer
+    pub fn vbe_service(&mut self) {
+        let al = self.gpr.get_register_value(Register::AL);
+        let status = match al {
+            0x00 => self.controller_info(),
+            0x01 => self.mode_info(),
+            0x02 => self.set_vbe_mode(),
+            0x03 => {
+                let mode = self.current_vbe_mode();
+                self.gpr.set_register_value(Register::BX, mode as usize);
+                VBE_SUCCESS
+            },
+            0x06 => self.scan_line_length(),
+            0x07 => self.display_start(),
+            // the DAC stays 6 bits wide
+            0x08 => {
+                self.gpr.set_register_value(Register::BH, 6);
+                VBE_SUCCESS
+            },
+            0x09 => self.palette_data(),
+            _ => VBE_FAILED
+        };
+        self.gpr.set_register_value(Register::AX, status);
+    }
+
+    fn es_di(&self) -> (u32, usize) {
+        let es = self.gpr.get_register_value(Register::ES) as u32;
+        let di = self.gpr.get_register_value(Register::DI);
+        (es << 16 | di as u32, self.segmentation_to_physical(&Register::ES, di) as usize)
+    }
+
+    // AX=4F00h
+    fn controller_info(&mut self) -> usize {
+        let (pointer, block) = self.es_di();
+        let size = if self.mem.read_many_u8(block, 4) == b"VBE2" { CONTROLLER_INFO_VBE2_SIZE } else { CONTROLLER_INFO_SIZE };
+        for offset in 0..size {
+            self.mem.write_u8(block + offset, 0);
+        }
+        for (i, &byte) in b"VESA".iter().enumerate() {
+            self.mem.write_u8(block + i, byte);
+        }
+        self.mem.write_u16(block + 0x04, VBE_VERSION);
+        self.mem.write_u32(block + 0x0E, pointer + CONTROLLER_INFO_RESERVED as u32);
+        self.mem.write_u16(block + 0x12, (VIDEO_MEMORY >> 16) as u16);
+        self.mem.write_u16(block + 0x14, 0x0100);
+        let mut offset = CONTROLLER_INFO_RESERVED;
+        for (mode, ..) in VBE_MODES {
+            self.mem.write_u16(block + offset, mode);
+            offset += 2;
+        }
+        self.mem.write_u16(block + offset, 0xFFFF);
+        offset += 2;
+        // OEM, vendor, product and revision strings
+        for (field, string) in [0x06, 0x16, 0x1A, 0x1E].into_iter().zip(OEM_STRINGS) {
+            self.mem.write_u32(block + field, pointer + offset as u32);
+            for &byte in string {
+                self.mem.write_u8(block + offset, byte);
+                offset += 1;
+            }
+        }
+        VBE_SUCCESS
+    }
+
+    // AX=4F01h: CX the mode
+    fn mode_info(&mut self) -> usize {
+        let cx = self.gpr.get_register_value(Register::CX) as u16;
+        let Some((_, width, height, bpp)) = vbe_mode(cx) else {
+            return VBE_FAILED;
+        };
+        let (_, block) = self.es_di();
+        for offset in 0..MODE_INFO_SIZE {
+            self.mem.write_u8(block + offset, 0);
+        }
+        let pitch = width * bpp.div_ceil(8);
+        let pages = (VIDEO_MEMORY / (pitch as usize * height as usize) - 1).min(0xFF) as u8;
+        let model = if bpp == 8 { MEMORY_MODEL_PACKED } else { MEMORY_MODEL_DIRECT };
+        self.mem.write_u16(block, MODE_ATTRIBUTES);
+        self.mem.write_u16(block + 0x10, pitch);
+        self.mem.write_u16(block + 0x12, width);
+        self.mem.write_u16(block + 0x14, height);
+        for (offset, value) in [(0x16, 8), (0x17, 16), (0x18, 1), (0x19, bpp as u8), (0x1A, 1), (0x1B, model), (0x1D, pages), (0x1E, 1)] {
+            self.mem.write_u8(block + offset, value);
+        }
+        self.mem.write_u32(block + 0x28, LFB_BASE as u32);
+        self.mem.write_u16(block + 0x32, pitch);
+        self.mem.write_u8(block + 0x34, pages);
+        self.mem.write_u8(block + 0x35, pages);
+        for (i, value) in color_fields(bpp).into_iter().enumerate() {
+            self.mem.write_u8(block + 0x1F + i, value);
+            self.mem.write_u8(block + 0x36 + i, value);
+        }
+        VBE_SUCCESS
+    }
+
+    // AX=4F02h: BX the mode, bit 14 asks for the linear framebuffer and bit
+    // 15 keeps the memory; the VGA modes go through AH=00h
+    fn set_vbe_mode(&mut self) -> usize {
+        let bx = self.gpr.get_register_value(Register::BX) as u16;
+        if bx & MODE_NUMBER < 0x100 {
+            let keep = if bx & MODE_NO_CLEAR != 0 { 0x80 } else { 0 };
+            self.set_video_mode(bx as u8 & 0x7F | keep);
+            return VBE_SUCCESS;
+        }
+        let Some((_, width, height, bpp)) = vbe_mode(bx) else {
+            return VBE_FAILED;
+        };
+        if bx & MODE_LFB == 0 {
+            return VBE_FAILED;
+        }
+        if bpp == 8 {
+            self.load_default_palette();
+        }
+        self.write_dispi(DISPI_ENABLE, 0);
+        self.write_dispi(DISPI_BPP, bpp);
+        self.write_dispi(DISPI_XRES, width);
+        self.write_dispi(DISPI_YRES, height);
+        let keep = if bx & MODE_NO_CLEAR != 0 { DISPI_NOCLEARMEM } else { 0 };
+        self.write_dispi(DISPI_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED | keep);
+        VBE_SUCCESS
+    }
+
+    // the VBE mode the DISPI registers describe, or the VGA mode
+    fn current_vbe_mode(&mut self) -> u16 {
+        if self.read_dispi(DISPI_ENABLE) & DISPI_ENABLED == 0 {
+            return self.mem.read_u8(BDA_VIDEO_MODE) as u16;
+        }
+        let current = (self.read_dispi(DISPI_XRES), self.read_dispi(DISPI_YRES), self.read_dispi(DISPI_BPP));
+        VBE_MODES.iter()
+            .find(|&&(_, width, height, bpp)| (width, height, bpp) == current)
+            .map_or(0, |&(mode, ..)| mode | MODE_LFB)
+    }
+
+    // AX=4F06h: BL=00h sets the width in pixels, 01h gets it, 02h sets it
+    // in bytes and 03h gets the maximum
+    fn scan_line_length(&mut self) -> usize {
+        if self.read_dispi(DISPI_ENABLE) & DISPI_ENABLED == 0 {
+            return VBE_INVALID_IN_MODE;
+        }
+        let bl = self.gpr.get_register_value(Register::BL);
+        let cx = self.gpr.get_register_value(Register::CX) as u16;
+        let bytes = self.read_dispi(DISPI_BPP).div_ceil(8);
+        match bl {
+            // the pitch comes back in BX, so it has to fit in 16 bits
+            0x00 if cx as usize * bytes as usize > u16::MAX as usize => return VBE_FAILED,
+            0x00 => self.write_dispi(DISPI_VIRT_WIDTH, cx),
+            0x02 => self.write_dispi(DISPI_VIRT_WIDTH, cx / bytes),
+            0x01 => {},
+            0x03 => {
+                let height = self.read_dispi(DISPI_YRES) as usize;
+                let pixels = (VIDEO_MEMORY / height / bytes as usize).min(u16::MAX as usize / bytes as usize);
+                self.gpr.set_register_value(Register::BX, pixels * bytes as usize);
+                self.gpr.set_register_value(Register::CX, pixels);
+                return VBE_SUCCESS;
+            },
+            _ => return VBE_FAILED
+        }
+        let width = self.read_dispi(DISPI_VIRT_WIDTH);
+        let lines = self.read_dispi(DISPI_VIRT_HEIGHT);
+        self.gpr.set_register_value(Register::BX, width as usize * bytes as usize);
+        self.gpr.set_register_value(Register::CX, width as usize);
+        self.gpr.set_register_value(Register::DX, lines as usize);
+        VBE_SUCCESS
+    }
+
+    // AX=4F07h: BL=00h/80h sets the first pixel shown from CX and DX, 01h
+    // gets it, 02h/82h sets it from a byte address in ECX; flips happen at
+    // once so 04h always reports them done
+    fn display_start(&mut self) -> usize {
+        if self.read_dispi(DISPI_ENABLE) & DISPI_ENABLED == 0 {
+            return VBE_INVALID_IN_MODE;
+        }
+        let bl = self.gpr.get_register_value(Register::BL);
+        match bl {
+            0x00 | 0x80 => {
+                let cx = self.gpr.get_register_value(Register::CX) as u16;
+                let dx = self.gpr.get_register_value(Register::DX) as u16;
+                self.write_dispi(DISPI_X_OFFSET, cx);
+                self.write_dispi(DISPI_Y_OFFSET, dx);
+            },
+            0x01 => {
+                let x = self.read_dispi(DISPI_X_OFFSET);
+                let y = self.read_dispi(DISPI_Y_OFFSET);
+                self.gpr.set_register_value(Register::BH, 0);
+                self.gpr.set_register_value(Register::CX, x as usize);
+                self.gpr.set_register_value(Register::DX, y as usize);
+            },
+            0x02 | 0x82 => {
+                let address = self.gpr.get_register_value(Register::ECX) as usize;
+                let bytes = self.read_dispi(DISPI_BPP).div_ceil(8) as usize;
+                let pitch = self.read_dispi(DISPI_VIRT_WIDTH) as usize * bytes;
+                self.write_dispi(DISPI_X_OFFSET, (address % pitch / bytes) as u16);
+                self.write_dispi(DISPI_Y_OFFSET, (address / pitch) as u16);
+            },
+            0x04 => self.gpr.set_register_value(Register::CX, 1),
+            _ => return VBE_FAILED
+        }
+        VBE_SUCCESS
+    }
+
+    // AX=4F09h: BL=00h/80h loads CX entries from DX out of ES:DI, 01h reads
+    // them back; each entry is blue, green, red and a pad byte
+    fn palette_data(&mut self) -> usize {
+        let bl = self.gpr.get_register_value(Register::BL);
+        let count = self.gpr.get_register_value(Register::CX) as usize;
+        let first = self.gpr.get_register_value(Register::DX);
+        let (_, table) = self.es_di();
+        match bl {
+            0x00 | 0x80 => {
+                self.io.write(DAC_WRITE_INDEX, first, Bits::Bit8);
+                for entry in 0..count {
+                    for component in [2, 1, 0] {
+                        let value = self.mem.read_u8(table + entry * 4 + component);
+                        self.io.write(DAC_DATA, value as u64, Bits::Bit8);
+                    }
+                }
+            },
+            0x01 => {
+                self.io.write(DAC_READ_INDEX, first, Bits::Bit8);
+                for entry in 0..count {
+                    for component in [2, 1, 0] {
+                        let value = self.io.read(DAC_DATA, Bits::Bit8);
+                        self.mem.write_u8(table + entry * 4 + component, value as u8);
+                    }
+                    self.mem.write_u8(table + entry * 4 + 3, 0);
+                }
+            },
+            _ => return VBE_FAILED
+        }
+        VBE_SUCCESS
+    }
+}
@@ -0,0 +1,754 @@
+use iced_x86::Register;
+use crate::ast::Bits;
+use crate::vm::cpu::bios::BIOS_SEGMENT;
+use crate::vm::cpu::Cpu;
+use crate::vm::font::{FONT_8X14, FONT_8X16, FONT_8X8, GLYPHS};
+use crate::vm::vbe::DISPI_ENABLE;
+use crate::vm::vga::{ATTRIBUTE_INDEX, ATTRIBUTE_READ, CRTC_INDEX, DAC_DATA, DAC_ENTRIES, DAC_READ_INDEX, DAC_WRITE_INDEX, FONT_BLOCKS, GRAPHICS_INDEX, INPUT_STATUS_1, MISC_OUTPUT_WRITE, PEL_MASK, SEQUENCER_INDEX};
+
+// video state: mode, columns, page size and start, cursor of each page,
+// cursor shape, active page and CRTC port
+pub const BDA_VIDEO_MODE: usize = 0x449;
+const BDA_COLUMNS: usize = 0x44A;
+const BDA_PAGE_SIZE: usize = 0x44C;
+const BDA_PAGE_START: usize = 0x44E;
+const BDA_CURSOR: usize = 0x450;
+const PAGES: usize = 8;
+const BDA_CURSOR_SHAPE: usize = 0x460;
+const BDA_ACTIVE_PAGE: usize = 0x462;
+const BDA_CRTC_PORT: usize = 0x463;
+// rows minus one and the character height of the loaded font
+const BDA_ROWS: usize = 0x484;
+const BDA_CHARACTER_HEIGHT: usize = 0x485;
+const TEXT_BASE: usize = 0xB8000;
+const GRAPHICS_BASE: usize = 0xA0000;
+// CGA modes keep odd lines 8K above the even ones
+const CGA_ODD_LINES: usize = 0x2000;
+// a space, light grey on black
+const BLANK: u16 = 0x0720;
+
+// the ROM fonts; INT 43h points at the graphics font and INT 1Fh at the
+// upper half of the 8x8 glyphs
+pub const FONT_8X16_OFFSET: u16 = 0xA000;
+pub const FONT_8X14_OFFSET: u16 = 0xB000;
+pub const FONT_8X8_OFFSET: u16 = 0xC000;
+pub const FONT_8X8_UPPER_OFFSET: u16 = FONT_8X8_OFFSET + (GLYPHS / 2 * 8) as u16;
+
+// with PAS set again the attribute controller goes back to the display
+const PALETTE_ADDRESS_SOURCE: u64 = 0x20;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Layout {
+    Text,
+    // 1 or 2 bits per pixel packed in bytes, even and odd lines in two banks
+    Cga(usize),
+    Planar,
+    Chained,
+}
+
+// what the BIOS loads into the DAC on a mode set
+#[derive(Clone, Copy)]
+enum Palette {
+    // the 16 CGA colors, intensity in bit 4
+    Cga,
+    // the 64 EGA colors, rgbRGB
+    Ega,
+    // the default 256 color palette
+    Vga,
+}
+
+struct VideoMode {
+    number: u8,
+    layout: Layout,
+    // characters across and down, and the resolution in pixels
+    columns: u8,
+    rows: u8,
+    character_height: u8,
+    width: u16,
+    height: u16,
+    palette: Palette,
+    misc: u8,
+    // sequencer 1-4, CRTC 0-18h, attribute 0-13h, graphics 0-8
+    sequencer: [u8; 4],
+    crtc: [u8; 0x19],
+    attribute: [u8; 0x14],
+    graphics: [u8; 9],
+}
+
+const TEXT_ATTRIBUTES: [u8; 0x14] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07, 0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F,
+    0x0C, 0x00, 0x0F, 0x08,
+];
+const TEXT_GRAPHICS: [u8; 9] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x0E, 0x0F, 0xFF];
+const EGA_ATTRIBUTES: [u8; 0x14] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07, 0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F,
+    0x01, 0x00, 0x0F, 0x00,
+];
+const CGA_ATTRIBUTES: [u8; 0x14] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17,
+    0x01, 0x00, 0x0F, 0x00,
+];
+const PLANAR_GRAPHICS: [u8; 9] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x0F, 0xFF];
+const CRTC_480_LINES: [u8; 0x19] = [
+    0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0x0B, 0x3E, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xEA, 0x8C, 0xDF, 0x28, 0x00, 0xE7, 0x04, 0xE3, 0xFF,
+];
+
+// the register sets of the IBM VGA BIOS; 00h, 02h and 05h are the same
+// as 01h, 03h and 04h without the color burst
+const VIDEO_MODES: [VideoMode; 10] = [
+    VideoMode {
+        number: 0x01, layout: Layout::Text, columns: 40, rows: 25, character_height: 16, width: 320, height: 400,
+        palette: Palette::Ega, misc: 0x67, sequencer: [0x08, 0x03, 0x00, 0x02],
+        crtc: [
+            0x2D, 0x27, 0x28, 0x90, 0x2B, 0xA0, 0xBF, 0x1F, 0x00, 0x4F, 0x0D, 0x0E, 0x00, 0x00, 0x00, 0x00,
+            0x9C, 0x8E, 0x8F, 0x14, 0x1F, 0x96, 0xB9, 0xA3, 0xFF,
+        ],
+        attribute: TEXT_ATTRIBUTES, graphics: TEXT_GRAPHICS,
+    },
+    VideoMode {
+        number: 0x03, layout: Layout::Text, columns: 80, rows: 25, character_height: 16, width: 640, height: 400,
+        palette: Palette::Ega, misc: 0x67, sequencer: [0x00, 0x03, 0x00, 0x02],
+        crtc: [
+            0x5F, 0x4F, 0x50, 0x82, 0x55, 0x81, 0xBF, 0x1F, 0x00, 0x4F, 0x0D, 0x0E, 0x00, 0x00, 0x00, 0x00,
+            0x9C, 0x8E, 0x8F, 0x28, 0x1F, 0x96, 0xB9, 0xA3, 0xFF,
+        ],
+        attribute: TEXT_ATTRIBUTES, graphics: TEXT_GRAPHICS,
+    },
+    VideoMode {
+        number: 0x04, layout: Layout::Cga(2), columns: 40, rows: 25, character_height: 8, width: 320, height: 200,
+        palette: Palette::Cga, misc: 0x63, sequencer: [0x09, 0x03, 0x00, 0x02],
+        crtc: [
+            0x2D, 0x27, 0x28, 0x90, 0x2B, 0x80, 0xBF, 0x1F, 0x00, 0xC1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x9C, 0x8E, 0x8F, 0x14, 0x00, 0x96, 0xB9, 0xA2, 0xFF,
+        ],
+        attribute: [
+            0x00, 0x13, 0x15, 0x17, 0x02, 0x04, 0x06, 0x07, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17,
+            0x01, 0x00, 0x03, 0x00,
+        ],
+        graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x0F, 0x0F, 0xFF],
+    },
+    VideoMode {
+        number: 0x06, layout: Layout::Cga(1), columns: 80, rows: 25, character_height: 8, width: 640, height: 200,
+        palette: Palette::Cga, misc: 0x63, sequencer: [0x01, 0x01, 0x00, 0x06],
+        crtc: [
+            0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F, 0x00, 0xC1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x9C, 0x8E, 0x8F, 0x28, 0x00, 0x96, 0xB9, 0xC2, 0xFF,
+        ],
+        attribute: [
+            0x00, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17, 0x17,
+            0x01, 0x00, 0x01, 0x00,
+        ],
+        graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0D, 0x0F, 0xFF],
+    },
+    VideoMode {
+        number: 0x0D, layout: Layout::Planar, columns: 40, rows: 25, character_height: 8, width: 320, height: 200,
+        palette: Palette::Cga, misc: 0x63, sequencer: [0x09, 0x0F, 0x00, 0x06],
+        crtc: [
+            0x2D, 0x27, 0x28, 0x90, 0x2B, 0x80, 0xBF, 0x1F, 0x00, 0xC0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x9C, 0x8E, 0x8F, 0x14, 0x00, 0x96, 0xB9, 0xE3, 0xFF,
+        ],
+        attribute: CGA_ATTRIBUTES, graphics: PLANAR_GRAPHICS,
+    },
+    VideoMode {
+        number: 0x0E, layout: Layout::Planar, columns: 80, rows: 25, character_height: 8, width: 640, height: 200,
+        palette: Palette::Cga, misc: 0x63, sequencer: [0x01, 0x0F, 0x00, 0x06],
+        crtc: [
+            0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F, 0x00, 0xC0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x9C, 0x8E, 0x8F, 0x28, 0x00, 0x96, 0xB9, 0xE3, 0xFF,
+        ],
+        attribute: CGA_ATTRIBUTES, graphics: PLANAR_GRAPHICS,
+    },
+    VideoMode {
+        number: 0x10, layout: Layout::Planar, columns: 80, rows: 25, character_height: 14, width: 640, height: 350,
+        palette: Palette::Ega, misc: 0xA3, sequencer: [0x01, 0x0F, 0x00, 0x06],
+        crtc: [
+            0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x83, 0x85, 0x5D, 0x28, 0x0F, 0x63, 0xBA, 0xE3, 0xFF,
+        ],
+        attribute: EGA_ATTRIBUTES, graphics: PLANAR_GRAPHICS,
+    },
+    VideoMode {
+        number: 0x11, layout: Layout::Planar, columns: 80, rows: 30, character_height: 16, width: 640, height: 480,
+        palette: Palette::Ega, misc: 0xE3, sequencer: [0x01, 0x0F, 0x00, 0x06],
+        crtc: CRTC_480_LINES,
+        attribute: [
+            0x00, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F,
+            0x01, 0x00, 0x0F, 0x00,
+        ],
+        graphics: PLANAR_GRAPHICS,
+    },
+    VideoMode {
+        number: 0x12, layout: Layout::Planar, columns: 80, rows: 30, character_height: 16, width: 640, height: 480,
+        palette: Palette::Ega, misc: 0xE3, sequencer: [0x01, 0x0F, 0x00, 0x06],
+        crtc: CRTC_480_LINES,
+        attribute: EGA_ATTRIBUTES, graphics: PLANAR_GRAPHICS,
+    },
+    VideoMode {
+        number: 0x13, layout: Layout::Chained, columns: 40, rows: 25, character_height: 8, width: 320, height: 200,
+        palette: Palette::Vga, misc: 0x63, sequencer: [0x01, 0x0F, 0x00, 0x0E],
+        crtc: [
+            0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x9C, 0x8E, 0x8F, 0x28, 0x40, 0x96, 0xB9, 0xA3, 0xFF,
+        ],
+        attribute: [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+            0x41, 0x00, 0x0F, 0x00,
+        ],
+        graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0F, 0xFF],
+    },
+];
+
+// the default 256 color palette: grey ramp, then a hue wheel of 24 colors
+// for each of three intensities at three saturations
+const GREYS: [u8; 16] = [0x00, 0x05, 0x08, 0x0B, 0x0E, 0x11, 0x14, 0x18, 0x1C, 0x20, 0x24, 0x28, 0x2D, 0x32, 0x38, 0x3F];
+const HUE_LEVELS: [[u8; 5]; 9] = [
+    [0x00, 0x10, 0x1F, 0x2F, 0x3F], [0x1F, 0x27, 0x2F, 0x37, 0x3F], [0x2D, 0x31, 0x36, 0x3A, 0x3F],
+    [0x00, 0x07, 0x0E, 0x15, 0x1C], [0x0E, 0x11, 0x15, 0x18, 0x1C], [0x14, 0x16, 0x18, 0x1A, 0x1C],
+    [0x00, 0x04, 0x08, 0x0C, 0x10], [0x08, 0x0A, 0x0C, 0x0E, 0x10], [0x0B, 0x0C, 0x0D, 0x0F, 0x10],
+];
+
+fn cga_color(index: usize, intensity: usize) -> [u8; 3] {
+    let component = |bit: usize| ((index >> bit) & 1) as u8 * 0x2A + intensity as u8 * 0x15;
+    // dark yellow is brown
+    let green = if index & 7 == 6 && intensity == 0 { 0x15 } else { component(1) };
+    [component(2), green, component(0)]
+}
+
+fn dac_entry(palette: Palette, index: usize) -> [u8; 3] {
+    match palette {
+        Palette::Cga if index < 64 => cga_color(index, (index >> 4) & 1),
+        Palette::Ega if index < 64 => {
+            let component = |high: usize, low: usize| ((index >> high) & 1) as u8 * 0x2A + ((index >> low) & 1) as u8 * 0x15;
+            [component(2, 5), component(1, 4), component(0, 3)]
+        },
+        Palette::Vga if index < 16 => cga_color(index, (index >> 3) & 1),
+        Palette::Vga if index < 32 => [GREYS[index - 16]; 3],
+        Palette::Vga if index < 248 => {
+            let levels = HUE_LEVELS[(index - 32) / 24];
+            let (r, g, b) = match (index - 32) % 24 {
+                hue @ 0..=4 => (hue, 0, 4),
+                hue @ 5..=8 => (4, 0, 8 - hue),
+                hue @ 9..=12 => (4, hue - 8, 0),
+                hue @ 13..=16 => (16 - hue, 4, 0),
+                hue @ 17..=20 => (0, 4, hue - 16),
+                hue => (0, 24 - hue, 4)
+            };
+            [levels[r], levels[g], levels[b]]
+        },
+        _ => [0; 3]
+    }
+}
+
+impl Cpu {
+    pub fn video_service(&mut self) {
+        let ah = self.gpr.get_register_value(Register::AH);
+        match ah {
+            0x00 => {
+                let al = self.gpr.get_register_value(Register::AL);
+                self.set_video_mode(al as u8);
+            },
+            0x01 => {
+                let shape = self.gpr.get_register_value(Register::CX) as u16;
+                self.mem.write_u16(BDA_CURSOR_SHAPE, shape);
+                self.write_indexed(CRTC_INDEX, 0x0A, (shape >> 8) as u8);
+                self.write_indexed(CRTC_INDEX, 0x0B, shape as u8);
+            },
+            0x02 => {
+                let position = self.gpr.get_register_value(Register::DX) as u16;
+                self.set_cursor(position);
+            },
+            0x03 => {
+                let position = self.mem.read_u16(BDA_CURSOR);
+                let shape = self.mem.read_u16(BDA_CURSOR_SHAPE);
+                self.gpr.set_register_value(Register::DX, position as usize);
+                self.gpr.set_register_value(Register::CX, shape as usize);
+            },
+            0x0B => self.cga_palette(),
+            0x0C => {
+                let x = self.gpr.get_register_value(Register::CX) as usize;
+                let y = self.gpr.get_register_value(Register::DX) as usize;
+                let color = self.gpr.get_register_value(Register::AL) as u8;
+                self.write_pixel(x, y, color);
+            },
+            0x0D => {
+                let x = self.gpr.get_register_value(Register::CX) as usize;
+                let y = self.gpr.get_register_value(Register::DX) as usize;
+                let color = self.read_pixel(x, y);
+                self.gpr.set_register_value(Register::AL, color as usize);
+            },
+            0x0E => {
+                let al = self.gpr.get_register_value(Register::AL);
+                let bl = self.gpr.get_register_value(Register::BL);
+                self.teletype(al as u8, bl as u8);
+            },
+            0x0F => {
+                let mode = self.mem.read_u8(BDA_VIDEO_MODE);
+                let columns = self.mem.read_u16(BDA_COLUMNS);
+                let page = self.mem.read_u8(BDA_ACTIVE_PAGE);
+                self.gpr.set_register_value(Register::AL, mode as usize);
+                self.gpr.set_register_value(Register::AH, columns as usize);
+                self.gpr.set_register_value(Register::BH, page as usize);
+            },
+            0x10 => self.palette_service(),
+            0x11 => self.character_generator(),
+            0x4F => self.vbe_service(),
+            _ => {}
+        }
+    }
+
+    fn video_mode(number: u8) -> Option<&'static VideoMode> {
+        let number = match number {
+            0x00 => 0x01,
+            0x02 => 0x03,
+            0x05 => 0x04,
+            number => number
+        };
+        VIDEO_MODES.iter().find(|mode| mode.number == number)
+    }
+
+    fn current_video_mode(&self) -> Option<&'static VideoMode> {
+        Self::video_mode(self.mem.read_u8(BDA_VIDEO_MODE))
+    }
+
+    fn write_indexed(&mut self, port: u16, index: u8, value: u8) {
+        self.io.write(port, index as u64, Bits::Bit8);
+        self.io.write(port + 1, value as u64, Bits::Bit8);
+    }
+
+    // reading input status 1 puts 0x3C0 back to expecting an index
+    fn write_attribute(&mut self, index: u8, value: u8) {
+        self.io.read(INPUT_STATUS_1, Bits::Bit8);
+        self.io.write(ATTRIBUTE_INDEX, index as u64, Bits::Bit8);
+        self.io.write(ATTRIBUTE_INDEX, value as u64, Bits::Bit8);
+        self.io.write(ATTRIBUTE_INDEX, PALETTE_ADDRESS_SOURCE, Bits::Bit8);
+    }
+
+    fn read_attribute(&mut self, index: u8) -> u8 {
+        self.io.read(INPUT_STATUS_1, Bits::Bit8);
+        self.io.write(ATTRIBUTE_INDEX, index as u64, Bits::Bit8);
+        let value = self.io.read(ATTRIBUTE_READ, Bits::Bit8) as u8;
+        self.io.read(INPUT_STATUS_1, Bits::Bit8);
+        self.io.write(ATTRIBUTE_INDEX, PALETTE_ADDRESS_SOURCE, Bits::Bit8);
+        value
+    }
+
+    // INT 10h AH=00h: program every register of the mode, load its DAC
+    // palette and font and, unless AL bit 7 is set, clear the screen
+    pub fn set_video_mode(&mut self, al: u8) {
+        let number = al & 0x7F;
+        let Some(mode) = Self::video_mode(number) else {
+            return;
+        };
+        // back from a VBE mode
+        self.write_dispi(DISPI_ENABLE, 0);
+        self.io.write(MISC_OUTPUT_WRITE, mode.misc as u64, Bits::Bit8);
+        // the sequencer is held in reset while it is reprogrammed
+        self.write_indexed(SEQUENCER_INDEX, 0x00, 0x01);
+        for (index, &value) in mode.sequencer.iter().enumerate() {
+            self.write_indexed(SEQUENCER_INDEX, index as u8 + 1, value);
+        }
+        self.write_indexed(SEQUENCER_INDEX, 0x00, 0x03);
+        // unprotect CRTC registers 0-7 first
+        self.write_indexed(CRTC_INDEX, 0x11, 0x00);
+        for (index, &value) in mode.crtc.iter().enumerate() {
+            self.write_indexed(CRTC_INDEX, index as u8, value);
+        }
+        for (index, &value) in mode.graphics.iter().enumerate() {
+            self.write_indexed(GRAPHICS_INDEX, index as u8, value);
+        }
+        for (index, &value) in mode.attribute.iter().enumerate() {
+            self.write_attribute(index as u8, value);
+        }
+        self.write_attribute(0x14, 0x00);
+        self.load_dac(mode.palette);
+
+        if al & 0x80 == 0 {
+            match mode.layout {
+                Layout::Text => for cell in 0..0x4000 {
+                    self.mem.write_u16(TEXT_BASE + cell * 2, BLANK);
+                },
+                Layout::Cga(_) => for offset in 0..0x8000 {
+                    self.mem.write_u8(TEXT_BASE + offset, 0);
+                },
+                Layout::Planar | Layout::Chained => for offset in 0..0x10000 {
+                    self.mem.write_u8(GRAPHICS_BASE + offset, 0);
+                }
+            }
+        }
+        let (font_offset, font) = match mode.character_height {
+            8 => (FONT_8X8_OFFSET, &FONT_8X8[..]),
+            14 => (FONT_8X14_OFFSET, &FONT_8X14[..]),
+            _ => (FONT_8X16_OFFSET, &FONT_8X16[..])
+        };
+        if mode.layout == Layout::Text {
+            self.vga.borrow_mut().load_font(0, 0, mode.character_height as usize, font);
+        } else {
+            self.mem.write_u16(0x43 * 4, font_offset);
+            self.mem.write_u16(0x43 * 4 + 2, BIOS_SEGMENT);
+        }
+
+        let page_size = match mode.layout {
+            Layout::Text => (mode.columns as u16 * mode.rows as u16 * 2).next_multiple_of(0x800),
+            Layout::Cga(_) => 0x4000,
+            Layout::Planar => mode.width / 8 * mode.height,
+            Layout::Chained => mode.width * mode.height
+        };
+        self.mem.write_u8(BDA_VIDEO_MODE, number);
+        self.mem.write_u16(BDA_COLUMNS, mode.columns as u16);
+        self.mem.write_u16(BDA_PAGE_SIZE, page_size);
+        self.mem.write_u16(BDA_PAGE_START, 0);
+        for page in 0..PAGES {
+            self.mem.write_u16(BDA_CURSOR + page * 2, 0);
+        }
+        self.mem.write_u16(BDA_CURSOR_SHAPE, (mode.crtc[0x0A] as u16) << 8 | mode.crtc[0x0B] as u16);
+        self.mem.write_u8(BDA_ACTIVE_PAGE, 0);
+        self.mem.write_u16(BDA_CRTC_PORT, CRTC_INDEX);
+        self.mem.write_u8(BDA_ROWS, mode.rows - 1);
+        self.mem.write_u16(BDA_CHARACTER_HEIGHT, mode.character_height as u16);
+        self.set_cursor(0);
+    }
+
+    fn load_dac(&mut self, palette: Palette) {
+        self.io.write(PEL_MASK, 0xFF, Bits::Bit8);
+        self.io.write(DAC_WRITE_INDEX, 0, Bits::Bit8);
+        for index in 0..DAC_ENTRIES {
+            for component in dac_entry(palette, index) {
+                self.io.write(DAC_DATA, component as u64, Bits::Bit8);
+            }
+        }
+    }
+
+    // the VBE 8 bpp modes start out with the mode 13h colors
+    pub fn load_default_palette(&mut self) {
+        self.load_dac(Palette::Vga);
+    }
+
+    // INT 10h AH=0Bh: BH=0 sets the background and border, BH=1 picks
+    // one of the two CGA palettes of the 4 color modes
+    fn cga_palette(&mut self) {
+        let bh = self.gpr.get_register_value(Register::BH);
+        let bl = self.gpr.get_register_value(Register::BL) as u8;
+        match bh {
+            0x00 => {
+                let color = bl & 0x07 | (bl & 0x08) << 1;
+                self.write_attribute(0x00, color);
+                self.write_attribute(0x11, color);
+            },
+            0x01 => {
+                let colors = if bl & 1 == 0 { [0x12, 0x14, 0x16] } else { [0x13, 0x15, 0x17] };
+                for (index, color) in colors.into_iter().enumerate() {
+                    self.write_attribute(index as u8 + 1, color);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // INT 10h AH=10h: the attribute palette and the DAC
+    fn palette_service(&mut self) {
+        let al = self.gpr.get_register_value(Register::AL);
+        let bl = self.gpr.get_register_value(Register::BL) as u8;
+        let bh = self.gpr.get_register_value(Register::BH) as u8;
+        let bx = self.gpr.get_register_value(Register::BX);
+        let table = self.segmentation_to_physical(&Register::ES, self.gpr.get_register_value(Register::DX)) as usize;
+        match al {
+            0x00 => self.write_attribute(bl, bh),
+            0x01 => self.write_attribute(0x11, bh),
+            // 16 palette registers and the border
+            0x02 => for index in 0..0x11 {
+                let value = self.mem.read_u8(table + index);
+                self.write_attribute(if index == 0x10 { 0x11 } else { index as u8 }, value);
+            },
+            // BL=0 turns bit 7 of the attribute into background intensity
+            0x03 => {
+                let mode = self.read_attribute(0x10);
+                self.write_attribute(0x10, if bl == 0 { mode & !0x08 } else { mode | 0x08 });
+            },
+            0x07 => {
+                let value = self.read_attribute(bl);
+                self.gpr.set_register_value(Register::BH, value as usize);
+            },
+            0x08 => {
+                let value = self.read_attribute(0x11);
+                self.gpr.set_register_value(Register::BH, value as usize);
+            },
+            0x10 => {
+                self.io.write(DAC_WRITE_INDEX, bx, Bits::Bit8);
+                for register in [Register::DH, Register::CH, Register::CL] {
+                    let value = self.gpr.get_register_value(register);
+                    self.io.write(DAC_DATA, value, Bits::Bit8);
+                }
+            },
+            0x12 => {
+                let count = self.gpr.get_register_value(Register::CX) as usize;
+                self.io.write(DAC_WRITE_INDEX, bx, Bits::Bit8);
+                for offset in 0..count * 3 {
+                    let value = self.mem.read_u8(table + offset);
+                    self.io.write(DAC_DATA, value as u64, Bits::Bit8);
+                }
+            },
+            0x15 => {
+                self.io.write(DAC_READ_INDEX, bx, Bits::Bit8);
+                for register in [Register::DH, Register::CH, Register::CL] {
+                    let value = self.io.read(DAC_DATA, Bits::Bit8);
+                    self.gpr.set_register_value(register, value as usize);
+                }
+            },
+            0x17 => {
+                let count = self.gpr.get_register_value(Register::CX) as usize;
+                self.io.write(DAC_READ_INDEX, bx, Bits::Bit8);
+                for offset in 0..count * 3 {
+                    let value = self.io.read(DAC_DATA, Bits::Bit8);
+                    self.mem.write_u8(table + offset, value as u8);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // the byte of a packed CGA pixel, its shift and its mask
+    fn cga_pixel(mode: &VideoMode, bits: usize, x: usize, y: usize) -> (usize, usize, u8) {
+        let per_byte = 8 / bits;
+        let line = mode.width as usize * bits / 8;
+        let addr = TEXT_BASE + (y & 1) * CGA_ODD_LINES + (y >> 1) * line + x / per_byte;
+        ((addr), (per_byte - 1 - x % per_byte) * bits, ((1 << bits) - 1) as u8)
+    }
+
+    // INT 10h AH=0Ch: bit 7 of the color XORs it into the screen, except
+    // in the 256 color mode
+    pub fn write_pixel(&mut self, x: usize, y: usize, color: u8) {
+        let Some(mode) = self.current_video_mode() else {
+            return;
+        };
+        if x >= mode.width as usize || y >= mode.height as usize {
+            return;
+        }
+        match mode.layout {
+            Layout::Text => {},
+            Layout::Cga(bits) => {
+                let (addr, shift, mask) = Self::cga_pixel(mode, bits, x, y);
+                let byte = self.mem.read_u8(addr);
+                let value = (color & mask) << shift;
+                let byte = if color & 0x80 != 0 { byte ^ value } else { byte & !(mask << shift) | value };
+                self.mem.write_u8(addr, byte);
+            },
+            // set/reset supplies the color, the bit mask picks the pixel and
+            // the function select does the XOR against the latches
+            Layout::Planar => {
+                let addr = GRAPHICS_BASE + y * (mode.width as usize / 8) + x / 8;
+                self.write_indexed(GRAPHICS_INDEX, 0x00, color & 0x0F);
+                self.write_indexed(GRAPHICS_INDEX, 0x01, 0x0F);
+                self.write_indexed(GRAPHICS_INDEX, 0x03, if color & 0x80 != 0 { 0x18 } else { 0x00 });
+                self.write_indexed(GRAPHICS_INDEX, 0x08, 0x80 >> (x % 8));
+                self.mem.read_u8(addr);
+                self.mem.write_u8(addr, 0xFF);
+                self.write_indexed(GRAPHICS_INDEX, 0x01, 0x00);
+                self.write_indexed(GRAPHICS_INDEX, 0x03, 0x00);
+                self.write_indexed(GRAPHICS_INDEX, 0x08, 0xFF);
+            },
+            Layout::Chained => self.mem.write_u8(GRAPHICS_BASE + y * mode.width as usize + x, color)
+        }
+    }
+
+    // INT 10h AH=0Dh
+    pub fn read_pixel(&mut self, x: usize, y: usize) -> u8 {
+        let Some(mode) = self.current_video_mode() else {
+            return 0;
+        };
+        if x >= mode.width as usize || y >= mode.height as usize {
+            return 0;
+        }
+        match mode.layout {
+            Layout::Text => 0,
+            Layout::Cga(bits) => {
+                let (addr, shift, mask) = Self::cga_pixel(mode, bits, x, y);
+                (self.mem.read_u8(addr) >> shift) & mask
+            },
+            // one plane at a time through the read map select
+            Layout::Planar => {
+                let addr = GRAPHICS_BASE + y * (mode.width as usize / 8) + x / 8;
+                let mut color = 0;
+                for plane in 0..4 {
+                    self.write_indexed(GRAPHICS_INDEX, 0x04, plane);
+                    let byte = self.mem.read_u8(addr);
+                    color |= ((byte >> (7 - x % 8)) & 1) << plane;
+                }
+                self.write_indexed(GRAPHICS_INDEX, 0x04, 0x00);
+                color
+            },
+            Layout::Chained => self.mem.read_u8(GRAPHICS_BASE + y * mode.width as usize + x)
+        }
+    }
+
+    // INT 10h AH=11h: fonts go to plane 2, the 1xh forms also resize the
+    // screen to the new character height
+    fn character_generator(&mut self) {
+        let al = self.gpr.get_register_value(Register::AL) as u8;
+        let block = self.gpr.get_register_value(Register::BL) as usize % FONT_BLOCKS;
+        let (glyphs, first, height) = match al {
+            0x00 | 0x10 => {
+                let height = self.gpr.get_register_value(Register::BH) as usize;
+                let count = self.gpr.get_register_value(Register::CX) as usize;
+                let first = self.gpr.get_register_value(Register::DX) as usize;
+                let table = self.segmentation_to_physical(&Register::ES, self.gpr.get_register_value(Register::BP));
+                (self.mem.read_many_u8(table as usize, count * height), first, height)
+            },
+            0x01 | 0x11 => (FONT_8X14.to_vec(), 0, 14),
+            0x02 | 0x12 => (FONT_8X8.to_vec(), 0, 8),
+            0x03 => {
+                let select = self.gpr.get_register_value(Register::BL);
+                self.io.write(SEQUENCER_INDEX, select << 8 | 0x03, Bits::Bit16);
+                return;
+            },
+            0x04 | 0x14 => (FONT_8X16.to_vec(), 0, 16),
+            0x30 => return self.font_information(),
+            _ => return
+        };
+        if height == 0 {
+            return;
+        }
+        self.vga.borrow_mut().load_font(block, first, height, &glyphs);
+        if al & 0x10 != 0 {
+            let rows = 400 / height;
+            let height = height as u8;
+            self.write_indexed(CRTC_INDEX, 0x09, height - 1);
+            self.write_indexed(CRTC_INDEX, 0x0A, height.saturating_sub(3));
+            self.write_indexed(CRTC_INDEX, 0x0B, height.saturating_sub(2));
+            self.mem.write_u16(BDA_CURSOR_SHAPE, (height.saturating_sub(3) as u16) << 8 | height.saturating_sub(2) as u16);
+            self.mem.write_u8(BDA_ROWS, rows as u8 - 1);
+            self.mem.write_u16(BDA_CHARACTER_HEIGHT, height as u16);
+        }
+    }
+
+    // AX=1130h: ES:BP to the font BH asks for, CX the height, DL the last row
+    fn font_information(&mut self) {
+        let pointer = match self.gpr.get_register_value(Register::BH) {
+            0x00 => self.mem.read_u32(0x1F * 4),
+            0x01 => self.mem.read_u32(0x43 * 4),
+            0x02 => (BIOS_SEGMENT as u32) << 16 | FONT_8X14_OFFSET as u32,
+            0x03 => (BIOS_SEGMENT as u32) << 16 | FONT_8X8_OFFSET as u32,
+            0x04 => (BIOS_SEGMENT as u32) << 16 | FONT_8X8_UPPER_OFFSET as u32,
+            0x06 => (BIOS_SEGMENT as u32) << 16 | FONT_8X16_OFFSET as u32,
+            _ => return
+        };
+        self.load_segment(Register::ES, (pointer >> 16) as u16).unwrap();
+        self.gpr.set_register_value(Register::BP, (pointer & 0xFFFF) as usize);
+        self.gpr.set_register_value(Register::CX, self.mem.read_u16(BDA_CHARACTER_HEIGHT) as usize);
+        self.gpr.set_register_value(Register::DL, self.mem.read_u8(BDA_ROWS) as usize);
+    }
+
+    // row in the high byte, column in the low byte
+    fn set_cursor(&mut self, position: u16) {
+        self.mem.write_u16(BDA_CURSOR, position);
+        let columns = self.mem.read_u16(BDA_COLUMNS);
+        let location = (position >> 8) * columns + (position & 0xFF);
+        self.write_indexed(CRTC_INDEX, 0x0E, (location >> 8) as u8);
+        self.write_indexed(CRTC_INDEX, 0x0F, location as u8);
+    }
+
+    // characters go where the cursor is; text keeps the cell's attribute,
+    // graphics draws the glyph in `color`; the screen scrolls up from the
+    // bottom line
+    fn teletype(&mut self, c: u8, color: u8) {
+        let columns = self.mem.read_u16(BDA_COLUMNS) as usize;
+        let rows = self.mem.read_u8(BDA_ROWS) as usize + 1;
+        let position = self.mem.read_u16(BDA_CURSOR);
+        let (mut row, mut column) = ((position >> 8) as usize, (position & 0xFF) as usize);
+        match c {
+            0x07 => {},
+            0x08 => column = column.saturating_sub(1),
+            b'\r' => column = 0,
+            b'\n' => row += 1,
+            _ => {
+                self.put_character(row, column, c, color);
+                column += 1;
+                if column == columns {
+                    column = 0;
+                    row += 1;
+                }
+            }
+        }
+        if row == rows {
+            self.scroll_up(columns, rows);
+            row -= 1;
+        }
+        self.set_cursor((row as u16) << 8 | column as u16);
+    }
+
+    fn put_character(&mut self, row: usize, column: usize, c: u8, color: u8) {
+        let columns = self.mem.read_u16(BDA_COLUMNS) as usize;
+        let mode = self.current_video_mode();
+        if mode.is_none_or(|mode| mode.layout == Layout::Text) {
+            self.mem.write_u8(TEXT_BASE + (row * columns + column) * 2, c);
+            return;
+        }
+        // the glyphs INT 43h points at
+        let height = self.mem.read_u16(BDA_CHARACTER_HEIGHT) as usize;
+        let font = self.mem.read_u32(0x43 * 4);
+        let font = ((font >> 16) << 4) as usize + (font & 0xFFFF) as usize;
+        let glyph = self.mem.read_many_u8(font + c as usize * height, height);
+        for (line, bits) in glyph.into_iter().enumerate() {
+            for dot in 0..8 {
+                let pixel = if bits & (0x80 >> dot) != 0 { color } else { 0 };
+                self.write_pixel(column * 8 + dot, row * height + line, pixel);
+            }
+        }
+    }
+
+    fn scroll_up(&mut self, columns: usize, rows: usize) {
+        let Some(mode) = self.current_video_mode().filter(|mode| mode.layout != Layout::Text) else {
+            let line = columns * 2;
+            let screen = self.mem.read_many_u8(TEXT_BASE + line, line * (rows - 1));
+            for (i, byte) in screen.into_iter().enumerate() {
+                self.mem.write_u8(TEXT_BASE + i, byte);
+            }
+            for cell in 0..columns {
+                self.mem.write_u16(TEXT_BASE + line * (rows - 1) + cell * 2, BLANK);
+            }
+            return;
+        };
+        let height = self.mem.read_u16(BDA_CHARACTER_HEIGHT) as usize;
+        match mode.layout {
+            // each bank holds every other line
+            Layout::Cga(bits) => {
+                let line = mode.width as usize * bits / 8;
+                let lines = mode.height as usize / 2;
+                for bank in [TEXT_BASE, TEXT_BASE + CGA_ODD_LINES] {
+                    self.move_lines(bank, line, height / 2, lines);
+                }
+            },
+            // write mode 1 stores the latches the read just loaded, so one
+            // byte moves all four planes
+            Layout::Planar => {
+                self.write_indexed(GRAPHICS_INDEX, 0x05, 0x01);
+                let line = mode.width as usize / 8;
+                let moved = line * (mode.height as usize - height);
+                for offset in 0..moved {
+                    self.mem.read_u8(GRAPHICS_BASE + offset + line * height);
+                    self.mem.write_u8(GRAPHICS_BASE + offset, 0);
+                }
+                self.write_indexed(GRAPHICS_INDEX, 0x05, 0x00);
+                self.write_indexed(GRAPHICS_INDEX, 0x00, 0x00);
+                self.write_indexed(GRAPHICS_INDEX, 0x01, 0x0F);
+                for offset in moved..line * mode.height as usize {
+                    self.mem.write_u8(GRAPHICS_BASE + offset, 0);
+                }
+                self.write_indexed(GRAPHICS_INDEX, 0x01, 0x00);
+            },
+            _ => self.move_lines(GRAPHICS_BASE, mode.width as usize, height, mode.height as usize)
+        }
+    }
+
+    // move `lines` lines of `line` bytes up by `by` and zero the ones freed
+    fn move_lines(&mut self, base: usize, line: usize, by: usize, lines: usize) {
+        let moved = line * (lines - by);
+        let screen = self.mem.read_many_u8(base + line * by, moved);
+        for (i, byte) in screen.into_iter().enumerate() {
+            self.mem.write_u8(base + i, byte);
+        }
+        for offset in moved..line * lines {
+            self.mem.write_u8(base + offset, 0);
+        }
+    }
+}
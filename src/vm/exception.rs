@@ -0,0 +1,44 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exception {
+    DivideError,
+    InvalidOpcode,
+    DoubleFault,
+    InvalidTss(u16),
+    SegmentNotPresent(u16),
+    StackFault(u16),
+    GeneralProtection(u16),
+    PageFault(u32),
+}
+
+impl Exception {
+    pub fn vector(&self) -> u8 {
+        match self {
+            Exception::DivideError => 0,
+            Exception::InvalidOpcode => 6,
+            Exception::DoubleFault => 8,
+            Exception::InvalidTss(_) => 10,
+            Exception::SegmentNotPresent(_) => 11,
+            Exception::StackFault(_) => 12,
+            Exception::GeneralProtection(_) => 13,
+            Exception::PageFault(_) => 14,
+        }
+    }
+
+    pub fn error_code(&self) -> Option<u32> {
+        match self {
+            Exception::DoubleFault => Some(0),
+            Exception::InvalidTss(code) |
+            Exception::SegmentNotPresent(code) |
+            Exception::StackFault(code) |
+            Exception::GeneralProtection(code) => Some(*code as u32),
+            Exception::PageFault(code) => Some(*code),
+            _ => None
+        }
+    }
+
+    // faults during delivery of these combine into a double fault
+    pub fn is_contributory(&self) -> bool {
+        matches!(self, Exception::DivideError | Exception::InvalidTss(_) | Exception::SegmentNotPresent(_) |
+            Exception::StackFault(_) | Exception::GeneralProtection(_))
+    }
+}
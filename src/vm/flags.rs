@@ -0,0 +1,95 @@
+use iced_x86::ConditionCode;
+use crate::ast::Bits;
+use crate::vm::register::FlagsRegister;
+
+pub const CF: u64 = 1;
+pub const PF: u64 = 4;
+pub const AF: u64 = 16;
+pub const ZF: u64 = 64;
+pub const SF: u64 = 128;
+pub const TF: u64 = 256;
+pub const IF: u64 = 512;
+pub const DF: u64 = 1024;
+pub const OF: u64 = 2048;
+pub const IOPL: u64 = 3 << 12;
+pub const NT: u64 = 1 << 14;
+pub const RF: u64 = 1 << 16;
+pub const VM: u64 = 1 << 17;
+
+impl FlagsRegister {
+    pub fn set(&mut self, flag: u64, value: bool) -> &mut Self {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    pub fn get(&self, flag: u64) -> bool {
+        self.flags & flag == flag
+    }
+
+    // ZF, SF and PF only ever depend on the truncated result
+    pub fn set_result(&mut self, result: u64, bits: Bits) -> u64 {
+        let result = result & bits.mask();
+        self.set(ZF, result == 0);
+        self.set(SF, result & bits.sign_bit() != 0);
+        self.set(PF, (result as u8).count_ones().is_multiple_of(2));
+        result
+    }
+
+    pub fn add(&mut self, a: u64, b: u64, carry: bool, bits: Bits) -> u64 {
+        let a = a & bits.mask();
+        let b = b & bits.mask();
+        let full = a as u128 + b as u128 + carry as u128;
+        let result = self.set_result(full as u64, bits);
+
+        self.set(CF, full > bits.mask() as u128);
+        self.set(AF, (a ^ b ^ result) & 0x10 != 0);
+        self.set(OF, (a ^ result) & (b ^ result) & bits.sign_bit() != 0);
+        result
+    }
+
+    pub fn sub(&mut self, a: u64, b: u64, borrow: bool, bits: Bits) -> u64 {
+        let a = a & bits.mask();
+        let b = b & bits.mask();
+        let full = (a as u128).wrapping_sub(b as u128).wrapping_sub(borrow as u128);
+        let result = self.set_result(full as u64, bits);
+
+        self.set(CF, (a as u128) < b as u128 + borrow as u128);
+        self.set(AF, (a ^ b ^ result) & 0x10 != 0);
+        self.set(OF, (a ^ b) & (a ^ result) & bits.sign_bit() != 0);
+        result
+    }
+
+    // AND, OR, XOR and TEST: CF and OF cleared, AF left undefined (cleared here)
+    pub fn logic(&mut self, result: u64, bits: Bits) -> u64 {
+        self.set(CF, false);
+        self.set(OF, false);
+        self.set(AF, false);
+        self.set_result(result, bits)
+    }
+
+    pub fn condition(&self, cc: ConditionCode) -> bool {
+        match cc {
+            ConditionCode::None => true,
+            ConditionCode::o => self.get(OF),
+            ConditionCode::no => !self.get(OF),
+            ConditionCode::b => self.get(CF),
+            ConditionCode::ae => !self.get(CF),
+            ConditionCode::e => self.get(ZF),
+            ConditionCode::ne => !self.get(ZF),
+            ConditionCode::be => self.get(CF) || self.get(ZF),
+            ConditionCode::a => !self.get(CF) && !self.get(ZF),
+            ConditionCode::s => self.get(SF),
+            ConditionCode::ns => !self.get(SF),
+            ConditionCode::p => self.get(PF),
+            ConditionCode::np => !self.get(PF),
+            ConditionCode::l => self.get(SF) != self.get(OF),
+            ConditionCode::ge => self.get(SF) == self.get(OF),
+            ConditionCode::le => self.get(ZF) || self.get(SF) != self.get(OF),
+            ConditionCode::g => !self.get(ZF) && self.get(SF) == self.get(OF),
+        }
+    }
+}
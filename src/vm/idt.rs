@@ -0,0 +1,74 @@
+use crate::ast::Bits;
+
+pub const TASK_GATE: u8 = 0x5;
+pub const INTERRUPT_GATE_16: u8 = 0x6;
+pub const TRAP_GATE_16: u8 = 0x7;
+pub const INTERRUPT_GATE: u8 = 0xE;
+pub const TRAP_GATE: u8 = 0xF;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Gate {
+    pub(crate) selector: u16,
+    pub(crate) offset: u64,
+    pub(crate) kind: u8,
+    pub(crate) dpl: u8,
+    pub(crate) present: bool,
+    pub(crate) ist: u8,
+}
+
+impl Gate {
+    // high is the second quadword of a 16 byte long mode gate, zero otherwise
+    pub fn from_raw(low: u64, high: u64) -> Self {
+        Self {
+            selector: (low >> 16) as u16,
+            offset: (low & 0xFFFF) | ((low >> 32) & 0xFFFF_0000) | (high & 0xFFFF_FFFF) << 32,
+            kind: ((low >> 40) & 0xF) as u8,
+            dpl: ((low >> 45) & 3) as u8,
+            present: low & (1 << 47) != 0,
+            ist: ((low >> 32) & 7) as u8,
+        }
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        matches!(self.kind, INTERRUPT_GATE_16 | INTERRUPT_GATE)
+    }
+
+    pub fn is_trap(&self) -> bool {
+        matches!(self.kind, TRAP_GATE_16 | TRAP_GATE)
+    }
+
+    // width of the values pushed onto the handler's stack outside long mode
+    pub fn bits(&self) -> Bits {
+        match self.kind {
+            INTERRUPT_GATE_16 | TRAP_GATE_16 => Bits::Bit16,
+            _ => Bits::Bit32
+        }
+    }
+}
+
+// IDTR: in real mode it locates the IVT, which is why it starts at 0:3FF
+#[derive(Debug, Clone, Copy)]
+pub struct Idt {
+    pub base: u64,
+    pub limit: u16,
+}
+
+impl Default for Idt {
+    fn default() -> Self {
+        Self {
+            base: 0,
+            limit: 0x3FF
+        }
+    }
+}
+
+impl Idt {
+    pub fn gate_address(&self, vector: u8, long: bool) -> Option<u64> {
+        let size = if long { 16 } else { 8 };
+        let offset = vector as u64 * size;
+        if offset + size - 1 > self.limit as u64 {
+            return None;
+        }
+        Some(self.base + offset)
+    }
+}
@@ -0,0 +1,64 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::ast::Bits;
+
+// a device claiming a range of ports sees the absolute port number and the
+// width of the access
+pub trait PortDevice {
+    fn read(&mut self, port: u16, bits: Bits) -> u64;
+    fn write(&mut self, port: u16, value: u64, bits: Bits);
+}
+
+struct PortRange {
+    start: u16,
+    end: u16,
+    device: Rc<RefCell<dyn PortDevice>>,
+}
+
+pub struct IoBus {
+    ranges: Vec<PortRange>,
+    pub log_unclaimed: bool,
+}
+
+impl IoBus {
+    pub fn new() -> Self {
+        Self {
+            ranges: Vec::new(),
+            log_unclaimed: true,
+        }
+    }
+
+    pub fn register(&mut self, start: u16, count: u16, device: Rc<RefCell<dyn PortDevice>>) {
+        self.ranges.push(PortRange { start, end: start + (count - 1), device });
+    }
+
+    fn device(&self, port: u16) -> Option<&Rc<RefCell<dyn PortDevice>>> {
+        self.ranges.iter()
+            .find(|range| port >= range.start && port <= range.end)
+            .map(|range| &range.device)
+    }
+
+    // nothing drives the data lines of an unclaimed port, so it reads as ones
+    pub fn read(&mut self, port: u16, bits: Bits) -> u64 {
+        match self.device(port) {
+            Some(device) => device.borrow_mut().read(port, bits) & bits.mask(),
+            None => {
+                if self.log_unclaimed {
+                    eprintln!("unhandled port read: {:#x}", port);
+                }
+                bits.mask()
+            }
+        }
+    }
+
+    pub fn write(&mut self, port: u16, value: u64, bits: Bits) {
+        match self.device(port) {
+            Some(device) => device.borrow_mut().write(port, value & bits.mask(), bits),
+            None => {
+                if self.log_unclaimed {
+                    eprintln!("unhandled port write: {:#x} <- {:#x}", port, value);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use sdl2::keyboard::Scancode;
+use crate::ast::Bits;
+use crate::vm::io::PortDevice;
+use crate::vm::pic::Pic;
+
+pub const KBD_DATA: u16 = 0x60;
+pub const KBD_STATUS: u16 = 0x64;
+const KEYBOARD_IRQ: u8 = 1;
+
+// status register
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_SYSTEM: u8 = 1 << 2;
+const STATUS_COMMAND: u8 = 1 << 3;
+const STATUS_UNLOCKED: u8 = 1 << 4;
+
+// controller command byte
+const CONFIG_IRQ: u8 = 1 << 0;
+const CONFIG_SYSTEM: u8 = 1 << 2;
+const CONFIG_DISABLE: u8 = 1 << 4;
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+// output port: reset is active low
+const OUTPUT_RESET: u8 = 1 << 0;
+const OUTPUT_A20: u8 = 1 << 1;
+const OUTPUT_IRQ: u8 = 1 << 4;
+
+// keyboard responses
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const SELF_TEST_PASSED: u8 = 0xAA;
+const ECHO: u8 = 0xEE;
+const EXTENDED: u8 = 0xE0;
+const BREAK: u8 = 0xF0;
+
+// 500 ms delay and 10.9 characters per second
+const DEFAULT_TYPEMATIC: u8 = 0x2B;
+
+// scan code set 1 and set 2 make codes, the extended keys get an E0 prefix
+const KEYS: &[(Scancode, u8, u8, bool)] = &[
+    (Scancode::Escape, 0x01, 0x76, false),
+    (Scancode::Num1, 0x02, 0x16, false),
+    (Scancode::Num2, 0x03, 0x1E, false),
+    (Scancode::Num3, 0x04, 0x26, false),
+    (Scancode::Num4, 0x05, 0x25, false),
+    (Scancode::Num5, 0x06, 0x2E, false),
+    (Scancode::Num6, 0x07, 0x36, false),
+    (Scancode::Num7, 0x08, 0x3D, false),
+    (Scancode::Num8, 0x09, 0x3E, false),
+    (Scancode::Num9, 0x0A, 0x46, false),
+    (Scancode::Num0, 0x0B, 0x45, false),
+    (Scancode::Minus, 0x0C, 0x4E, false),
+    (Scancode::Equals, 0x0D, 0x55, false),
+    (Scancode::Backspace, 0x0E, 0x66, false),
+    (Scancode::Tab, 0x0F, 0x0D, false),
+    (Scancode::Q, 0x10, 0x15, false),
+    (Scancode::W, 0x11, 0x1D, false),
+    (Scancode::E, 0x12, 0x24, false),
+    (Scancode::R, 0x13, 0x2D, false),
+    (Scancode::T, 0x14, 0x2C, false),
+    (Scancode::Y, 0x15, 0x35, false),
+    (Scancode::U, 0x16, 0x3C, false),
+    (Scancode::I, 0x17, 0x43, false),
+    (Scancode::O, 0x18, 0x44, false),
+    (Scancode::P, 0x19, 0x4D, false),
+    (Scancode::LeftBracket, 0x1A, 0x54, false),
+    (Scancode::RightBracket, 0x1B, 0x5B, false),
+    (Scancode::Return, 0x1C, 0x5A, false),
+    (Scancode::LCtrl, 0x1D, 0x14, false),
+    (Scancode::A, 0x1E, 0x1C, false),
+    (Scancode::S, 0x1F, 0x1B, false),
+    (Scancode::D, 0x20, 0x23, false),
+    (Scancode::F, 0x21, 0x2B, false),
+    (Scancode::G, 0x22, 0x34, false),
+    (Scancode::H, 0x23, 0x33, false),
+    (Scancode::J, 0x24, 0x3B, false),
+    (Scancode::K, 0x25, 0x42, false),
+    (Scancode::L, 0x26, 0x4B, false),
+    (Scancode::Semicolon, 0x27, 0x4C, false),
+    (Scancode::Apostrophe, 0x28, 0x52, false),
+    (Scancode::Grave, 0x29, 0x0E, false),
+    (Scancode::LShift, 0x2A, 0x12, false),
+    (Scancode::Backslash, 0x2B, 0x5D, false),
+    (Scancode::Z, 0x2C, 0x1A, false),
+    (Scancode::X, 0x2D, 0x22, false),
+    (Scancode::C, 0x2E, 0x21, false),
+    (Scancode::V, 0x2F, 0x2A, false),
+    (Scancode::B, 0x30, 0x32, false),
+    (Scancode::N, 0x31, 0x31, false),
+    (Scancode::M, 0x32, 0x3A, false),
+    (Scancode::Comma, 0x33, 0x41, false),
+    (Scancode::Period, 0x34, 0x49, false),
+    (Scancode::Slash, 0x35, 0x4A, false),
+    (Scancode::RShift, 0x36, 0x59, false),
+    (Scancode::KpMultiply, 0x37, 0x7C, false),
+    (Scancode::LAlt, 0x38, 0x11, false),
+    (Scancode::Space, 0x39, 0x29, false),
+    (Scancode::CapsLock, 0x3A, 0x58, false),
+    (Scancode::F1, 0x3B, 0x05, false),
+    (Scancode::F2, 0x3C, 0x06, false),
+    (Scancode::F3, 0x3D, 0x04, false),
+    (Scancode::F4, 0x3E, 0x0C, false),
+    (Scancode::F5, 0x3F, 0x03, false),
+    (Scancode::F6, 0x40, 0x0B, false),
+    (Scancode::F7, 0x41, 0x83, false),
+    (Scancode::F8, 0x42, 0x0A, false),
+    (Scancode::F9, 0x43, 0x01, false),
+    (Scancode::F10, 0x44, 0x09, false),
+    (Scancode::NumLockClear, 0x45, 0x77, false),
+    (Scancode::ScrollLock, 0x46, 0x7E, false),
+    (Scancode::Kp7, 0x47, 0x6C, false),
+    (Scancode::Kp8, 0x48, 0x75, false),
+    (Scancode::Kp9, 0x49, 0x7D, false),
+    (Scancode::KpMinus, 0x4A, 0x7B, false),
+    (Scancode::Kp4, 0x4B, 0x6B, false),
+    (Scancode::Kp5, 0x4C, 0x73, false),
+    (Scancode::Kp6, 0x4D, 0x74, false),
+    (Scancode::KpPlus, 0x4E, 0x79, false),
+    (Scancode::Kp1, 0x4F, 0x69, false),
+    (Scancode::Kp2, 0x50, 0x72, false),
+    (Scancode::Kp3, 0x51, 0x7A, false),
+    (Scancode::Kp0, 0x52, 0x70, false),
+    (Scancode::KpPeriod, 0x53, 0x71, false),
+    (Scancode::NonUsBackslash, 0x56, 0x61, false),
+    (Scancode::F11, 0x57, 0x78, false),
+    (Scancode::F12, 0x58, 0x07, false),
+    (Scancode::KpEnter, 0x1C, 0x5A, true),
+    (Scancode::RCtrl, 0x1D, 0x14, true),
+    (Scancode::KpDivide, 0x35, 0x4A, true),
+    (Scancode::RAlt, 0x38, 0x11, true),
+    (Scancode::Home, 0x47, 0x6C, true),
+    (Scancode::Up, 0x48, 0x75, true),
+    (Scancode::PageUp, 0x49, 0x7D, true),
+    (Scancode::Left, 0x4B, 0x6B, true),
+    (Scancode::Right, 0x4D, 0x74, true),
+    (Scancode::End, 0x4F, 0x69, true),
+    (Scancode::Down, 0x50, 0x72, true),
+    (Scancode::PageDown, 0x51, 0x7A, true),
+    (Scancode::Insert, 0x52, 0x70, true),
+    (Scancode::Delete, 0x53, 0x71, true),
+    (Scancode::LGui, 0x5B, 0x1F, true),
+    (Scancode::RGui, 0x5C, 0x27, true),
+    (Scancode::Application, 0x5D, 0x2F, true),
+];
+
+// the keyboard itself, sitting behind the controller
+struct Keyboard {
+    queue: VecDeque<u8>,
+    scanning: bool,
+    scan_set: u8,
+    leds: u8,
+    typematic: u8,
+    // a command waiting for its parameter byte
+    pending: Option<u8>,
+    held: Option<Scancode>,
+    repeat_ns: u64,
+}
+
+impl Keyboard {
+    fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            scanning: true,
+            scan_set: 2,
+            leds: 0,
+            typematic: DEFAULT_TYPEMATIC,
+            pending: None,
+            held: None,
+            repeat_ns: 0,
+        }
+    }
+
+    fn set_defaults(&mut self) {
+        self.scan_set = 2;
+        self.typematic = DEFAULT_TYPEMATIC;
+        self.held = None;
+    }
+
+    // bits 5-6 give the delay in quarter seconds, bits 0-4 the rate as
+    // (8 + A) * 2^B * 4.17 ms
+    fn typematic_delay_ns(&self) -> u64 {
+        (((self.typematic >> 5) & 3) as u64 + 1) * 250_000_000
+    }
+
+    fn typematic_period_ns(&self) -> u64 {
+        let a = (self.typematic & 7) as u64;
+        let b = ((self.typematic >> 3) & 3) as u64;
+        (8 + a) * (1 << b) * 4_170_000
+    }
+
+    fn command(&mut self, value: u8) {
+        if let Some(command) = self.pending.take() {
+            match command {
+                0xED => self.leds = value & 7,
+                0xF0 if value == 0 => {
+                    self.queue.push_back(ACK);
+                    self.queue.push_back(self.scan_set);
+                    return;
+                },
+                0xF0 if value == 1 || value == 2 => self.scan_set = value,
+                0xF0 => {
+                    self.queue.push_back(RESEND);
+                    return;
+                },
+                0xF3 => self.typematic = value & 0x7F,
+                _ => {}
+            }
+            self.queue.push_back(ACK);
+            return;
+        }
+        match value {
+            0xED | 0xF0 | 0xF3 => {
+                self.pending = Some(value);
+                self.queue.push_back(ACK);
+            },
+            0xEE => self.queue.push_back(ECHO),
+            // MF2 keyboard
+            0xF2 => self.queue.extend([ACK, 0xAB, 0x83]),
+            0xF4 => {
+                self.scanning = true;
+                self.queue.push_back(ACK);
+            },
+            0xF5 => {
+                self.set_defaults();
+                self.scanning = false;
+                self.queue.push_back(ACK);
+            },
+            0xF6 => {
+                self.set_defaults();
+                self.queue.push_back(ACK);
+            },
+            0xFF => {
+                *self = Keyboard::new();
+                self.queue.extend([ACK, SELF_TEST_PASSED]);
+            },
+            _ => self.queue.push_back(RESEND)
+        }
+    }
+
+    // set 1 comes out as is (or after translation from set 2 by the
+    // controller), set 2 breaks with an F0 prefix
+    fn send(&mut self, key: Scancode, pressed: bool, translate: bool) {
+        let Some(&(_, set1, set2, extended)) = KEYS.iter().find(|entry| entry.0 == key) else {
+            return;
+        };
+        if extended {
+            self.queue.push_back(EXTENDED);
+        }
+        if self.scan_set == 1 || translate {
+            self.queue.push_back(if pressed { set1 } else { set1 | 0x80 });
+        } else {
+            if !pressed {
+                self.queue.push_back(BREAK);
+            }
+            self.queue.push_back(set2);
+        }
+    }
+
+    fn key(&mut self, key: Scancode, pressed: bool, translate: bool) {
+        if !self.scanning {
+            return;
+        }
+        if pressed {
+            self.held = Some(key);
+            self.repeat_ns = self.typematic_delay_ns();
+        } else if self.held == Some(key) {
+            self.held = None;
+        }
+        self.send(key, pressed, translate);
+    }
+
+    // the last key held down repeats its make code
+    fn advance(&mut self, ns: u64, translate: bool) {
+        let Some(key) = self.held else {
+            return;
+        };
+        if self.repeat_ns > ns {
+            self.repeat_ns -= ns;
+            return;
+        }
+        self.repeat_ns = self.typematic_period_ns();
+        self.send(key, true, translate);
+    }
+}
+
+// the 8042 with its output port wired to A20 and the CPU reset line
+pub struct KeyboardController {
+    keyboard: Keyboard,
+    pic: Rc<RefCell<Pic>>,
+    // responses of the controller itself go before keyboard data
+    replies: VecDeque<u8>,
+    output: Option<u8>,
+    config: u8,
+    output_port: u8,
+    // a controller command waiting for its data byte
+    pending: Option<u8>,
+    last_was_command: bool,
+    reset_requested: bool,
+}
+
+impl KeyboardController {
+    pub fn new(pic: Rc<RefCell<Pic>>) -> Self {
+        Self {
+            keyboard: Keyboard::new(),
+            pic,
+            replies: VecDeque::new(),
+            output: None,
+            config: CONFIG_IRQ | CONFIG_SYSTEM | CONFIG_TRANSLATE,
+            output_port: OUTPUT_RESET | OUTPUT_A20,
+            pending: None,
+            last_was_command: false,
+            reset_requested: false,
+        }
+    }
+
+    pub fn a20(&self) -> bool {
+        self.output_port & OUTPUT_A20 != 0
+    }
+
+    pub fn leds(&self) -> u8 {
+        self.keyboard.leds
+    }
+
+    pub fn take_reset(&mut self) -> bool {
+        std::mem::take(&mut self.reset_requested)
+    }
+
+    fn translate(&self) -> bool {
+        self.config & CONFIG_TRANSLATE != 0 && self.keyboard.scan_set == 2
+    }
+
+    pub fn key(&mut self, key: Scancode, pressed: bool) {
+        let translate = self.translate();
+        self.keyboard.key(key, pressed, translate);
+        self.fill();
+    }
+
+    pub fn advance(&mut self, ns: u64) {
+        let translate = self.translate();
+        self.keyboard.advance(ns, translate);
+        self.fill();
+    }
+
+    // move the next byte into the output buffer, IRQ1 goes up with it
+    fn fill(&mut self) {
+        if self.output.is_some() {
+            return;
+        }
+        let byte = match self.replies.pop_front() {
+            Some(byte) => byte,
+            None if self.config & CONFIG_DISABLE == 0 => match self.keyboard.queue.pop_front() {
+                Some(byte) => byte,
+                None => return
+            },
+            None => return
+        };
+        self.output = Some(byte);
+        if self.config & CONFIG_IRQ != 0 {
+            self.output_port |= OUTPUT_IRQ;
+            self.pic.borrow_mut().raise_irq(KEYBOARD_IRQ);
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = STATUS_UNLOCKED;
+        if self.output.is_some() {
+            status |= STATUS_OUTPUT_FULL;
+        }
+        if self.config & CONFIG_SYSTEM != 0 {
+            status |= STATUS_SYSTEM;
+        }
+        if self.last_was_command {
+            status |= STATUS_COMMAND;
+        }
+        status
+    }
+
+    fn write_output_port(&mut self, value: u8) {
+        if value & OUTPUT_RESET == 0 {
+            self.reset_requested = true;
+        }
+        self.output_port = value | OUTPUT_RESET;
+    }
+
+    fn command(&mut self, value: u8) {
+        match value {
+            0x20 => self.replies.push_back(self.config),
+            0x60 | 0xD1 | 0xD2 => self.pending = Some(value),
+            0xA7 | 0xA8 => {},
+            // no auxiliary device: its clock line reads stuck low
+            0xA9 => self.replies.push_back(0x01),
+            0xAA => {
+                self.replies.push_back(0x55);
+                self.config |= CONFIG_SYSTEM;
+            },
+            0xAB => self.replies.push_back(0x00),
+            0xAD => self.config |= CONFIG_DISABLE,
+            0xAE => self.config &= !CONFIG_DISABLE,
+            // input port: keyboard not inhibited, colour display
+            0xC0 => self.replies.push_back(0xBF),
+            0xD0 => self.replies.push_back(self.output_port),
+            0xDD => self.output_port &= !OUTPUT_A20,
+            0xDF => self.output_port |= OUTPUT_A20,
+            // pulse the output port lines whose bit is clear, bit 0 is reset
+            0xF0..=0xFF if value & 1 == 0 => self.reset_requested = true,
+            _ => {}
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        match self.pending.take() {
+            Some(0x60) => self.config = value,
+            Some(0xD1) => self.write_output_port(value),
+            Some(0xD2) => self.replies.push_back(value),
+            _ => {
+                // talking to the keyboard enables it again
+                self.config &= !CONFIG_DISABLE;
+                self.keyboard.command(value);
+            }
+        }
+    }
+}
+
+impl PortDevice for KeyboardController {
+    fn read(&mut self, port: u16, _bits: Bits) -> u64 {
+        if port == KBD_STATUS {
+            return self.status() as u64;
+        }
+        let value = match self.output.take() {
+            Some(value) => {
+                self.output_port &= !OUTPUT_IRQ;
+                self.pic.borrow_mut().lower_irq(KEYBOARD_IRQ);
+                value
+            },
+            None => 0
+        };
+        self.fill();
+        value as u64
+    }
+
+    fn write(&mut self, port: u16, value: u64, _bits: Bits) {
+        let value = value as u8;
+        self.last_was_command = port == KBD_STATUS;
+        if port == KBD_STATUS {
+            self.command(value);
+        } else {
+            self.write_data(value);
+        }
+        self.fill();
+    }
+}
@@ -1,64 +1,194 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::ast::Bits;
+
 pub const HUNDRED_MO: usize = 104_857_600;
 
+// what a read from an address nothing decodes returns
+const OPEN_BUS: u8 = 0xFF;
+
+// a memory-mapped device sees offsets relative to the start of its region
+pub trait MmioDevice {
+    fn read(&mut self, offset: usize) -> u8;
+    fn write(&mut self, offset: usize, value: u8);
+}
+
+enum Backing {
+    Ram(Vec<u8>),
+    Rom(Vec<u8>),
+    Device(Rc<RefCell<dyn MmioDevice>>),
+}
+
+struct Region {
+    start: usize,
+    end: usize,
+    backing: Backing,
+}
+
+// the physical address bus
 pub struct Memory {
-    data: Vec<u8>,
+    regions: Vec<Region>,
+    // with the A20 gate closed address bit 20 is forced low, wrapping at 1M
+    a20: bool,
 }
 
 
 impl Memory {
-    pub fn new(size: usize) -> Self {
+    pub fn new() -> Self {
         Self {
-            data: vec![0; size],
+            regions: Vec::new(),
+            a20: true,
+        }
+    }
+
+    pub fn set_a20(&mut self, enabled: bool) {
+        self.a20 = enabled;
+    }
+
+    fn gate(&self, addr: usize) -> usize {
+        if self.a20 { addr } else { addr & !(1 << 20) }
+    }
+
+    // mapping over an existing region at the same address replaces it
+    fn map(&mut self, start: usize, size: usize, backing: Backing) {
+        self.regions.retain(|region| region.start != start);
+        self.regions.push(Region { start, end: start + size, backing });
+    }
+
+    pub fn map_ram(&mut self, start: usize, size: usize) {
+        self.map(start, size, Backing::Ram(vec![0; size]));
+    }
+
+    pub fn map_rom(&mut self, start: usize, data: Vec<u8>) {
+        self.map(start, data.len(), Backing::Rom(data));
+    }
+
+    pub fn map_device(&mut self, start: usize, size: usize, device: Rc<RefCell<dyn MmioDevice>>) {
+        self.map(start, size, Backing::Device(device));
+    }
+
+    fn region(&self, addr: usize) -> Option<&Region> {
+        self.regions.iter().find(|region| addr >= region.start && addr < region.end)
+    }
+
+    fn region_mut(&mut self, addr: usize) -> Option<&mut Region> {
+        self.regions.iter_mut().find(|region| addr >= region.start && addr < region.end)
+    }
+
+    // plain RAM or ROM holding the whole range, for the multi-byte fast path
+    fn slice(&self, addr: usize, size: usize) -> Option<&[u8]> {
+        let addr = self.gate(addr);
+        let region = self.region(addr)?;
+        if addr + size > region.end {
+            return None;
+        }
+        match &region.backing {
+            Backing::Ram(data) | Backing::Rom(data) => Some(&data[addr - region.start..addr - region.start + size]),
+            Backing::Device(_) => None
+        }
+    }
+
+    fn slice_mut(&mut self, addr: usize, size: usize) -> Option<&mut [u8]> {
+        let addr = self.gate(addr);
+        let region = self.region_mut(addr)?;
+        if addr + size > region.end {
+            return None;
+        }
+        match &mut region.backing {
+            Backing::Ram(data) => Some(&mut data[addr - region.start..addr - region.start + size]),
+            _ => None
+        }
+    }
+
+    fn read_bytes<const N: usize>(&self, addr: usize) -> [u8; N] {
+        let mut bytes = [0; N];
+        match self.slice(addr, N) {
+            Some(data) => bytes.copy_from_slice(data),
+            None => {
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.read_u8(addr + i);
+                }
+            }
+        }
+        bytes
+    }
+
+    fn write_bytes(&mut self, addr: usize, bytes: &[u8]) {
+        match self.slice_mut(addr, bytes.len()) {
+            Some(data) => data.copy_from_slice(bytes),
+            None => {
+                for (i, &byte) in bytes.iter().enumerate() {
+                    self.write_u8(addr + i, byte);
+                }
+            }
         }
     }
 
     pub fn read_many_u8(&self, addr: usize, size: usize) -> Vec<u8> {
-        self.data[addr..addr + size].to_vec()
+        (0..size).map(|i| self.read_u8(addr + i)).collect()
     }
     pub fn read_u8(&self, addr: usize) -> u8 {
-        self.data[addr]
+        let addr = self.gate(addr);
+        match self.region(addr) {
+            Some(region) => match &region.backing {
+                Backing::Ram(data) | Backing::Rom(data) => data[addr - region.start],
+                Backing::Device(device) => device.borrow_mut().read(addr - region.start),
+            },
+            None => OPEN_BUS
+        }
     }
 
     pub fn read_u16(&self, addr: usize) -> u16 {
-        u16::from_le_bytes([self.data[addr], self.data[addr + 1]])
+        u16::from_le_bytes(self.read_bytes(addr))
     }
 
     pub fn read_u32(&self, addr: usize) -> u32 {
-        u32::from_le_bytes([
-            self.data[addr],
-            self.data[addr + 1],
-            self.data[addr + 2],
-            self.data[addr + 3],
-        ])
+        u32::from_le_bytes(self.read_bytes(addr))
+    }
+
+    pub fn read_u64(&self, addr: usize) -> u64 {
+        u64::from_le_bytes(self.read_bytes(addr))
+    }
+
+    pub fn read(&self, addr: usize, bits: Bits) -> u64 {
+        match bits {
+            Bits::Bit8 => self.read_u8(addr) as u64,
+            Bits::Bit16 => self.read_u16(addr) as u64,
+            Bits::Bit32 => self.read_u32(addr) as u64,
+            Bits::Bit64 => self.read_u64(addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, value: u64, bits: Bits) {
+        match bits {
+            Bits::Bit8 => self.write_u8(addr, value as u8),
+            Bits::Bit16 => self.write_u16(addr, value as u16),
+            Bits::Bit32 => self.write_u32(addr, value as u32),
+            Bits::Bit64 => self.write_u64(addr, value),
+        }
     }
 
+    // ROM and unmapped addresses silently drop writes
     pub fn write_u8(&mut self, addr: usize, value: u8) {
-        self.data[addr] = value;
+        let addr = self.gate(addr);
+        if let Some(region) = self.region_mut(addr) {
+            match &mut region.backing {
+                Backing::Ram(data) => data[addr - region.start] = value,
+                Backing::Rom(_) => {},
+                Backing::Device(device) => device.borrow_mut().write(addr - region.start, value),
+            }
+        }
     }
 
     pub fn write_u16(&mut self, addr: usize, value: u16) {
-        let bytes = value.to_le_bytes();
-        self.data[addr] = bytes[0];
-        self.data[addr + 1] = bytes[1];
+        self.write_bytes(addr, &value.to_le_bytes());
     }
 
     pub fn write_u32(&mut self, addr: usize, value: u32) {
-        let bytes = value.to_le_bytes();
-        self.data[addr] = bytes[0];
-        self.data[addr + 1] = bytes[1];
-        self.data[addr + 2] = bytes[2];
-        self.data[addr + 3] = bytes[3];
+        self.write_bytes(addr, &value.to_le_bytes());
     }
 
     pub fn write_u64(&mut self, addr: usize, value: u64) {
-        let bytes = value.to_le_bytes();
-        self.data[addr] = bytes[0];
-        self.data[addr + 1] = bytes[1];
-        self.data[addr + 2] = bytes[2];
-        self.data[addr + 3] = bytes[3];
-        self.data[addr + 4] = bytes[4];
-        self.data[addr + 5] = bytes[5];
-        self.data[addr + 6] = bytes[6];
-        self.data[addr + 7] = bytes[7];
+        self.write_bytes(addr, &value.to_le_bytes());
     }
 }
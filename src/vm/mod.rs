@@ -1,8 +1,21 @@
 pub mod cpu;
 mod mem;
+mod io;
+mod pic;
+mod pit;
+mod cmos;
+mod keyboard;
+pub mod serial;
+mod flags;
+mod exception;
 mod segment;
+mod paging;
+mod idt;
 mod register;
 mod virtualdisk;
+mod vga;
+mod font;
+mod vbe;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Mode {
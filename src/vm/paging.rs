@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+// bits shared by every level of 2-level, PAE and 4-level tables
+pub const PAGE_PRESENT: u64 = 1 << 0;
+pub const PAGE_WRITABLE: u64 = 1 << 1;
+pub const PAGE_USER: u64 = 1 << 2;
+pub const PAGE_ACCESSED: u64 = 1 << 5;
+pub const PAGE_DIRTY: u64 = 1 << 6;
+pub const PAGE_SIZE: u64 = 1 << 7;
+pub const PAGE_GLOBAL: u64 = 1 << 8;
+pub const PAGE_NO_EXECUTE: u64 = 1 << 63;
+
+// #PF error code
+pub const PF_PROTECTION: u32 = 1 << 0;
+pub const PF_WRITE: u32 = 1 << 1;
+pub const PF_USER: u32 = 1 << 2;
+pub const PF_RESERVED: u32 = 1 << 3;
+pub const PF_FETCH: u32 = 1 << 4;
+
+pub const PAGE_SHIFT: u32 = 12;
+pub const PAGE_MASK: u64 = (1 << PAGE_SHIFT) - 1;
+
+// a translation with the rights of every level of the walk folded in
+#[derive(Debug, Clone, Copy)]
+pub struct TlbEntry {
+    pub(crate) frame: u64,
+    pub(crate) size: u64,
+    pub(crate) writable: bool,
+    pub(crate) user: bool,
+    pub(crate) executable: bool,
+    pub(crate) global: bool,
+    pub(crate) dirty: bool,
+    // physical address and width of the leaf entry, to set D later on
+    pub(crate) leaf: u64,
+    pub(crate) wide: bool,
+}
+
+// keyed by 4K linear page, large pages are split as they are used
+#[derive(Debug, Default)]
+pub struct Tlb {
+    entries: HashMap<u64, TlbEntry>,
+}
+
+impl Tlb {
+    pub fn lookup(&self, linear: u64) -> Option<TlbEntry> {
+        self.entries.get(&(linear >> PAGE_SHIFT)).copied()
+    }
+
+    pub fn insert(&mut self, linear: u64, entry: TlbEntry) {
+        self.entries.insert(linear >> PAGE_SHIFT, entry);
+    }
+
+    pub fn mark_dirty(&mut self, linear: u64) {
+        if let Some(entry) = self.entries.get_mut(&(linear >> PAGE_SHIFT)) {
+            entry.dirty = true;
+        }
+    }
+
+    // MOV CR3 keeps global pages, everything else flushes them too
+    pub fn flush(&mut self, keep_global: bool) {
+        if keep_global {
+            self.entries.retain(|_, entry| entry.global);
+        } else {
+            self.entries.clear();
+        }
+    }
+
+    // INVLPG drops the whole page containing the address, whatever its size
+    pub fn invalidate(&mut self, linear: u64) {
+        self.entries.retain(|page, entry| {
+            let mask = !(entry.size - 1);
+            (page << PAGE_SHIFT) & mask != linear & mask
+        });
+    }
+}
@@ -0,0 +1,413 @@
+use crate::ast::Bits;
+use crate::vm::io::PortDevice;
+
+pub const PIC_MASTER: u16 = 0x20;
+pub const PIC_SLAVE: u16 = 0xA0;
+// the slave's INT output is wired to IR2 of the master
+const CASCADE_IRQ: u8 = 2;
+
+// ICW1 and OCW3 bits
+const ICW1_ICW4: u8 = 1 << 0;
+const ICW1_SINGLE: u8 = 1 << 1;
+const ICW1_LEVEL: u8 = 1 << 3;
+const ICW1_INIT: u8 = 1 << 4;
+const OCW3_SELECT: u8 = 1 << 3;
+const OCW3_READ_ISR: u8 = 1 << 0;
+const OCW3_READ: u8 = 1 << 1;
+const OCW3_POLL: u8 = 1 << 2;
+const OCW3_SPECIAL_MASK: u8 = 1 << 5;
+const OCW3_SET_SPECIAL_MASK: u8 = 1 << 6;
+const ICW4_AUTO_EOI: u8 = 1 << 1;
+// OCW2 with the level in the low three bits
+const SPECIFIC_EOI: u8 = 0x60;
+
+#[derive(Debug, Default)]
+struct Pic8259 {
+    irr: u8,
+    isr: u8,
+    imr: u8,
+    // current level of the IR inputs, for edge detection
+    lines: u8,
+    vector_base: u8,
+    // next initialization word expected, 0 once operational
+    icw_step: u8,
+    needs_icw4: bool,
+    single: bool,
+    level_triggered: bool,
+    auto_eoi: bool,
+    rotate_on_auto_eoi: bool,
+    special_mask: bool,
+    read_isr: bool,
+    poll: bool,
+    // IR with the lowest priority, the one after it has the highest
+    lowest_priority: u8,
+}
+
+impl Pic8259 {
+    fn priority(&self, irq: u8) -> u8 {
+        irq.wrapping_sub(self.lowest_priority).wrapping_sub(1) & 7
+    }
+
+    // the IR with the best priority among the bits set in mask
+    fn highest(&self, mask: u8) -> Option<u8> {
+        (0..8).filter(|irq| mask & (1 << irq) != 0).min_by_key(|&irq| self.priority(irq))
+    }
+
+    fn set_irq(&mut self, irq: u8, level: bool) {
+        let bit = 1 << irq;
+        if level {
+            if self.level_triggered || self.lines & bit == 0 {
+                self.irr |= bit;
+            }
+            self.lines |= bit;
+        } else {
+            self.lines &= !bit;
+            if self.level_triggered {
+                self.irr &= !bit;
+            }
+        }
+    }
+
+    // a request only gets through if nothing of equal or better priority is
+    // in service, special mask mode lets masked in-service levels be ignored
+    fn pending(&self) -> Option<u8> {
+        let irq = self.highest(self.irr & !self.imr)?;
+        let in_service = if self.special_mask { self.isr & !self.imr } else { self.isr };
+        match self.highest(in_service) {
+            Some(current) if self.priority(current) <= self.priority(irq) => None,
+            _ => Some(irq)
+        }
+    }
+
+    fn acknowledge(&mut self, irq: u8) {
+        let bit = 1 << irq;
+        self.irr &= !bit;
+        if self.auto_eoi {
+            if self.rotate_on_auto_eoi {
+                self.lowest_priority = irq;
+            }
+        } else {
+            self.isr |= bit;
+        }
+    }
+
+    fn end_of_interrupt(&mut self, value: u8) {
+        let level = value & 7;
+        match value >> 5 {
+            // non-specific, with or without rotation
+            0b001 | 0b101 => {
+                if let Some(irq) = self.highest(self.isr) {
+                    self.isr &= !(1 << irq);
+                    if value >> 5 == 0b101 {
+                        self.lowest_priority = irq;
+                    }
+                }
+            },
+            // specific, with or without rotation
+            0b011 => self.isr &= !(1 << level),
+            0b111 => {
+                self.isr &= !(1 << level);
+                self.lowest_priority = level;
+            },
+            0b100 => self.rotate_on_auto_eoi = true,
+            0b000 => self.rotate_on_auto_eoi = false,
+            0b110 => self.lowest_priority = level,
+            _ => {}
+        }
+    }
+
+    fn write_command(&mut self, value: u8) {
+        if value & ICW1_INIT != 0 {
+            *self = Pic8259 {
+                vector_base: self.vector_base,
+                icw_step: 2,
+                needs_icw4: value & ICW1_ICW4 != 0,
+                single: value & ICW1_SINGLE != 0,
+                level_triggered: value & ICW1_LEVEL != 0,
+                lowest_priority: 7,
+                ..Pic8259::default()
+            };
+        } else if value & OCW3_SELECT != 0 {
+            if value & OCW3_READ != 0 {
+                self.read_isr = value & OCW3_READ_ISR != 0;
+            }
+            if value & OCW3_SET_SPECIAL_MASK != 0 {
+                self.special_mask = value & OCW3_SPECIAL_MASK != 0;
+            }
+            self.poll = value & OCW3_POLL != 0;
+        } else {
+            self.end_of_interrupt(value);
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        match self.icw_step {
+            2 => {
+                self.vector_base = value & 0xF8;
+                self.icw_step = if !self.single { 3 } else if self.needs_icw4 { 4 } else { 0 };
+            },
+            // ICW3 only describes the wiring, which is fixed here
+            3 => self.icw_step = if self.needs_icw4 { 4 } else { 0 },
+            4 => {
+                self.auto_eoi = value & ICW4_AUTO_EOI != 0;
+                self.icw_step = 0;
+            },
+            _ => self.imr = value
+        }
+    }
+
+    // a poll command acknowledges the request the way an INTA cycle would
+    fn read_command(&mut self) -> u8 {
+        if self.poll {
+            self.poll = false;
+            return match self.pending() {
+                Some(irq) => {
+                    self.acknowledge(irq);
+                    0x80 | irq
+                },
+                None => 0
+            };
+        }
+        if self.read_isr { self.isr } else { self.irr }
+    }
+}
+
+// the master and slave pair of a PC/AT
+#[derive(Debug)]
+pub struct Pic {
+    master: Pic8259,
+    slave: Pic8259,
+}
+
+impl Pic {
+    // BIOS programming: master at vector 08h, slave at 70h, all unmasked
+    pub fn new() -> Self {
+        let mut pic = Self {
+            master: Pic8259::default(),
+            slave: Pic8259::default(),
+        };
+        for (chip, base) in [(&mut pic.master, 0x08), (&mut pic.slave, 0x70)] {
+            chip.write_command(ICW1_INIT | ICW1_ICW4);
+            chip.write_data(base);
+            chip.write_data(0);
+            chip.write_data(1);
+        }
+        pic
+    }
+
+    pub fn set_irq(&mut self, irq: u8, level: bool) {
+        if irq < 8 {
+            self.master.set_irq(irq, level);
+        } else {
+            self.slave.set_irq(irq - 8, level);
+        }
+        self.update_cascade();
+    }
+
+    pub fn raise_irq(&mut self, irq: u8) {
+        self.set_irq(irq, true);
+    }
+
+    pub fn lower_irq(&mut self, irq: u8) {
+        self.set_irq(irq, false);
+    }
+
+    // the slave's output acts as a level on IR2 whatever the master's mode
+    fn update_cascade(&mut self) {
+        if self.slave.pending().is_some() {
+            self.master.irr |= 1 << CASCADE_IRQ;
+        } else {
+            self.master.irr &= !(1 << CASCADE_IRQ);
+        }
+    }
+
+    // the INTA cycle: returns the vector to deliver, spurious requests that
+    // vanished in the meantime come back as IR7 of the chip involved
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let irq = self.master.pending()?;
+        self.master.acknowledge(irq);
+        if irq != CASCADE_IRQ || self.master.single {
+            return Some(self.master.vector_base + irq);
+        }
+        let vector = match self.slave.pending() {
+            Some(irq) => {
+                self.slave.acknowledge(irq);
+                self.slave.vector_base + irq
+            },
+            None => self.slave.vector_base + 7
+        };
+        self.update_cascade();
+        Some(vector)
+    }
+
+    // the IRQ a BIOS handler on this vector has to acknowledge, if it is in
+    // service at all: software INTs and real mode exceptions share these
+    // vectors and must not EOI whatever else is being serviced
+    pub fn irq_for_vector(&self, vector: u8) -> Option<u8> {
+        let (chip, first) = if vector & 0xF8 == self.master.vector_base {
+            (&self.master, 0)
+        } else if vector & 0xF8 == self.slave.vector_base {
+            (&self.slave, 8)
+        } else {
+            return None;
+        };
+        let irq = vector & 7;
+        (chip.isr & (1 << irq) != 0).then_some(irq + first)
+    }
+
+    // specific EOIs; the master's cascade input is released once nothing on
+    // the slave is in service any more
+    pub fn end_of_interrupt(&mut self, irq: u8) {
+        if irq >= 8 {
+            self.slave.end_of_interrupt(SPECIFIC_EOI | (irq - 8));
+            if self.slave.isr == 0 {
+                self.master.end_of_interrupt(SPECIFIC_EOI | CASCADE_IRQ);
+            }
+        } else {
+            self.master.end_of_interrupt(SPECIFIC_EOI | irq);
+        }
+        self.update_cascade();
+    }
+}
+
+impl PortDevice for Pic {
+    fn read(&mut self, port: u16, _bits: Bits) -> u64 {
+        let chip = if port & 0xF0 == PIC_SLAVE { &mut self.slave } else { &mut self.master };
+        let value = if port & 1 == 0 { chip.read_command() } else { chip.imr };
+        self.update_cascade();
+        value as u64
+    }
+
+    fn write(&mut self, port: u16, value: u64, _bits: Bits) {
+        let chip = if port & 0xF0 == PIC_SLAVE { &mut self.slave } else { &mut self.master };
+        if port & 1 == 0 {
+            chip.write_command(value as u8);
+        } else {
+            chip.write_data(value as u8);
+        }
+        self.update_cascade();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an edge on the IR line
+    fn pulse(pic: &mut Pic, irq: u8) {
+        pic.raise_irq(irq);
+        pic.lower_irq(irq);
+    }
+
+    #[test]
+    fn lower_irqs_have_priority() {
+        let mut pic = Pic::new();
+        pulse(&mut pic, 3);
+        pulse(&mut pic, 1);
+        assert_eq!(pic.acknowledge(), Some(0x09));
+        // IR3 waits while IR1 is in service
+        assert_eq!(pic.acknowledge(), None);
+        pic.end_of_interrupt(1);
+        assert_eq!(pic.acknowledge(), Some(0x0B));
+    }
+
+    #[test]
+    fn higher_priority_nests() {
+        let mut pic = Pic::new();
+        pulse(&mut pic, 3);
+        assert_eq!(pic.acknowledge(), Some(0x0B));
+        pulse(&mut pic, 1);
+        assert_eq!(pic.acknowledge(), Some(0x09));
+        // a specific EOI leaves the nested IR1 in service
+        pic.end_of_interrupt(3);
+        assert_eq!(pic.irq_for_vector(0x0B), None);
+        assert_eq!(pic.irq_for_vector(0x09), Some(1));
+    }
+
+    #[test]
+    fn masked_irqs_wait() {
+        let mut pic = Pic::new();
+        pic.write(PIC_MASTER + 1, 0x10, Bits::Bit8);
+        pulse(&mut pic, 4);
+        assert_eq!(pic.acknowledge(), None);
+        pic.write(PIC_MASTER + 1, 0, Bits::Bit8);
+        assert_eq!(pic.acknowledge(), Some(0x0C));
+    }
+
+    #[test]
+    fn slave_through_the_cascade() {
+        let mut pic = Pic::new();
+        pulse(&mut pic, 8);
+        assert_eq!(pic.acknowledge(), Some(0x70));
+        assert_eq!(pic.master.isr, 1 << CASCADE_IRQ);
+        assert_eq!(pic.irq_for_vector(0x70), Some(8));
+        pic.end_of_interrupt(8);
+        assert_eq!((pic.master.isr, pic.slave.isr), (0, 0));
+    }
+
+    #[test]
+    fn software_interrupts_are_not_in_service() {
+        let pic = Pic::new();
+        assert_eq!(pic.irq_for_vector(0x08), None);
+        assert_eq!(pic.irq_for_vector(0x10), None);
+    }
+
+    #[test]
+    fn non_specific_eoi_clears_the_highest() {
+        let mut pic = Pic::new();
+        pulse(&mut pic, 3);
+        pic.acknowledge();
+        pulse(&mut pic, 1);
+        pic.acknowledge();
+        pic.write(PIC_MASTER, 0x20, Bits::Bit8);
+        assert_eq!(pic.master.isr, 1 << 3);
+    }
+
+    #[test]
+    fn rotate_on_eoi() {
+        let mut pic = Pic::new();
+        pulse(&mut pic, 0);
+        assert_eq!(pic.acknowledge(), Some(0x08));
+        // rotate on non-specific EOI: IR0 drops to the lowest priority
+        pic.write(PIC_MASTER, 0xA0, Bits::Bit8);
+        pulse(&mut pic, 0);
+        pulse(&mut pic, 1);
+        assert_eq!(pic.acknowledge(), Some(0x09));
+        pic.end_of_interrupt(1);
+        assert_eq!(pic.acknowledge(), Some(0x08));
+    }
+
+    #[test]
+    fn set_priority() {
+        let mut pic = Pic::new();
+        // IR4 lowest makes IR5 the highest
+        pic.write(PIC_MASTER, 0xC4, Bits::Bit8);
+        for irq in [3, 4, 5] {
+            pulse(&mut pic, irq);
+        }
+        let mut vectors = Vec::new();
+        while let Some(vector) = pic.acknowledge() {
+            vectors.push(vector);
+            pic.write(PIC_MASTER, 0x20, Bits::Bit8);
+        }
+        assert_eq!(vectors, [0x0D, 0x0B, 0x0C]);
+    }
+
+    #[test]
+    fn auto_eoi_rotation() {
+        let mut pic = Pic::new();
+        pic.write(PIC_MASTER, (ICW1_INIT | ICW1_ICW4) as u64, Bits::Bit8);
+        for value in [0x08, 0x04, 0x01 | ICW4_AUTO_EOI] {
+            pic.write(PIC_MASTER + 1, value as u64, Bits::Bit8);
+        }
+        // rotate in automatic EOI mode
+        pic.write(PIC_MASTER, 0x80, Bits::Bit8);
+        pulse(&mut pic, 3);
+        pulse(&mut pic, 6);
+        assert_eq!(pic.acknowledge(), Some(0x0B));
+        assert_eq!(pic.master.isr, 0);
+        pulse(&mut pic, 3);
+        assert_eq!(pic.acknowledge(), Some(0x0E));
+        assert_eq!(pic.acknowledge(), Some(0x0B));
+    }
+}
@@ -0,0 +1,420 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::ast::Bits;
+use crate::vm::io::PortDevice;
+use crate::vm::pic::Pic;
+
+pub const PIT_BASE: u16 = 0x40;
+pub const PORT_B: u16 = 0x61;
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+const TIMER_IRQ: u8 = 0;
+
+// port 61h
+const SPEAKER_GATE: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+const REFRESH: u8 = 1 << 4;
+const TIMER2_OUTPUT: u8 = 1 << 5;
+
+// access modes of the control word
+const ACCESS_LATCH: u8 = 0;
+const ACCESS_LOW: u8 = 1;
+const ACCESS_HIGH: u8 = 2;
+const ACCESS_WORD: u8 = 3;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Channel {
+    mode: u8,
+    access: u8,
+    bcd: bool,
+    // 0 stands for the full 65536 (10000 in BCD)
+    reload: u16,
+    count: u32,
+    output: bool,
+    gate: bool,
+    // a count was written but not yet loaded into the counter
+    null_count: bool,
+    // a complete count has been written since the last control word
+    written: bool,
+    // the counter is running: modes 1 and 5 wait for a gate edge
+    armed: bool,
+    latched: Option<u16>,
+    status: Option<u8>,
+    read_high: bool,
+    write_high: bool,
+    low_byte: u8,
+}
+
+impl Channel {
+    fn modulus(&self) -> u32 {
+        if self.bcd { 10000 } else { 0x10000 }
+    }
+
+    fn initial_count(&self) -> u32 {
+        if self.reload == 0 { self.modulus() } else { self.reload as u32 }
+    }
+
+    fn set_mode(&mut self, mode: u8, access: u8, bcd: bool) {
+        // 6 and 7 are aliases of 2 and 3
+        self.mode = if mode > 5 { mode - 4 } else { mode };
+        self.access = access;
+        self.bcd = bcd;
+        self.output = self.mode != 0;
+        self.written = false;
+        self.armed = false;
+        self.null_count = true;
+        self.latched = None;
+        self.read_high = false;
+        self.write_high = false;
+    }
+
+    fn write_count(&mut self, value: u8) {
+        let value = match self.access {
+            ACCESS_LOW => value as u16,
+            ACCESS_HIGH => (value as u16) << 8,
+            ACCESS_WORD if !self.write_high => {
+                self.low_byte = value;
+                self.write_high = true;
+                // mode 0 stops counting until the whole count is written
+                if self.mode == 0 {
+                    self.output = false;
+                    self.armed = false;
+                }
+                return;
+            },
+            _ => {
+                self.write_high = false;
+                (value as u16) << 8 | self.low_byte as u16
+            }
+        };
+        self.reload = if self.bcd { from_bcd(value) } else { value };
+        self.written = true;
+        self.null_count = true;
+        match self.mode {
+            0 | 4 => {
+                self.output = self.mode == 4;
+                self.load();
+            },
+            // periodic modes pick the new count up at the end of the cycle
+            2 | 3 if self.armed => {},
+            2 | 3 => self.load(),
+            _ => {}
+        }
+    }
+
+    fn load(&mut self) {
+        self.count = self.initial_count();
+        self.null_count = false;
+        self.armed = true;
+    }
+
+    fn counter_value(&self) -> u16 {
+        let count = (self.count % self.modulus()) as u16;
+        if self.bcd { to_bcd(count) } else { count }
+    }
+
+    fn latch(&mut self) {
+        if self.latched.is_none() {
+            self.latched = Some(self.counter_value());
+        }
+    }
+
+    fn status_byte(&self) -> u8 {
+        (self.output as u8) << 7 | (self.null_count as u8) << 6 | self.access << 4 | self.mode << 1 | self.bcd as u8
+    }
+
+    fn read(&mut self) -> u8 {
+        if let Some(status) = self.status.take() {
+            return status;
+        }
+        let value = self.latched.unwrap_or_else(|| self.counter_value());
+        let (byte, done) = match self.access {
+            ACCESS_LOW => (value as u8, true),
+            ACCESS_HIGH => ((value >> 8) as u8, true),
+            ACCESS_WORD if !self.read_high => {
+                self.read_high = true;
+                (value as u8, false)
+            },
+            _ => {
+                self.read_high = false;
+                ((value >> 8) as u8, true)
+            }
+        };
+        if done {
+            self.latched = None;
+        }
+        byte
+    }
+
+    fn set_gate(&mut self, gate: bool) {
+        let rising = gate && !self.gate;
+        self.gate = gate;
+        match self.mode {
+            1 | 5 if rising && self.written => {
+                self.load();
+                self.output = self.mode == 5;
+            },
+            2 | 3 if rising && self.armed => self.load(),
+            2 | 3 if !gate => self.output = true,
+            _ => {}
+        }
+    }
+
+    // one input clock
+    fn tick(&mut self) {
+        if !self.armed || (!self.gate && matches!(self.mode, 0 | 2 | 3 | 4)) {
+            return;
+        }
+        match self.mode {
+            0 | 1 => {
+                self.count = if self.count <= 1 { self.modulus() + self.count - 1 } else { self.count - 1 };
+                if self.count == self.modulus() {
+                    self.output = true;
+                }
+            },
+            2 => {
+                self.count -= 1;
+                if self.count == 1 {
+                    self.output = false;
+                } else if self.count == 0 {
+                    self.output = true;
+                    self.load();
+                }
+            },
+            3 => {
+                // odd counts spend one more clock with the output high
+                let step = if self.count & 1 == 1 { if self.output { 1 } else { 3 } } else { 2 };
+                self.count = self.count.saturating_sub(step);
+                if self.count == 0 {
+                    self.output = !self.output;
+                    self.load();
+                }
+            },
+            4 | 5 => {
+                if self.count == 0 {
+                    self.output = true;
+                    self.armed = false;
+                    return;
+                }
+                self.count -= 1;
+                if self.count == 0 {
+                    self.output = false;
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+fn from_bcd(value: u16) -> u16 {
+    (0..4).rev().fold(0, |acc, digit| acc * 10 + ((value >> (digit * 4)) & 0xF))
+}
+
+fn to_bcd(value: u16) -> u16 {
+    (0..4).fold(0, |acc, digit| acc | ((value / 10u16.pow(digit)) % 10) << (digit * 4))
+}
+
+// the 8254 and the bits of port B that hang off it
+pub struct Pit {
+    channels: [Channel; 3],
+    pic: Rc<RefCell<Pic>>,
+    port_b: u8,
+    elapsed_ns: u64,
+    ticks: u64,
+}
+
+impl Pit {
+    pub fn new(pic: Rc<RefCell<Pic>>) -> Self {
+        let mut channels = [Channel::default(); 3];
+        // channels 0 and 1 have their gates tied high
+        channels[0].gate = true;
+        channels[1].gate = true;
+        Self {
+            channels,
+            pic,
+            port_b: 0,
+            elapsed_ns: 0,
+            ticks: 0,
+        }
+    }
+
+    // run the counters up to the emulated time, IRQ0 follows channel 0's
+    // output and the PIC catches the rising edges
+    pub fn advance(&mut self, ns: u64) {
+        self.elapsed_ns += ns;
+        let target = (self.elapsed_ns as u128 * PIT_FREQUENCY as u128 / 1_000_000_000) as u64;
+        while self.ticks < target {
+            let before = self.channels[0].output;
+            for channel in self.channels.iter_mut() {
+                channel.tick();
+            }
+            self.ticks += 1;
+            let after = self.channels[0].output;
+            if after != before {
+                self.pic.borrow_mut().set_irq(TIMER_IRQ, after);
+            }
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        let select = value >> 6;
+        let access = (value >> 4) & 3;
+        if select == 3 {
+            // read-back: bit 5 clear latches counts, bit 4 clear latches status
+            for (i, channel) in self.channels.iter_mut().enumerate() {
+                if value & (2 << i) == 0 {
+                    continue;
+                }
+                if value & 0x20 == 0 {
+                    channel.latch();
+                }
+                if value & 0x10 == 0 && channel.status.is_none() {
+                    channel.status = Some(channel.status_byte());
+                }
+            }
+            return;
+        }
+        let channel = &mut self.channels[select as usize];
+        if access == ACCESS_LATCH {
+            channel.latch();
+        } else {
+            channel.set_mode((value >> 1) & 7, access, value & 1 != 0);
+        }
+    }
+}
+
+impl PortDevice for Pit {
+    fn read(&mut self, port: u16, _bits: Bits) -> u64 {
+        match port {
+            PORT_B => {
+                // the refresh bit toggles every 15us
+                let refresh = if (self.elapsed_ns / 15_000) & 1 == 1 { REFRESH } else { 0 };
+                let output = if self.channels[2].output { TIMER2_OUTPUT } else { 0 };
+                (self.port_b | refresh | output) as u64
+            },
+            0x40..=0x42 => self.channels[(port - PIT_BASE) as usize].read() as u64,
+            _ => 0xFF
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u64, _bits: Bits) {
+        let value = value as u8;
+        match port {
+            PORT_B => {
+                self.port_b = value & (SPEAKER_GATE | SPEAKER_DATA);
+                self.channels[2].set_gate(value & SPEAKER_GATE != 0);
+            },
+            0x40..=0x42 => self.channels[(port - PIT_BASE) as usize].write_count(value),
+            0x43 => self.write_control(value),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(mode: u8, count: u16, bcd: bool) -> Channel {
+        let mut channel = Channel { gate: true, ..Channel::default() };
+        channel.set_mode(mode, ACCESS_WORD, bcd);
+        channel.write_count(count as u8);
+        channel.write_count((count >> 8) as u8);
+        channel
+    }
+
+    // the output level after each of the given number of clocks
+    fn outputs(channel: &mut Channel, clocks: usize) -> Vec<bool> {
+        (0..clocks).map(|_| {
+            channel.tick();
+            channel.output
+        }).collect()
+    }
+
+    #[test]
+    fn mode0_goes_high_at_terminal_count() {
+        let mut channel = channel(0, 3, false);
+        assert!(!channel.output);
+        assert_eq!(outputs(&mut channel, 4), [false, false, true, true]);
+        // and keeps counting down from the full range
+        assert_eq!(channel.counter_value(), 0xFFFF);
+    }
+
+    #[test]
+    fn mode1_waits_for_the_gate() {
+        let mut channel = channel(1, 2, false);
+        channel.gate = false;
+        assert_eq!(outputs(&mut channel, 2), [true, true]);
+        channel.set_gate(true);
+        assert!(!channel.output);
+        assert_eq!(outputs(&mut channel, 3), [false, true, true]);
+    }
+
+    #[test]
+    fn mode2_pulses_low_for_one_clock() {
+        let mut channel = channel(2, 3, false);
+        assert_eq!(outputs(&mut channel, 6), [true, false, true, true, false, true]);
+    }
+
+    #[test]
+    fn mode3_even_count() {
+        let mut channel = channel(3, 4, false);
+        assert_eq!(outputs(&mut channel, 8), [true, false, false, true, true, false, false, true]);
+    }
+
+    // (n + 1) / 2 clocks high and (n - 1) / 2 low
+    #[test]
+    fn mode3_odd_count() {
+        let mut channel = channel(3, 5, false);
+        assert_eq!(outputs(&mut channel, 10), [true, true, false, false, true, true, true, false, false, true]);
+    }
+
+    #[test]
+    fn mode3_new_count_waits_for_the_half_cycle() {
+        let mut channel = channel(3, 4, false);
+        channel.write_count(8);
+        channel.write_count(0);
+        assert_eq!(outputs(&mut channel, 6), [true, false, false, false, false, true]);
+    }
+
+    #[test]
+    fn mode4_strobes_once() {
+        let mut channel = channel(4, 2, false);
+        assert_eq!(outputs(&mut channel, 5), [true, false, true, true, true]);
+    }
+
+    #[test]
+    fn mode5_strobes_after_the_gate() {
+        let mut channel = channel(5, 2, false);
+        channel.gate = false;
+        channel.set_gate(true);
+        assert_eq!(outputs(&mut channel, 4), [true, false, true, true]);
+    }
+
+    #[test]
+    fn bcd_counting() {
+        let mut decimal = channel(2, 0x0100, true);
+        assert_eq!(decimal.counter_value(), 0x0100);
+        decimal.tick();
+        assert_eq!(decimal.counter_value(), 0x0099);
+        // 0 is 10000 in BCD
+        assert_eq!(channel(0, 0, true).count, 10000);
+    }
+
+    #[test]
+    fn bcd_round_trip() {
+        for value in 0..10000 {
+            assert_eq!(from_bcd(to_bcd(value)), value);
+        }
+        assert_eq!(to_bcd(1234), 0x1234);
+    }
+
+    #[test]
+    fn word_access_reads_a_latched_count() {
+        let mut channel = channel(2, 0x1234, false);
+        channel.latch();
+        channel.tick();
+        assert_eq!(channel.read(), 0x34);
+        assert_eq!(channel.read(), 0x12);
+        assert_eq!(channel.read(), 0x33);
+    }
+}
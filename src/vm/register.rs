@@ -61,6 +61,11 @@ impl GeneralPurposeRegisters {
             Register::FS => self.segment.fs as u64,
             Register::GS => self.segment.gs as u64,
             Register::SS => self.segment.ss as u64,
+            // SIL/DIL/BPL/SPL and the R8-R15 byte, word and dword forms
+            reg if reg.is_gpr() => {
+                let size = reg.size() as u32 * 8;
+                self.get_register_value(reg.full_register()) & (u64::MAX >> (64 - size))
+            },
 
             _ => 0
         }
@@ -114,185 +119,235 @@ impl GeneralPurposeRegisters {
             Register::FS => self.segment.fs = value as u16,
             Register::GS => self.segment.gs = value as u16,
             Register::SS => self.segment.ss = value as u16,
+            reg if reg.is_gpr() => {
+                let full = reg.full_register();
+                let new = if reg.size() == 4 {
+                    value as u32 as u64
+                } else {
+                    let mask = u64::MAX >> (64 - reg.size() as u32 * 8);
+                    (self.get_register_value(full) & !mask) | (value as u64 & mask)
+                };
+                self.set_register_value(full, new as usize);
+            },
 
             _ => {}
         }
     }
 
-    pub fn set_al(&mut self, val: u8) {
-        self.gp8.al = val;
-        self.gp16.ax = val as u16;
+    fn sync_rax(&mut self) {
+        let val = self.gp64.rax;
         self.gp32.eax = val as u32;
-        self.gp64.rax = val as u64;
+        self.gp16.ax = val as u16;
+        self.gp8.al = val as u8;
+        self.gp8.ah = (val >> 8) as u8;
+    }
+
+    fn sync_rbx(&mut self) {
+        let val = self.gp64.rbx;
+        self.gp32.ebx = val as u32;
+        self.gp16.bx = val as u16;
+        self.gp8.bl = val as u8;
+        self.gp8.bh = (val >> 8) as u8;
+    }
+
+    fn sync_rcx(&mut self) {
+        let val = self.gp64.rcx;
+        self.gp32.ecx = val as u32;
+        self.gp16.cx = val as u16;
+        self.gp8.cl = val as u8;
+        self.gp8.ch = (val >> 8) as u8;
+    }
+
+    fn sync_rdx(&mut self) {
+        let val = self.gp64.rdx;
+        self.gp32.edx = val as u32;
+        self.gp16.dx = val as u16;
+        self.gp8.dl = val as u8;
+        self.gp8.dh = (val >> 8) as u8;
+    }
+
+    fn sync_rsi(&mut self) {
+        let val = self.gp64.rsi;
+        self.gp32.esi = val as u32;
+        self.gp16.si = val as u16;
+    }
+
+    fn sync_rdi(&mut self) {
+        let val = self.gp64.rdi;
+        self.gp32.edi = val as u32;
+        self.gp16.di = val as u16;
+    }
+
+    fn sync_rbp(&mut self) {
+        let val = self.gp64.rbp;
+        self.gp32.ebp = val as u32;
+        self.gp16.bp = val as u16;
+    }
+
+    fn sync_rsp(&mut self) {
+        let val = self.gp64.rsp;
+        self.gp32.esp = val as u32;
+        self.gp16.sp = val as u16;
+    }
+
+    pub fn set_al(&mut self, val: u8) {
+        self.gp64.rax = (self.gp64.rax & !0xFF) | val as u64;
+        self.sync_rax();
     }
 
     pub fn set_ah(&mut self, val: u8) {
-        self.gp8.ah = val;
-        self.gp16.ax = (self.gp8.ah as u16) << 8 | self.gp8.al as u16;
-        self.gp32.eax = (self.gp16.ax as u32) << 16 | self.gp16.ax as u32;
-        self.gp64.rax = (self.gp32.eax as u64) << 32 | self.gp32.eax as u64;
+        self.gp64.rax = (self.gp64.rax & !0xFF00) | (val as u64) << 8;
+        self.sync_rax();
     }
 
     pub fn set_bl(&mut self, val: u8) {
-        self.gp8.bl = val;
-        self.gp16.bx = val as u16;
-        self.gp32.ebx = val as u32;
-        self.gp64.rbx = val as u64;
+        self.gp64.rbx = (self.gp64.rbx & !0xFF) | val as u64;
+        self.sync_rbx();
     }
 
     pub fn set_bh(&mut self, val: u8) {
-        self.gp8.bh = val;
-        self.gp16.bx = (self.gp8.bh as u16) << 8 | self.gp8.bl as u16;
-        self.gp32.ebx = (self.gp16.bx as u32) << 16 | self.gp16.bx as u32;
-        self.gp64.rbx = (self.gp32.ebx as u64) << 32 | self.gp32.ebx as u64;
+        self.gp64.rbx = (self.gp64.rbx & !0xFF00) | (val as u64) << 8;
+        self.sync_rbx();
     }
 
     pub fn set_cl(&mut self, val: u8) {
-        self.gp8.cl = val;
-        self.gp16.cx = val as u16;
-        self.gp32.ecx = val as u32;
-        self.gp64.rcx = val as u64;
+        self.gp64.rcx = (self.gp64.rcx & !0xFF) | val as u64;
+        self.sync_rcx();
     }
 
     pub fn set_ch(&mut self, val: u8) {
-        self.gp8.ch = val;
-        self.gp16.cx = (self.gp8.ch as u16) << 8 | self.gp8.cl as u16;
-        self.gp32.ecx = (self.gp16.cx as u32) << 16 | self.gp16.cx as u32;
-        self.gp64.rcx = (self.gp32.ecx as u64) << 32 | self.gp32.ecx as u64;
+        self.gp64.rcx = (self.gp64.rcx & !0xFF00) | (val as u64) << 8;
+        self.sync_rcx();
     }
 
     pub fn set_dl(&mut self, val: u8) {
-        self.gp8.dl = val;
-        self.gp16.dx = val as u16;
-        self.gp32.edx = val as u32;
-        self.gp64.rdx = val as u64;
+        self.gp64.rdx = (self.gp64.rdx & !0xFF) | val as u64;
+        self.sync_rdx();
     }
 
     pub fn set_dh(&mut self, val: u8) {
-        self.gp8.dh = val;
-        self.gp16.dx = (self.gp8.dh as u16) << 8 | self.gp8.dl as u16;
-        self.gp32.edx = (self.gp16.dx as u32) << 16 | self.gp16.dx as u32;
-        self.gp64.rdx = (self.gp32.edx as u64) << 32 | self.gp32.edx as u64;
+        self.gp64.rdx = (self.gp64.rdx & !0xFF00) | (val as u64) << 8;
+        self.sync_rdx();
     }
 
     pub fn set_ax(&mut self, val: u16) {
-        self.gp16.ax = val;
-        self.gp32.eax = val as u32;
-        self.gp64.rax = val as u64;
+        self.gp64.rax = (self.gp64.rax & !0xFFFF) | val as u64;
+        self.sync_rax();
     }
 
     pub fn set_bx(&mut self, val: u16) {
-        self.gp16.bx = val;
-        self.gp32.ebx = val as u32;
-        self.gp64.rbx = val as u64;
+        self.gp64.rbx = (self.gp64.rbx & !0xFFFF) | val as u64;
+        self.sync_rbx();
     }
 
     pub fn set_cx(&mut self, val: u16) {
-        self.gp16.cx = val;
-        self.gp32.ecx = val as u32;
-        self.gp64.rcx = val as u64;
+        self.gp64.rcx = (self.gp64.rcx & !0xFFFF) | val as u64;
+        self.sync_rcx();
     }
 
     pub fn set_dx(&mut self, val: u16) {
-        self.gp16.dx = val;
-        self.gp32.edx = val as u32;
-        self.gp64.rdx = val as u64;
+        self.gp64.rdx = (self.gp64.rdx & !0xFFFF) | val as u64;
+        self.sync_rdx();
     }
 
     pub fn set_si(&mut self, val: u16) {
-        self.gp16.si = val;
-        self.gp32.esi = val as u32;
-        self.gp64.rsi = val as u64;
+        self.gp64.rsi = (self.gp64.rsi & !0xFFFF) | val as u64;
+        self.sync_rsi();
     }
 
     pub fn set_di(&mut self, val: u16) {
-        self.gp16.di = val;
-        self.gp32.edi = val as u32;
-        self.gp64.rdi = val as u64;
+        self.gp64.rdi = (self.gp64.rdi & !0xFFFF) | val as u64;
+        self.sync_rdi();
     }
 
     pub fn set_bp(&mut self, val: u16) {
-        self.gp16.bp = val;
-        self.gp32.ebp = val as u32;
-        self.gp64.rbp = val as u64;
+        self.gp64.rbp = (self.gp64.rbp & !0xFFFF) | val as u64;
+        self.sync_rbp();
     }
 
     pub fn set_sp(&mut self, val: u16) {
-        self.gp16.sp = val;
-        self.gp32.esp = val as u32;
-        self.gp64.rsp = val as u64;
+        self.gp64.rsp = (self.gp64.rsp & !0xFFFF) | val as u64;
+        self.sync_rsp();
     }
 
     pub fn set_eax(&mut self, val: u32) {
-        self.gp32.eax = val;
         self.gp64.rax = val as u64;
+        self.sync_rax();
     }
 
     pub fn set_ebx(&mut self, val: u32) {
-        self.gp32.ebx = val;
         self.gp64.rbx = val as u64;
+        self.sync_rbx();
     }
 
     pub fn set_ecx(&mut self, val: u32) {
-        self.gp32.ecx = val;
         self.gp64.rcx = val as u64;
+        self.sync_rcx();
     }
 
     pub fn set_edx(&mut self, val: u32) {
-        self.gp32.edx = val;
         self.gp64.rdx = val as u64;
+        self.sync_rdx();
     }
 
     pub fn set_esi(&mut self, val: u32) {
-        self.gp32.esi = val;
         self.gp64.rsi = val as u64;
+        self.sync_rsi();
     }
 
     pub fn set_edi(&mut self, val: u32) {
-        self.gp32.edi = val;
         self.gp64.rdi = val as u64;
+        self.sync_rdi();
     }
 
     pub fn set_ebp(&mut self, val: u32) {
-        self.gp32.ebp = val;
         self.gp64.rbp = val as u64;
+        self.sync_rbp();
     }
 
     pub fn set_esp(&mut self, val: u32) {
-        self.gp32.esp = val;
         self.gp64.rsp = val as u64;
+        self.sync_rsp();
     }
 
     pub fn set_rax(&mut self, val: u64) {
         self.gp64.rax = val;
+        self.sync_rax();
     }
 
     pub fn set_rbx(&mut self, val: u64) {
         self.gp64.rbx = val;
+        self.sync_rbx();
     }
 
     pub fn set_rcx(&mut self, val: u64) {
         self.gp64.rcx = val;
+        self.sync_rcx();
     }
 
     pub fn set_rdx(&mut self, val: u64) {
         self.gp64.rdx = val;
+        self.sync_rdx();
     }
 
     pub fn set_rsi(&mut self, val: u64) {
         self.gp64.rsi = val;
+        self.sync_rsi();
     }
 
     pub fn set_rdi(&mut self, val: u64) {
         self.gp64.rdi = val;
+        self.sync_rdi();
     }
 
     pub fn set_rbp(&mut self, val: u64) {
         self.gp64.rbp = val;
+        self.sync_rbp();
     }
 
     pub fn set_rsp(&mut self, val: u64) {
         self.gp64.rsp = val;
+        self.sync_rsp();
     }
 
     pub fn set_r8(&mut self, val: u64) {
@@ -526,13 +581,14 @@ pub struct SegmentRegisters {
     pub ss: u16,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct ControlRegisters {
     pub cr0: u64,
     pub cr2: u64,
     pub cr3: u64,
     pub cr4: u64,
     pub cr8: u64,
+    pub efer: u64,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -552,113 +608,3 @@ pub struct FlagsRegister {
     pub eflags: u32,
     pub flags: u64,
 }
-
-impl FlagsRegister {
-    pub fn is_carry(&self) -> bool {
-        self.flags & 1 == 1
-    }
-
-    pub fn carry(&mut self) -> &mut Self {
-        self.flags |= 1;
-        self
-    }
-
-    pub fn no_carry(&mut self) -> &mut Self {
-        self.flags &= !1;
-        self
-    }
-
-    pub fn is_parity(&self) -> bool {
-        self.flags & 4 == 4
-    }
-
-    pub fn parity(&mut self) -> &mut Self {
-        self.flags |= 4;
-        self
-    }
-
-    pub fn no_parity(&mut self) -> &mut Self {
-        self.flags &= !4;
-        self
-    }
-
-    pub fn is_adjust(&self) -> bool {
-        self.flags & 16 == 16
-    }
-
-    pub fn adjust(&mut self) -> &mut Self {
-        self.flags |= 16;
-        self
-    }
-
-    pub fn is_zero(&self) -> bool {
-        self.flags & 64 == 64
-    }
-
-    pub fn zero(&mut self) -> &mut Self {
-        self.flags |= 64;
-        self
-    }
-
-    pub fn is_sign(&self) -> bool {
-        self.flags & 128 == 128
-    }
-
-    pub fn sign(&mut self) -> &mut Self {
-        self.flags |= 128;
-        self
-    }
-
-    pub fn is_trap(&self) -> bool {
-        self.flags & 256 == 256
-    }
-
-    pub fn trap(&mut self) -> &mut Self {
-        self.flags |= 256;
-        self
-    }
-
-    pub fn is_interrupt(&self) -> bool {
-        self.flags & 512 == 512
-    }
-
-    pub fn interrupt(&mut self) -> &mut Self {
-        self.flags |= 512;
-        self
-    }
-
-    pub fn no_interrupt(&mut self) -> &mut Self {
-        self.flags &= !512;
-        self
-    }
-
-    pub fn is_direction(&self) -> bool {
-        self.flags & 1024 == 1024
-    }
-
-    pub fn direction(&mut self) -> &mut Self {
-        self.flags |= 1024;
-        self
-    }
-
-    pub fn is_overflow(&self) -> bool {
-        self.flags & 2048 == 2048
-    }
-
-    pub fn overflow(&mut self) -> &mut Self {
-        self.flags |= 2048;
-        self
-    }
-
-    pub fn is_iopl(&self) -> bool {
-        self.flags & 12288 == 12288
-    }
-
-    pub fn iopl(&mut self) -> &mut Self {
-        self.flags |= 12288;
-        self
-    }
-
-
-}
-
@@ -1,120 +1,158 @@
-use lazy_static::lazy_static;
-
-const GDT_SIZE: usize = 8;
-
+// access byte in the low 8 bits, G/DB/L/AVL in bits 12-15
+pub const ACCESSED: u16 = 1 << 0;
+pub const READ_WRITE: u16 = 1 << 1;
+pub const CONFORMING_EXPAND_DOWN: u16 = 1 << 2;
+pub const EXECUTABLE: u16 = 1 << 3;
+pub const CODE_DATA: u16 = 1 << 4;
+pub const PRESENT: u16 = 1 << 7;
+pub const LONG: u16 = 1 << 13;
+pub const DEFAULT_BIG: u16 = 1 << 14;
+pub const GRANULARITY: u16 = 1 << 15;
+
+// system descriptor types
+pub const TSS_AVAILABLE: u16 = 0x9;
+pub const TSS_BUSY: u16 = 0xB;
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct SegmentRegister {
-    selector: u16,
-    pub(crate) base: u32,
-    limit: u32,
-    flags: u16,
+    pub(crate) selector: u16,
+    pub(crate) base: u64,
+    pub(crate) limit: u32,
+    pub(crate) flags: u16,
 }
 
+impl SegmentRegister {
+    // real mode (and virtual 8086) loads only touch the selector and base,
+    // the cached limit and attributes are kept
+    pub fn load_real(&mut self, selector: u16) {
+        self.selector = selector;
+        self.base = (selector as u64) << 4;
+    }
+
+    pub fn load_descriptor(&mut self, selector: u16, desc: SegmentDescriptor) {
+        self.selector = selector;
+        self.base = desc.base as u64;
+        self.limit = desc.limit;
+        self.flags = desc.flags;
+    }
+
+    pub fn is_big(&self) -> bool {
+        self.flags & DEFAULT_BIG != 0
+    }
 
+    pub fn is_long(&self) -> bool {
+        self.flags & LONG != 0
+    }
 
+    pub fn is_code(&self) -> bool {
+        self.flags & EXECUTABLE != 0
+    }
 
-lazy_static! {
-    pub static ref CS: SegmentRegister = SegmentRegister {
-        selector: 0x08,
-        base: 0,
-        limit: 0,
-        flags: 0x9A
-    };
-
-    pub static ref DS: SegmentRegister = SegmentRegister {
-        selector: 0x10,
-        base: 0,
-        limit: 0,
-        flags: 0x92
-    };
-
-    pub static ref SS: SegmentRegister = SegmentRegister {
-        selector: 0x18,
-        base: 0,
-        limit: 0,
-        flags: 0x92
-    };
-
-    pub static ref ES: SegmentRegister = SegmentRegister {
-        selector: 0x20,
-        base: 0,
-        limit: 0,
-        flags: 0x92
-    };
-
-    pub static ref FS: SegmentRegister = SegmentRegister {
-        selector: 0x28,
-        base: 0,
-        limit: 0,
-        flags: 0x92
-    };
-
-    pub static ref GS: SegmentRegister = SegmentRegister {
-        selector: 0x30,
-        base: 0,
-        limit: 0,
-        flags: 0x92
-    };
-
-    pub static ref TR: SegmentRegister = SegmentRegister {
-        selector: 0x38,
-        base: 0,
-        limit: 0,
-        flags: 0x82
-    };
-
-    pub static ref LDTR: SegmentRegister = SegmentRegister {
-        selector: 0x40,
-        base: 0,
-        limit: 0,
-        flags: 0x82
-    };
+    pub fn dpl(&self) -> u8 {
+        ((self.flags >> 5) & 3) as u8
+    }
+}
 
+// the hidden part of ES, CS, SS, DS, FS and GS, in encoding order
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentCache {
+    segments: [SegmentRegister; 6],
+}
 
+impl Default for SegmentCache {
+    fn default() -> Self {
+        // power-on state: base 0, 64K limit, present read/write data, and CS
+        // executable so the far jump after setting PE can still be fetched
+        let seg = SegmentRegister {
+            selector: 0,
+            base: 0,
+            limit: 0xFFFF,
+            flags: PRESENT | CODE_DATA | READ_WRITE | ACCESSED,
+        };
+        let mut segments = [seg; 6];
+        segments[Self::index(iced_x86::Register::CS)].flags |= EXECUTABLE;
+        Self { segments }
+    }
 }
 
-impl SegmentRegister {
-    pub fn from_register(register: iced_x86::Register) -> &'static SegmentRegister {
+impl SegmentCache {
+    fn index(register: iced_x86::Register) -> usize {
         match register {
-            iced_x86::Register::CS => &*CS,
-            iced_x86::Register::DS => &*DS,
-            iced_x86::Register::SS => &*SS,
-            iced_x86::Register::ES => &*ES,
-            iced_x86::Register::FS => &*FS,
-            iced_x86::Register::GS => &*GS,
-            _ => &*CS
+            iced_x86::Register::ES => 0,
+            iced_x86::Register::CS => 1,
+            iced_x86::Register::SS => 2,
+            iced_x86::Register::DS => 3,
+            iced_x86::Register::FS => 4,
+            iced_x86::Register::GS => 5,
+            _ => 3
         }
     }
+
+    pub fn get(&self, register: iced_x86::Register) -> &SegmentRegister {
+        &self.segments[Self::index(register)]
+    }
+
+    pub fn get_mut(&mut self, register: iced_x86::Register) -> &mut SegmentRegister {
+        &mut self.segments[Self::index(register)]
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct SegmentDescriptor {
-    base: u32,
-    limit: u32,
-    flags: u16,
+    pub(crate) base: u32,
+    pub(crate) limit: u32,
+    pub(crate) flags: u16,
 }
 
-pub struct GDT {
-    pub gdt: [SegmentDescriptor; GDT_SIZE]
+impl SegmentDescriptor {
+    pub fn from_raw(raw: u64) -> Self {
+        let flags = ((raw >> 40) & 0xF0FF) as u16;
+        let mut limit = (raw & 0xFFFF) as u32 | ((raw >> 32) & 0xF_0000) as u32;
+        if flags & GRANULARITY != 0 {
+            limit = limit << 12 | 0xFFF;
+        }
+        let base = ((raw >> 16) & 0xFF_FFFF) as u32 | ((raw >> 32) & 0xFF00_0000) as u32;
+
+        Self { base, limit, flags }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.flags & PRESENT != 0
+    }
+
+    pub fn dpl(&self) -> u8 {
+        ((self.flags >> 5) & 3) as u8
+    }
+}
+
+// GDTR: the descriptors themselves live in guest memory
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Gdt {
+    pub base: u64,
+    pub limit: u16,
 }
 
-impl GDT {
-    pub fn new(gdt: [SegmentDescriptor; GDT_SIZE]) -> Self {
+impl Gdt {
+    pub fn new(base: u64, limit: u16) -> Self {
         Self {
-            gdt
+            base,
+            limit
         }
     }
 
-    pub fn load_segment(&mut self, selector: u16, seg: &mut SegmentRegister) {
-        let index = (selector >> 3) as usize;
-
-        if index < self.gdt.len() {
-            let desc = self.gdt[index];
-            seg.selector = selector;
-            seg.base = desc.base;
-            seg.limit = desc.limit;
-            seg.flags = desc.flags;
+    pub fn descriptor_address(&self, selector: u16) -> Option<u64> {
+        let offset = (selector & !7) as u64;
+        if offset + 7 > self.limit as u64 {
+            return None;
         }
+        Some(self.base + offset)
     }
 }
+
+// TR: selector plus the cached base and limit of the current TSS
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TaskRegister {
+    pub(crate) selector: u16,
+    pub(crate) base: u64,
+    pub(crate) limit: u32,
+}
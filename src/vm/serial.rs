@@ -0,0 +1,521 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::rc::Rc;
+use crate::ast::Bits;
+use crate::vm::io::PortDevice;
+use crate::vm::pic::Pic;
+
+// base port and IRQ of COM1-COM4
+pub const COM_PORTS: [(u16, u8); 4] = [(0x3F8, 4), (0x2F8, 3), (0x3E8, 4), (0x2E8, 3)];
+pub const UART_PORTS: u16 = 8;
+
+const FIFO_SIZE: usize = 16;
+// the divisor divides this down to the baud rate
+const UART_CLOCK: u64 = 115_200;
+
+// register offsets
+const DATA: u16 = 0;
+const IER: u16 = 1;
+const IIR_FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const MSR: u16 = 6;
+const SCR: u16 = 7;
+
+const IER_RX: u8 = 1 << 0;
+const IER_THR: u8 = 1 << 1;
+const IER_LINE: u8 = 1 << 2;
+const IER_MODEM: u8 = 1 << 3;
+
+// interrupt identification, in priority order
+const IIR_NONE: u8 = 0x01;
+const IIR_LINE: u8 = 0x06;
+const IIR_RX: u8 = 0x04;
+const IIR_TIMEOUT: u8 = 0x0C;
+const IIR_THR: u8 = 0x02;
+const IIR_MODEM: u8 = 0x00;
+const IIR_FIFO: u8 = 0xC0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+// OUT2 gates the interrupt line onto the bus on a PC
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TX_EMPTY: u8 = 1 << 6;
+
+const MSR_DELTA: u8 = 0x0F;
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+
+// where the other end of a serial line goes; reads never block
+pub trait SerialBackend {
+    fn write(&mut self, byte: u8);
+    fn read(&mut self) -> Option<u8>;
+    fn connected(&mut self) -> bool {
+        true
+    }
+    fn uses_stdout(&self) -> bool {
+        false
+    }
+}
+
+// an unplugged port: output vanishes, nothing comes in
+struct Disconnected;
+
+impl SerialBackend for Disconnected {
+    fn write(&mut self, _byte: u8) {}
+
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn connected(&mut self) -> bool {
+        false
+    }
+}
+
+fn set_nonblocking(fd: i32) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+}
+
+fn read_byte(reader: &mut impl Read) -> Option<u8> {
+    let mut byte = [0];
+    match reader.read(&mut byte) {
+        Ok(1) => Some(byte[0]),
+        _ => None
+    }
+}
+
+// stdin is polled rather than made non-blocking: on a terminal it shares
+// its file description with stdout, which would then fail with EAGAIN
+struct Stdio;
+
+impl SerialBackend for Stdio {
+    // a terminal that cannot keep up loses the byte rather than stalling
+    fn write(&mut self, byte: u8) {
+        let mut stdout = std::io::stdout().lock();
+        match stdout.write_all(&[byte]).and_then(|_| stdout.flush()) {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => eprintln!("serial output: {}", e),
+            _ => {}
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let mut fd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut fd, 1, 0) } != 1 || fd.revents & libc::POLLIN == 0 {
+            return None;
+        }
+        let mut byte = 0u8;
+        match unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+            1 => Some(byte),
+            _ => None
+        }
+    }
+
+    fn uses_stdout(&self) -> bool {
+        true
+    }
+}
+
+struct LogFile(File);
+
+impl SerialBackend for LogFile {
+    fn write(&mut self, byte: u8) {
+        self.0.write_all(&[byte]).unwrap();
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+// the master side of a pseudo-terminal, the slave is for the user to open
+struct Pty(File);
+
+impl Pty {
+    fn new() -> Result<Self, String> {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 || libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err("cannot allocate a pty".to_string());
+            }
+            let file = File::from(OwnedFd::from_raw_fd(fd));
+            let mut termios = std::mem::zeroed();
+            libc::tcgetattr(fd, &mut termios);
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(fd, libc::TCSANOW, &termios);
+            set_nonblocking(fd);
+            let name = std::ffi::CStr::from_ptr(libc::ptsname(fd)).to_string_lossy().into_owned();
+            eprintln!("serial port on {}", name);
+            Ok(Self(file))
+        }
+    }
+}
+
+impl SerialBackend for Pty {
+    // with nobody on the slave side output is dropped once the buffer fills
+    fn write(&mut self, byte: u8) {
+        let _ = self.0.write(&[byte]);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        read_byte(&mut self.0)
+    }
+}
+
+// one client at a time, a new one can connect after the last one left
+struct Socket {
+    listener: UnixListener,
+    client: Option<UnixStream>,
+}
+
+impl Socket {
+    fn new(path: &str) -> Result<Self, String> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).map_err(|e| format!("{}: {}", path, e))?;
+        listener.set_nonblocking(true).unwrap();
+        Ok(Self { listener, client: None })
+    }
+
+    fn client(&mut self) -> Option<&mut UnixStream> {
+        if self.client.is_none() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                stream.set_nonblocking(true).unwrap();
+                self.client = Some(stream);
+            }
+        }
+        self.client.as_mut()
+    }
+}
+
+impl SerialBackend for Socket {
+    fn write(&mut self, byte: u8) {
+        if let Some(client) = self.client() {
+            if let Err(e) = client.write(&[byte]) {
+                if e.kind() != ErrorKind::WouldBlock {
+                    self.client = None;
+                }
+            }
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let client = self.client()?;
+        let mut byte = [0];
+        match client.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            _ => {
+                self.client = None;
+                None
+            }
+        }
+    }
+
+    fn connected(&mut self) -> bool {
+        self.client().is_some()
+    }
+}
+
+// stdio, file:<path>, pty or unix:<path>
+pub fn open_backend(spec: &str) -> Result<Box<dyn SerialBackend>, String> {
+    match spec.split_once(':') {
+        Some(("file", path)) => File::create(path)
+            .map(|file| Box::new(LogFile(file)) as Box<dyn SerialBackend>)
+            .map_err(|e| format!("{}: {}", path, e)),
+        Some(("unix", path)) => Ok(Box::new(Socket::new(path)?)),
+        None if spec == "stdio" => Ok(Box::new(Stdio)),
+        None if spec == "pty" => Ok(Box::new(Pty::new()?)),
+        None if spec == "none" => Ok(Box::new(Disconnected)),
+        _ => Err(format!("unknown serial backend {}", spec))
+    }
+}
+
+// a 16550A; transmission is instantaneous, reception is paced at the
+// programmed baud rate
+pub struct Uart {
+    base: u16,
+    irq: u8,
+    pic: Rc<RefCell<Pic>>,
+    backend: Box<dyn SerialBackend>,
+    rx: VecDeque<u8>,
+    divisor: u16,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    msr: u8,
+    scratch: u8,
+    fifo_enabled: bool,
+    rx_trigger: usize,
+    thr_interrupt: bool,
+    timeout: bool,
+    irq_level: bool,
+    // emulated time until the next character can arrive, and since the
+    // receive FIFO last moved
+    rx_ns: u64,
+    idle_ns: u64,
+}
+
+impl Uart {
+    pub fn new(base: u16, irq: u8, pic: Rc<RefCell<Pic>>) -> Self {
+        let mut uart = Self {
+            base,
+            irq,
+            pic,
+            backend: Box::new(Disconnected),
+            rx: VecDeque::new(),
+            divisor: 12,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: 0,
+            msr: 0,
+            scratch: 0,
+            fifo_enabled: false,
+            rx_trigger: 1,
+            thr_interrupt: false,
+            timeout: false,
+            irq_level: false,
+            rx_ns: 0,
+            idle_ns: 0,
+        };
+        uart.reset();
+        uart
+    }
+
+    pub fn attach(&mut self, backend: Box<dyn SerialBackend>) {
+        self.backend = backend;
+        self.update_modem_status();
+    }
+
+    // the master reset line: registers go back, the backend stays attached
+    pub fn reset(&mut self) {
+        self.rx.clear();
+        self.ier = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.lsr = LSR_THR_EMPTY | LSR_TX_EMPTY;
+        self.msr = 0;
+        self.fifo_enabled = false;
+        self.rx_trigger = 1;
+        self.thr_interrupt = false;
+        self.timeout = false;
+        self.update_modem_status();
+        self.msr &= !MSR_DELTA;
+        self.update_irq();
+    }
+
+    // a start bit, eight data bits and a stop bit
+    fn character_ns(&self) -> u64 {
+        10 * 1_000_000_000 * self.divisor.max(1) as u64 / UART_CLOCK
+    }
+
+    fn capacity(&self) -> usize {
+        if self.fifo_enabled { FIFO_SIZE } else { 1 }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() >= self.capacity() {
+            self.lsr |= LSR_OVERRUN;
+        } else {
+            self.rx.push_back(byte);
+        }
+        self.lsr |= LSR_DATA_READY;
+        self.idle_ns = 0;
+        self.timeout = false;
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOPBACK != 0 {
+            self.receive(byte);
+        } else {
+            self.backend.write(byte);
+        }
+        self.thr_interrupt = true;
+    }
+
+    // in loopback the modem outputs feed the modem inputs
+    fn update_modem_status(&mut self) {
+        let status = if self.mcr & MCR_LOOPBACK != 0 {
+            let mut status = 0;
+            if self.mcr & MCR_RTS != 0 { status |= MSR_CTS; }
+            if self.mcr & MCR_DTR != 0 { status |= MSR_DSR; }
+            if self.mcr & MCR_OUT1 != 0 { status |= MSR_RI; }
+            if self.mcr & MCR_OUT2 != 0 { status |= MSR_DCD; }
+            status
+        } else if self.backend.connected() {
+            MSR_CTS | MSR_DSR | MSR_DCD
+        } else {
+            0
+        };
+        let changed = (status ^ self.msr) & 0xF0;
+        let mut delta = self.msr & MSR_DELTA;
+        // CTS, DSR and DCD report any change, RI only its trailing edge
+        delta |= (changed & (MSR_CTS | MSR_DSR | MSR_DCD)) >> 4;
+        if self.msr & MSR_RI != 0 && status & MSR_RI == 0 {
+            delta |= MSR_RI >> 4;
+        }
+        self.msr = status | delta;
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_LINE != 0 && self.lsr & LSR_OVERRUN != 0 {
+            IIR_LINE
+        } else if self.ier & IER_RX != 0 && self.rx.len() >= self.rx_trigger {
+            IIR_RX
+        } else if self.ier & IER_RX != 0 && self.timeout {
+            IIR_TIMEOUT
+        } else if self.ier & IER_THR != 0 && self.thr_interrupt {
+            IIR_THR
+        } else if self.ier & IER_MODEM != 0 && self.msr & MSR_DELTA != 0 {
+            IIR_MODEM
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn update_irq(&mut self) {
+        let level = self.interrupt_id() != IIR_NONE && self.mcr & MCR_OUT2 != 0;
+        if level != self.irq_level {
+            self.irq_level = level;
+            self.pic.borrow_mut().set_irq(self.irq, level);
+        }
+    }
+
+    pub fn advance(&mut self, ns: u64) {
+        let character = self.character_ns();
+        self.rx_ns += ns;
+        if self.rx_ns >= character {
+            self.rx_ns = 0;
+            if self.mcr & MCR_LOOPBACK == 0 {
+                if self.rx.len() < self.capacity() {
+                    if let Some(byte) = self.backend.read() {
+                        self.receive(byte);
+                    }
+                }
+                self.update_modem_status();
+            }
+        }
+        // data below the trigger level times out after four characters
+        if self.fifo_enabled && !self.rx.is_empty() && !self.timeout {
+            self.idle_ns += ns;
+            if self.idle_ns >= 4 * character {
+                self.timeout = true;
+            }
+        }
+        self.update_irq();
+    }
+
+    fn read_register(&mut self, offset: u16) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            DATA if dlab => self.divisor as u8,
+            DATA => {
+                let byte = self.rx.pop_front().unwrap_or(0);
+                if self.rx.is_empty() {
+                    self.lsr &= !LSR_DATA_READY;
+                }
+                self.idle_ns = 0;
+                self.timeout = false;
+                byte
+            },
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                // reading the identification acknowledges THR empty
+                if id == IIR_THR {
+                    self.thr_interrupt = false;
+                }
+                id | if self.fifo_enabled { IIR_FIFO } else { 0 }
+            },
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let lsr = self.lsr;
+                self.lsr &= !LSR_OVERRUN;
+                lsr
+            },
+            MSR => {
+                let msr = self.msr;
+                self.msr &= !MSR_DELTA;
+                msr
+            },
+            _ => self.scratch
+        }
+    }
+
+    fn write_register(&mut self, offset: u16, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            DATA if dlab => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            DATA => self.transmit(value),
+            IER if dlab => self.divisor = (self.divisor & 0x00FF) | (value as u16) << 8,
+            IER => {
+                // enabling the THR interrupt with the holding register empty
+                // fires it straight away
+                if value & IER_THR != 0 && self.ier & IER_THR == 0 {
+                    self.thr_interrupt = true;
+                }
+                self.ier = value & 0x0F;
+            },
+            IIR_FCR => {
+                let enabled = value & FCR_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 || enabled != self.fifo_enabled {
+                    self.rx.clear();
+                    self.lsr &= !LSR_DATA_READY;
+                    self.timeout = false;
+                }
+                if value & FCR_CLEAR_TX != 0 {
+                    self.thr_interrupt = self.ier & IER_THR != 0;
+                }
+                self.fifo_enabled = enabled;
+                self.rx_trigger = if enabled { [1, 4, 8, 14][(value >> 6) as usize] } else { 1 };
+            },
+            LCR => self.lcr = value,
+            MCR => {
+                self.mcr = value & 0x1F;
+                self.update_modem_status();
+            },
+            // the line and modem status registers are read-only here
+            LSR | MSR => {},
+            SCR => self.scratch = value,
+            _ => {}
+        }
+    }
+}
+
+impl PortDevice for Uart {
+    fn read(&mut self, port: u16, _bits: Bits) -> u64 {
+        let value = self.read_register(port - self.base);
+        self.update_irq();
+        value as u64
+    }
+
+    fn write(&mut self, port: u16, value: u64, _bits: Bits) {
+        self.write_register(port - self.base, value as u8);
+        self.update_irq();
+    }
+}
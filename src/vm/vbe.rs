@@ -0,0 +1,217 @@
+use crate::ast::Bits;
+use crate::vm::io::PortDevice;
+use crate::vm::mem::MmioDevice;
+
+// the Bochs VBE extensions ("DISPI"): an index port and a 16 bit data port
+pub const DISPI_INDEX: u16 = 0x1CE;
+pub const DISPI_DATA: u16 = 0x1CF;
+
+// the linear framebuffer sits high in the 32 bit physical space, where
+// the Bochs VGA puts it
+pub const LFB_BASE: usize = 0xE000_0000;
+pub const VIDEO_MEMORY: usize = 0x100_0000;
+pub const MAX_XRES: u16 = 1920;
+pub const MAX_YRES: u16 = 1200;
+pub const MAX_BPP: u16 = 32;
+
+// DISPI registers
+pub const DISPI_ID: u16 = 0x00;
+pub const DISPI_XRES: u16 = 0x01;
+pub const DISPI_YRES: u16 = 0x02;
+pub const DISPI_BPP: u16 = 0x03;
+pub const DISPI_ENABLE: u16 = 0x04;
+const DISPI_BANK: u16 = 0x05;
+pub const DISPI_VIRT_WIDTH: u16 = 0x06;
+pub const DISPI_VIRT_HEIGHT: u16 = 0x07;
+pub const DISPI_X_OFFSET: u16 = 0x08;
+pub const DISPI_Y_OFFSET: u16 = 0x09;
+const DISPI_VIDEO_MEMORY_64K: u16 = 0x0A;
+
+// the interface versions a guest may ask for, the last is what we are
+const DISPI_ID0: u16 = 0xB0C0;
+const DISPI_ID5: u16 = 0xB0C5;
+
+// enable register bits
+pub const DISPI_ENABLED: u16 = 0x01;
+// while set XRES, YRES and BPP read back their maximums
+pub const DISPI_GETCAPS: u16 = 0x02;
+pub const DISPI_LFB_ENABLED: u16 = 0x40;
+pub const DISPI_NOCLEARMEM: u16 = 0x80;
+
+pub struct Vbe {
+    index: u16,
+    id: u16,
+    xres: u16,
+    yres: u16,
+    bpp: u16,
+    enable: u16,
+    bank: u16,
+    virt_width: u16,
+    virt_height: u16,
+    x_offset: u16,
+    y_offset: u16,
+    memory: Vec<u8>,
+    framebuffer: Vec<u32>,
+}
+
+impl Vbe {
+    pub fn new() -> Self {
+        Self {
+            index: 0,
+            id: DISPI_ID0,
+            xres: 640,
+            yres: 480,
+            bpp: 8,
+            enable: 0,
+            bank: 0,
+            virt_width: 640,
+            virt_height: 480,
+            x_offset: 0,
+            y_offset: 0,
+            memory: vec![0; VIDEO_MEMORY],
+            framebuffer: Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enable & DISPI_ENABLED != 0
+    }
+
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.xres as usize, self.yres as usize)
+    }
+
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        (self.bpp as usize).div_ceil(8)
+    }
+
+    fn pitch(&self) -> usize {
+        self.virt_width as usize * self.bytes_per_pixel()
+    }
+
+    // a new virtual width leaves as many lines as the memory holds
+    fn set_virtual_width(&mut self, width: u16) {
+        self.virt_width = width.max(self.xres);
+        let lines = VIDEO_MEMORY / self.pitch();
+        self.virt_height = lines.min(u16::MAX as usize) as u16;
+    }
+
+    fn valid_mode(&self) -> bool {
+        self.xres != 0 && self.yres != 0 && self.xres <= MAX_XRES && self.yres <= MAX_YRES
+            && matches!(self.bpp, 8 | 15 | 16 | 24 | 32)
+            && self.xres as usize * self.yres as usize * self.bytes_per_pixel() <= VIDEO_MEMORY
+    }
+
+    fn set_enable(&mut self, value: u16) {
+        if value & DISPI_ENABLED != 0 && !self.is_enabled() {
+            if !self.valid_mode() {
+                return;
+            }
+            self.set_virtual_width(self.xres);
+            self.x_offset = 0;
+            self.y_offset = 0;
+            self.bank = 0;
+            if value & DISPI_NOCLEARMEM == 0 {
+                let size = self.yres as usize * self.pitch();
+                self.memory[..size].fill(0);
+            }
+        }
+        self.enable = value;
+    }
+
+    // panned by the offsets into the virtual screen; 8 bpp goes through
+    // the VGA DAC
+    pub fn render(&mut self, palette: &[u32]) {
+        let (width, height) = self.resolution();
+        self.framebuffer.resize(width * height, 0);
+        let bytes = self.bytes_per_pixel();
+        let pitch = self.pitch();
+        let start = self.y_offset as usize * pitch + self.x_offset as usize * bytes;
+        for y in 0..height {
+            let line = start + y * pitch;
+            for x in 0..width {
+                let addr = line + x * bytes;
+                let Some(pixel) = self.memory.get(addr..addr + bytes) else {
+                    self.framebuffer[y * width + x] = 0xFF00_0000;
+                    continue;
+                };
+                self.framebuffer[y * width + x] = match self.bpp {
+                    8 => palette[pixel[0] as usize],
+                    15 | 16 => {
+                        let value = u16::from_le_bytes([pixel[0], pixel[1]]) as u32;
+                        // 5:5:5 or 5:6:5, each widened to 8 bits
+                        let (r, g) = if self.bpp == 15 {
+                            ((value >> 10) & 0x1F, ((value >> 5) & 0x1F) << 1 | (value >> 9) & 1)
+                        } else {
+                            ((value >> 11) & 0x1F, (value >> 5) & 0x3F)
+                        };
+                        let b = value & 0x1F;
+                        0xFF00_0000 | (r << 3 | r >> 2) << 16 | (g << 2 | g >> 4) << 8 | (b << 3 | b >> 2)
+                    },
+                    _ => 0xFF00_0000 | (pixel[2] as u32) << 16 | (pixel[1] as u32) << 8 | pixel[0] as u32
+                };
+            }
+        }
+    }
+}
+
+impl PortDevice for Vbe {
+    fn read(&mut self, port: u16, _bits: Bits) -> u64 {
+        if port == DISPI_INDEX {
+            return self.index as u64;
+        }
+        let caps = self.enable & DISPI_GETCAPS != 0;
+        let value = match self.index {
+            DISPI_ID => self.id,
+            DISPI_XRES => if caps { MAX_XRES } else { self.xres },
+            DISPI_YRES => if caps { MAX_YRES } else { self.yres },
+            DISPI_BPP => if caps { MAX_BPP } else { self.bpp },
+            DISPI_ENABLE => self.enable,
+            DISPI_BANK => self.bank,
+            DISPI_VIRT_WIDTH => self.virt_width,
+            DISPI_VIRT_HEIGHT => self.virt_height,
+            DISPI_X_OFFSET => self.x_offset,
+            DISPI_Y_OFFSET => self.y_offset,
+            DISPI_VIDEO_MEMORY_64K => (VIDEO_MEMORY >> 16) as u16,
+            _ => 0
+        };
+        value as u64
+    }
+
+    // the geometry only changes while the display is disabled
+    fn write(&mut self, port: u16, value: u64, _bits: Bits) {
+        if port == DISPI_INDEX {
+            self.index = value as u16;
+            return;
+        }
+        let value = value as u16;
+        let enabled = self.is_enabled();
+        match self.index {
+            DISPI_ID if (DISPI_ID0..=DISPI_ID5).contains(&value) => self.id = value,
+            DISPI_XRES if !enabled => self.xres = value,
+            DISPI_YRES if !enabled => self.yres = value,
+            // 0 is taken to mean 8
+            DISPI_BPP if !enabled => self.bpp = if value == 0 { 8 } else { value },
+            DISPI_ENABLE => self.set_enable(value),
+            DISPI_BANK => self.bank = value,
+            DISPI_VIRT_WIDTH if enabled => self.set_virtual_width(value),
+            DISPI_X_OFFSET => self.x_offset = value,
+            DISPI_Y_OFFSET => self.y_offset = value,
+            _ => {}
+        }
+    }
+}
+
+impl MmioDevice for Vbe {
+    fn read(&mut self, offset: usize) -> u8 {
+        self.memory[offset]
+    }
+
+    fn write(&mut self, offset: usize, value: u8) {
+        self.memory[offset] = value;
+    }
+}
@@ -0,0 +1,598 @@
+use std::time::Instant;
+use crate::ast::Bits;
+use crate::vm::io::PortDevice;
+use crate::vm::mem::MmioDevice;
+
+// the legacy VGA window at A0000-BFFFF
+pub const VGA_BASE: usize = 0xA0000;
+pub const VGA_SIZE: usize = 0x20000;
+// four planes of 64K behind the window
+const PLANES: usize = 4;
+const PLANE_SIZE: usize = 0x10000;
+
+// 0x3C0-0x3CF: attribute controller, misc output, sequencer, DAC, graphics controller
+pub const VGA_PORTS: u16 = 0x3C0;
+pub const VGA_PORT_COUNT: u16 = 0x10;
+pub const ATTRIBUTE_INDEX: u16 = 0x3C0;
+pub const ATTRIBUTE_READ: u16 = 0x3C1;
+pub const MISC_OUTPUT_WRITE: u16 = 0x3C2;
+pub const SEQUENCER_INDEX: u16 = 0x3C4;
+const SEQUENCER_DATA: u16 = 0x3C5;
+pub const PEL_MASK: u16 = 0x3C6;
+pub const DAC_READ_INDEX: u16 = 0x3C7;
+pub const DAC_WRITE_INDEX: u16 = 0x3C8;
+pub const DAC_DATA: u16 = 0x3C9;
+const FEATURE_CONTROL_READ: u16 = 0x3CA;
+const MISC_OUTPUT_READ: u16 = 0x3CC;
+pub const GRAPHICS_INDEX: u16 = 0x3CE;
+const GRAPHICS_DATA: u16 = 0x3CF;
+pub const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+pub const INPUT_STATUS_1: u16 = 0x3DA;
+
+// sequencer registers
+const CLOCKING_MODE: usize = 0x01;
+const MAP_MASK: usize = 0x02;
+const CHARACTER_MAP_SELECT: usize = 0x03;
+const MEMORY_MODE: usize = 0x04;
+const SEQUENCER_REGISTERS: usize = 0x05;
+const SCREEN_OFF: u8 = 1 << 5;
+// cleared, even addresses go to planes 0 and 2 and odd ones to 1 and 3
+const ODD_EVEN_DISABLE: u8 = 1 << 2;
+// the low two address bits pick the plane
+const CHAIN_4: u8 = 1 << 3;
+
+// graphics controller registers
+const SET_RESET: usize = 0x00;
+const ENABLE_SET_RESET: usize = 0x01;
+const COLOR_COMPARE: usize = 0x02;
+const DATA_ROTATE: usize = 0x03;
+const READ_MAP_SELECT: usize = 0x04;
+const GRAPHICS_MODE: usize = 0x05;
+const MISCELLANEOUS: usize = 0x06;
+const COLOR_DONT_CARE: usize = 0x07;
+const BIT_MASK: usize = 0x08;
+const GRAPHICS_REGISTERS: usize = 0x09;
+const READ_MODE_COMPARE: u8 = 1 << 3;
+const HOST_ODD_EVEN: u8 = 1 << 4;
+// CGA style 2 bits per pixel, and 8 bits per pixel for 256 colors
+const SHIFT_INTERLEAVE: u8 = 1 << 5;
+const SHIFT_256: u8 = 1 << 6;
+const GRAPHICS_ENABLE: u8 = 1 << 0;
+
+// attribute controller registers, the first 16 are the palette
+const MODE_CONTROL: usize = 0x10;
+const COLOR_PLANE_ENABLE: usize = 0x12;
+const COLOR_SELECT: usize = 0x14;
+const ATTRIBUTE_REGISTERS: usize = 0x15;
+// cleared while the palette is being programmed, the screen shows nothing
+const PALETTE_ADDRESS_SOURCE: u8 = 1 << 5;
+const BLINK_ENABLE: u8 = 1 << 3;
+const PALETTE_BITS_5_4: u8 = 1 << 7;
+
+// CRTC registers
+const HORIZONTAL_DISPLAY_END: usize = 0x01;
+const OVERFLOW: usize = 0x07;
+const MAX_SCAN_LINE: usize = 0x09;
+const CURSOR_START: usize = 0x0A;
+const CURSOR_END: usize = 0x0B;
+const START_ADDRESS_HIGH: usize = 0x0C;
+const START_ADDRESS_LOW: usize = 0x0D;
+const CURSOR_LOCATION_HIGH: usize = 0x0E;
+const CURSOR_LOCATION_LOW: usize = 0x0F;
+const VERTICAL_RETRACE_END: usize = 0x11;
+const VERTICAL_DISPLAY_END: usize = 0x12;
+const OFFSET: usize = 0x13;
+const UNDERLINE_LOCATION: usize = 0x14;
+const CRTC_MODE_CONTROL: usize = 0x17;
+const LINE_COMPARE: usize = 0x18;
+const CRTC_REGISTERS: usize = 0x19;
+const CURSOR_DISABLE: u8 = 1 << 5;
+const DOUBLE_SCAN: u8 = 1 << 7;
+// registers 0-7 are read-only while this is set
+const CRTC_PROTECT: u8 = 1 << 7;
+const DWORD_MODE: u8 = 1 << 6;
+const BYTE_MODE: u8 = 1 << 6;
+const ADDRESS_WRAP: u8 = 1 << 5;
+// cleared, the row scan counter replaces address bits 13 and 14 (CGA banks)
+const MAP_13: u8 = 1 << 0;
+const MAP_14: u8 = 1 << 1;
+
+// glyphs sit 32 bytes apart in plane 2, in eight blocks of 256
+const GLYPH_SIZE: usize = 32;
+pub const FONT_BLOCKS: usize = 8;
+
+// attribute byte: foreground in the low nibble, background in bits 4-6,
+// bit 3 also picks font A over font B
+const ATTRIBUTE_BLINK: u8 = 1 << 7;
+const ATTRIBUTE_FONT_A: u8 = 1 << 3;
+
+pub const DAC_ENTRIES: usize = 256;
+
+// vertical refresh, blinking counts frames of it; a line takes about 32us
+const REFRESH_HZ: u128 = 70;
+const FRAME_US: u128 = 1_000_000 / REFRESH_HZ;
+const LINE_US: u128 = 32;
+const DISPLAY_DISABLED: u8 = 1 << 0;
+const VERTICAL_RETRACE: u8 = 1 << 3;
+
+pub struct Vga {
+    planes: Vec<Vec<u8>>,
+    latches: [u8; PLANES],
+    misc_output: u8,
+    sequencer_index: u8,
+    sequencer: [u8; SEQUENCER_REGISTERS],
+    graphics_index: u8,
+    graphics: [u8; GRAPHICS_REGISTERS],
+    // 0x3C0 alternates between index and data, reading 0x3DA resets it
+    attribute_index: u8,
+    attribute_data: bool,
+    attribute: [u8; ATTRIBUTE_REGISTERS],
+    crtc_index: u8,
+    crtc: [u8; CRTC_REGISTERS],
+    // 6 bits per component, written and read three bytes at a time
+    dac: Vec<[u8; 3]>,
+    dac_write_index: u8,
+    dac_read_index: u8,
+    dac_reading: bool,
+    dac_component: usize,
+    dac_latch: [u8; 3],
+    pel_mask: u8,
+    framebuffer: Vec<u32>,
+    width: usize,
+    height: usize,
+    epoch: Instant,
+}
+
+impl Vga {
+    // registers come up cleared, the BIOS sets a mode
+    pub fn new() -> Self {
+        Self {
+            planes: vec![vec![0; PLANE_SIZE]; PLANES],
+            latches: [0; PLANES],
+            misc_output: 0,
+            sequencer_index: 0,
+            sequencer: [0; SEQUENCER_REGISTERS],
+            graphics_index: 0,
+            graphics: [0; GRAPHICS_REGISTERS],
+            attribute_index: 0,
+            attribute_data: false,
+            attribute: [0; ATTRIBUTE_REGISTERS],
+            crtc_index: 0,
+            crtc: [0; CRTC_REGISTERS],
+            dac: vec![[0; 3]; DAC_ENTRIES],
+            dac_write_index: 0,
+            dac_read_index: 0,
+            dac_reading: false,
+            dac_component: 0,
+            dac_latch: [0; 3],
+            pel_mask: 0xFF,
+            framebuffer: Vec::new(),
+            width: 0,
+            height: 0,
+            epoch: Instant::now(),
+        }
+    }
+
+    fn register_pair(&self, high: usize, low: usize) -> usize {
+        (self.crtc[high] as usize) << 8 | self.crtc[low] as usize
+    }
+
+    // where the screen starts, in CRTC address units
+    pub fn start_address(&self) -> usize {
+        self.register_pair(START_ADDRESS_HIGH, START_ADDRESS_LOW)
+    }
+
+    pub fn character_height(&self) -> u8 {
+        (self.crtc[MAX_SCAN_LINE] & 0x1F) + 1
+    }
+
+    fn columns(&self) -> usize {
+        self.crtc[HORIZONTAL_DISPLAY_END] as usize + 1
+    }
+
+    // bits 8 and 9 of the vertical counters live in the overflow register
+    // and, for line compare, in the maximum scan line register
+    fn displayed_lines(&self) -> usize {
+        let overflow = self.crtc[OVERFLOW] as usize;
+        let end = self.crtc[VERTICAL_DISPLAY_END] as usize | (overflow & 0x02) << 7 | (overflow & 0x40) << 3;
+        end + 1
+    }
+
+    fn line_compare(&self) -> usize {
+        let overflow = self.crtc[OVERFLOW] as usize;
+        let scan = self.crtc[MAX_SCAN_LINE] as usize;
+        self.crtc[LINE_COMPARE] as usize | (overflow & 0x10) << 4 | (scan & 0x40) << 3
+    }
+
+    // address units from one character row to the next
+    fn row_pitch(&self) -> usize {
+        self.crtc[OFFSET] as usize * 2
+    }
+
+    fn is_graphics(&self) -> bool {
+        self.graphics[MISCELLANEOUS] & GRAPHICS_ENABLE != 0
+    }
+
+    // the CRTC address counter as an offset into the planes: doubled in
+    // word mode, quadrupled in dword mode, and with the row scan counter
+    // standing in for bits 13 and 14 in the CGA compatible modes
+    fn plane_address(&self, counter: usize, scan: usize) -> usize {
+        let mode = self.crtc[CRTC_MODE_CONTROL];
+        let mut address = if self.crtc[UNDERLINE_LOCATION] & DWORD_MODE != 0 {
+            counter << 2
+        } else if mode & BYTE_MODE == 0 {
+            let wrap = if mode & ADDRESS_WRAP != 0 { 15 } else { 13 };
+            counter << 1 | (counter >> wrap) & 1
+        } else {
+            counter
+        };
+        if mode & MAP_13 == 0 {
+            address = address & !(1 << 13) | (scan & 1) << 13;
+        }
+        if mode & MAP_14 == 0 {
+            address = address & !(1 << 14) | (scan & 2) << 13;
+        }
+        address % PLANE_SIZE
+    }
+
+    // the address the cursor sits on and the scan lines it covers
+    fn cursor(&self) -> Option<(usize, usize, usize)> {
+        let start = self.crtc[CURSOR_START];
+        if start & CURSOR_DISABLE != 0 {
+            return None;
+        }
+        let location = self.register_pair(CURSOR_LOCATION_HIGH, CURSOR_LOCATION_LOW);
+        let end = (self.crtc[CURSOR_END] & 0x1F).min(self.character_height() - 1);
+        Some((location, (start & 0x1F) as usize, end as usize))
+    }
+
+    fn frame(&self) -> u128 {
+        self.epoch.elapsed().as_millis() * REFRESH_HZ / 1000
+    }
+
+    // blinking characters flip every 16 frames, the cursor every 8
+    fn blink_visible(&self) -> bool {
+        self.frame() & 16 == 0
+    }
+
+    fn cursor_visible(&self) -> bool {
+        self.frame() & 8 == 0
+    }
+
+    // blocks 0-3 start every 16K of plane 2, blocks 4-7 halfway between them
+    fn font_block(block: usize) -> usize {
+        (block & 3) << 14 | (block & 4) << 11
+    }
+
+    // glyphs of `height` bytes each, from character `first` on
+    pub fn load_font(&mut self, block: usize, first: usize, height: usize, glyphs: &[u8]) {
+        let base = Self::font_block(block % FONT_BLOCKS);
+        for (i, glyph) in glyphs.chunks(height).enumerate() {
+            let offset = base + ((first + i) & 0xFF) * GLYPH_SIZE;
+            self.planes[2][offset..offset + GLYPH_SIZE].fill(0);
+            self.planes[2][offset..offset + glyph.len()].copy_from_slice(glyph);
+        }
+    }
+
+    // the fonts attribute bit 3 chooses between, A when it is set
+    fn character_maps(&self) -> (usize, usize) {
+        let select = self.sequencer[CHARACTER_MAP_SELECT] as usize;
+        let a = (select >> 3) & 4 | (select >> 2) & 3;
+        let b = (select >> 2) & 4 | select & 3;
+        (Self::font_block(a), Self::font_block(b))
+    }
+
+    // a DAC entry, its 6 bit components scaled up to 8 bits
+    fn dac_color(&self, index: u8) -> u32 {
+        let [r, g, b] = self.dac[(index & self.pel_mask) as usize].map(|c| (c << 2 | c >> 4) as u32);
+        0xFF00_0000 | r << 16 | g << 8 | b
+    }
+
+    // a 4 bit pixel or text color through the attribute palette to the DAC
+    fn palette_color(&self, index: u8) -> u32 {
+        let index = index & self.attribute[COLOR_PLANE_ENABLE] & 0x0F;
+        let select = self.attribute[COLOR_SELECT];
+        let mut dac = self.attribute[index as usize] & 0x3F;
+        if self.attribute[MODE_CONTROL] & PALETTE_BITS_5_4 != 0 {
+            dac = dac & 0x0F | (select & 0x03) << 4;
+        }
+        self.dac_color(dac | (select & 0x0C) << 4)
+    }
+
+    // draws the displayed part of video memory, one scan line at a time
+    pub fn render(&mut self) {
+        let columns = self.columns();
+        let double_scan = self.crtc[MAX_SCAN_LINE] & DOUBLE_SCAN != 0;
+        let height = self.displayed_lines() / if double_scan { 2 } else { 1 };
+        let graphics = self.is_graphics();
+        let mode = self.graphics[GRAPHICS_MODE];
+        let dots = if graphics && mode & SHIFT_256 != 0 { 4 } else { 8 };
+        let width = columns * dots;
+        if (width, height) != (self.width, self.height) {
+            self.width = width;
+            self.height = height;
+            self.framebuffer = vec![0; width * height];
+        }
+        if self.sequencer[CLOCKING_MODE] & SCREEN_OFF != 0 || self.attribute_index & PALETTE_ADDRESS_SOURCE == 0 {
+            self.framebuffer.fill(0xFF00_0000);
+            return;
+        }
+
+        let scan_lines = self.character_height() as usize;
+        let split = (self.line_compare() + 1) / if double_scan { 2 } else { 1 };
+        let (font_a, font_b) = self.character_maps();
+        let blink = self.attribute[MODE_CONTROL] & BLINK_ENABLE != 0;
+        let blink_visible = self.blink_visible();
+        let cursor = self.cursor().filter(|_| self.cursor_visible());
+        let mut line = vec![0; width];
+        for y in 0..height {
+            // below the line compare the screen starts over at address 0
+            let (start, y_in_screen) = if y >= split { (0, y - split) } else { (self.start_address(), y) };
+            let row = y_in_screen / scan_lines;
+            let scan = y_in_screen % scan_lines;
+            let base = start + row * self.row_pitch();
+            for (column, pixels) in line.chunks_mut(dots).enumerate() {
+                let counter = base + column;
+                let address = self.plane_address(counter, scan);
+                let bytes = [0, 1, 2, 3].map(|plane| self.planes[plane][address]);
+                if !graphics {
+                    let (c, attribute) = (bytes[0], bytes[1]);
+                    let font = if attribute & ATTRIBUTE_FONT_A != 0 { font_a } else { font_b };
+                    let hidden = blink && attribute & ATTRIBUTE_BLINK != 0 && !blink_visible;
+                    let mut bits = if hidden { 0 } else { self.planes[2][font + c as usize * GLYPH_SIZE + scan] };
+                    if let Some((location, first, last)) = cursor {
+                        if location == counter && (first..=last).contains(&scan) {
+                            bits = 0xFF;
+                        }
+                    }
+                    let background = if blink { (attribute >> 4) & 7 } else { attribute >> 4 };
+                    let colors = [self.palette_color(background), self.palette_color(attribute & 0x0F)];
+                    for (dot, pixel) in pixels.iter_mut().enumerate() {
+                        *pixel = colors[((bits >> (7 - dot)) & 1) as usize];
+                    }
+                } else if mode & SHIFT_256 != 0 {
+                    for (pixel, &byte) in pixels.iter_mut().zip(bytes.iter()) {
+                        *pixel = self.dac_color(byte);
+                    }
+                } else if mode & SHIFT_INTERLEAVE != 0 {
+                    for (dot, pixel) in pixels.iter_mut().enumerate() {
+                        let byte = bytes[dot / 4];
+                        *pixel = self.palette_color((byte >> (6 - (dot % 4) * 2)) & 3);
+                    }
+                } else {
+                    for (dot, pixel) in pixels.iter_mut().enumerate() {
+                        let index = (0..PLANES).fold(0, |index, plane| index | ((bytes[plane] >> (7 - dot)) & 1) << plane);
+                        *pixel = self.palette_color(index);
+                    }
+                }
+            }
+            self.framebuffer[y * width..(y + 1) * width].copy_from_slice(&line);
+        }
+    }
+
+    // 0xAARRGGBB pixels, a line of `resolution().0` after another
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    // the DAC as ARGB, for the VBE 8 bpp modes
+    pub fn palette(&self) -> Vec<u32> {
+        (0..DAC_ENTRIES).map(|index| self.dac_color(index as u8)).collect()
+    }
+
+    // the part of A0000-BFFFF the graphics controller decodes
+    fn window(&self, offset: usize) -> Option<usize> {
+        let (start, size) = match (self.graphics[MISCELLANEOUS] >> 2) & 3 {
+            0 => (0, 0x20000),
+            1 => (0, 0x10000),
+            2 => (0x10000, 0x8000),
+            _ => (0x18000, 0x8000)
+        };
+        offset.checked_sub(start).filter(|&addr| addr < size)
+    }
+
+    // read mode 1: a bit is set where every plane matches the color compare
+    fn compare(&self) -> u8 {
+        let mut result = 0xFF;
+        for plane in 0..PLANES {
+            if self.graphics[COLOR_DONT_CARE] & (1 << plane) != 0 {
+                let color = if self.graphics[COLOR_COMPARE] & (1 << plane) != 0 { 0xFF } else { 0 };
+                result &= !(self.latches[plane] ^ color);
+            }
+        }
+        result
+    }
+
+    // write modes 0-3: set/reset or the rotated byte, the logical operation
+    // against the latches, then the bit mask, for the byte of each plane
+    fn write_data(&self, value: u8) -> [u8; PLANES] {
+        let write_mode = self.graphics[GRAPHICS_MODE] & 3;
+        if write_mode == 1 {
+            return self.latches;
+        }
+        let rotate = self.graphics[DATA_ROTATE];
+        let rotated = value.rotate_right((rotate & 7) as u32);
+        let set_reset = self.graphics[SET_RESET];
+        let expand = |bit: u8| if bit != 0 { 0xFF } else { 0 };
+        let mut mask = self.graphics[BIT_MASK];
+        if write_mode == 3 {
+            mask &= rotated;
+        }
+        let mut data = [0; PLANES];
+        for (plane, byte) in data.iter_mut().enumerate() {
+            let bit = 1 << plane;
+            let latch = self.latches[plane];
+            let source = match write_mode {
+                0 if self.graphics[ENABLE_SET_RESET] & bit != 0 => expand(set_reset & bit),
+                0 => rotated,
+                2 => expand(value & bit),
+                _ => expand(set_reset & bit)
+            };
+            let result = match (rotate >> 3) & 3 {
+                0 => source,
+                1 => source & latch,
+                2 => source | latch,
+                _ => source ^ latch
+            };
+            *byte = result & mask | latch & !mask;
+        }
+        data
+    }
+}
+
+fn write_register(registers: &mut [u8], index: u8, value: u64) {
+    if let Some(register) = registers.get_mut(index as usize) {
+        *register = value as u8;
+    }
+}
+
+impl MmioDevice for Vga {
+    // every read loads the four latches
+    fn read(&mut self, offset: usize) -> u8 {
+        let Some(addr) = self.window(offset) else {
+            return 0xFF;
+        };
+        let select = (self.graphics[READ_MAP_SELECT] & 3) as usize;
+        let (plane, addr) = if self.sequencer[MEMORY_MODE] & CHAIN_4 != 0 {
+            (addr & 3, addr & !3)
+        } else if self.graphics[GRAPHICS_MODE] & HOST_ODD_EVEN != 0 {
+            (select & 2 | addr & 1, addr & !1)
+        } else {
+            (select, addr)
+        };
+        let addr = addr % PLANE_SIZE;
+        self.latches = [0, 1, 2, 3].map(|plane| self.planes[plane][addr]);
+        if self.graphics[GRAPHICS_MODE] & READ_MODE_COMPARE != 0 {
+            return self.compare();
+        }
+        self.latches[plane]
+    }
+
+    // every plane enabled in the map mask takes its byte of the write
+    fn write(&mut self, offset: usize, value: u8) {
+        let Some(mut addr) = self.window(offset) else {
+            return;
+        };
+        let mut mask = self.sequencer[MAP_MASK];
+        if self.sequencer[MEMORY_MODE] & CHAIN_4 != 0 {
+            mask &= 1 << (addr & 3);
+            addr &= !3;
+        } else if self.sequencer[MEMORY_MODE] & ODD_EVEN_DISABLE == 0 {
+            mask &= if addr & 1 == 0 { 0b0101 } else { 0b1010 };
+            addr &= !1;
+        }
+        let data = self.write_data(value);
+        for (plane, memory) in self.planes.iter_mut().enumerate() {
+            if mask & (1 << plane) != 0 {
+                memory[addr % PLANE_SIZE] = data[plane];
+            }
+        }
+    }
+}
+
+impl PortDevice for Vga {
+    fn read(&mut self, port: u16, _bits: Bits) -> u64 {
+        let value = match port {
+            ATTRIBUTE_INDEX => self.attribute_index,
+            ATTRIBUTE_READ => self.attribute.get((self.attribute_index & 0x1F) as usize).copied().unwrap_or(0),
+            // input status 0, the monitor switch sense
+            MISC_OUTPUT_WRITE => 0x10,
+            SEQUENCER_INDEX => self.sequencer_index,
+            SEQUENCER_DATA => self.sequencer.get(self.sequencer_index as usize).copied().unwrap_or(0),
+            PEL_MASK => self.pel_mask,
+            DAC_READ_INDEX => if self.dac_reading { 0x03 } else { 0x00 },
+            DAC_WRITE_INDEX => self.dac_write_index,
+            DAC_DATA => {
+                let value = self.dac[self.dac_read_index as usize][self.dac_component];
+                self.dac_component += 1;
+                if self.dac_component == 3 {
+                    self.dac_component = 0;
+                    self.dac_read_index = self.dac_read_index.wrapping_add(1);
+                }
+                value
+            },
+            FEATURE_CONTROL_READ => 0,
+            MISC_OUTPUT_READ => self.misc_output,
+            GRAPHICS_INDEX => self.graphics_index,
+            GRAPHICS_DATA => self.graphics.get(self.graphics_index as usize).copied().unwrap_or(0),
+            CRTC_INDEX => self.crtc_index,
+            CRTC_DATA => self.crtc.get(self.crtc_index as usize).copied().unwrap_or(0),
+            // retrace timing follows the host clock, like blinking
+            INPUT_STATUS_1 => {
+                self.attribute_data = false;
+                let position = self.epoch.elapsed().as_micros() % FRAME_US;
+                if position >= FRAME_US - FRAME_US / 20 {
+                    VERTICAL_RETRACE | DISPLAY_DISABLED
+                } else if position % LINE_US >= LINE_US * 4 / 5 {
+                    DISPLAY_DISABLED
+                } else {
+                    0
+                }
+            },
+            _ => 0xFF
+        };
+        value as u64
+    }
+
+    // a word written to an index port sets the index and the data together
+    fn write(&mut self, port: u16, value: u64, bits: Bits) {
+        if matches!(port, SEQUENCER_INDEX | GRAPHICS_INDEX | CRTC_INDEX) && bits == Bits::Bit16 {
+            PortDevice::write(self, port, value & 0xFF, Bits::Bit8);
+            PortDevice::write(self, port + 1, value >> 8, Bits::Bit8);
+            return;
+        }
+        match port {
+            ATTRIBUTE_INDEX => {
+                if self.attribute_data {
+                    write_register(&mut self.attribute, self.attribute_index & 0x1F, value);
+                } else {
+                    self.attribute_index = value as u8 & 0x3F;
+                }
+                self.attribute_data = !self.attribute_data;
+            },
+            MISC_OUTPUT_WRITE => self.misc_output = value as u8,
+            SEQUENCER_INDEX => self.sequencer_index = value as u8,
+            SEQUENCER_DATA => write_register(&mut self.sequencer, self.sequencer_index, value),
+            PEL_MASK => self.pel_mask = value as u8,
+            DAC_READ_INDEX => {
+                self.dac_read_index = value as u8;
+                self.dac_reading = true;
+                self.dac_component = 0;
+            },
+            DAC_WRITE_INDEX => {
+                self.dac_write_index = value as u8;
+                self.dac_reading = false;
+                self.dac_component = 0;
+            },
+            DAC_DATA => {
+                self.dac_latch[self.dac_component] = value as u8 & 0x3F;
+                self.dac_component += 1;
+                if self.dac_component == 3 {
+                    self.dac_component = 0;
+                    self.dac[self.dac_write_index as usize] = self.dac_latch;
+                    self.dac_write_index = self.dac_write_index.wrapping_add(1);
+                }
+            },
+            GRAPHICS_INDEX => self.graphics_index = value as u8,
+            GRAPHICS_DATA => write_register(&mut self.graphics, self.graphics_index, value),
+            CRTC_INDEX => self.crtc_index = value as u8,
+            CRTC_DATA => {
+                let index = self.crtc_index as usize;
+                // only the line compare bit of the overflow stays writable
+                if self.crtc[VERTICAL_RETRACE_END] & CRTC_PROTECT != 0 && index <= OVERFLOW {
+                    if index == OVERFLOW {
+                        self.crtc[OVERFLOW] = self.crtc[OVERFLOW] & !0x10 | value as u8 & 0x10;
+                    }
+                    return;
+                }
+                write_register(&mut self.crtc, self.crtc_index, value);
+            },
+            _ => {}
+        }
+    }
+}
@@ -3,6 +3,10 @@ use std::io::{Read, Seek, SeekFrom, Write};
 
 const SECTOR_SIZE: usize = 512;
 const DISK_SIZE: usize = 100 * 1024 * 1024;
+// the geometry the BIOS reports for it
+pub const HEADS: u8 = 16;
+pub const SECTORS_PER_TRACK: u8 = 63;
+pub const CYLINDERS: u16 = (DISK_SIZE / SECTOR_SIZE / (HEADS as usize * SECTORS_PER_TRACK as usize)) as u16;
 
 
 pub struct VirtualDisk {